@@ -0,0 +1,100 @@
+use super::interrupts::Interrupt;
+
+// Everything the SM83 core sees of the system it is running in
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    // Lets the rest of the system run for one M-cycle (4 T-cycles)
+    fn tick(&mut self);
+    // The enabled and requested interrupt with the highest priority
+    fn pending_interrupt(&self) -> Option<Interrupt>;
+    // Clears the request once the CPU jumps to the interrupt's vector
+    fn acknowledge(&mut self, interrupt: Interrupt);
+
+    // Looks at memory for tracers and debuggers, which must not show up as bus activity
+    fn peek(&mut self, address: u16) -> u8 {
+        self.read(address)
+    }
+
+    // Which bank is switched in at the address, to tell labels in different banks apart
+    fn bank_at(&self, _address: u16) -> usize {
+        0
+    }
+
+    fn has_interrupt(&self) -> bool {
+        self.pending_interrupt().is_some()
+    }
+
+    // Called by STOP, returns true when the CPU should sleep until a button is pressed
+    fn stop(&mut self) -> bool {
+        false
+    }
+
+    // Wakes the CPU up from STOP
+    fn joypad_line_low(&self) -> bool {
+        true
+    }
+
+    // T-cycles the CPU has to sit idle for, e.g. while a DMA has the bus
+    fn take_stalled_cycles(&mut self) -> u32 {
+        0
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+    Idle,
+}
+
+// 64KB of RAM and nothing else, no interrupts are ever requested
+pub struct FlatBus {
+    pub memory: Box<[u8; 0x10000]>,
+    // What the CPU did on the bus in every M-cycle, grows until it is cleared
+    pub activity: Vec<Access>,
+    access: Access,
+}
+
+impl FlatBus {
+    pub fn new() -> FlatBus {
+        FlatBus {
+            memory: Box::new([0; 0x10000]),
+            activity: Vec::new(),
+            access: Access::Idle,
+        }
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        FlatBus::new()
+    }
+}
+
+impl Bus for FlatBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.memory[address as usize];
+        self.access = Access::Read(address, value);
+        value
+    }
+
+    fn peek(&mut self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+        self.access = Access::Write(address, value);
+    }
+
+    fn tick(&mut self) {
+        self.activity.push(std::mem::replace(&mut self.access, Access::Idle));
+    }
+
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        None
+    }
+
+    fn acknowledge(&mut self, _interrupt: Interrupt) {}
+}
@@ -1,8 +1,3 @@
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub enum Column {
-    Zero,
-    One,
-}
 fn bit(condition: bool) -> u8 {
     if condition {
         1
@@ -10,9 +5,24 @@ fn bit(condition: bool) -> u8 {
         0
     }
 }
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Controller {
-    pub column: Column,
+    // P14 and P15 select the groups by being pulled low, both or neither can be selected
+    pub directions_selected: bool,
+    pub buttons_selected: bool,
     pub start: bool,
     pub select: bool,
     pub b: bool,
@@ -26,7 +36,8 @@ pub struct Controller {
 impl Controller {
     pub fn new() -> Controller {
         Controller {
-            column: Column::Zero,
+            directions_selected: false,
+            buttons_selected: false,
             start: false,
             select: false,
             b: false,
@@ -38,34 +49,72 @@ impl Controller {
         }
     }
 
-    pub fn to_byte(&self) -> u8 {
-        let column_bit = if self.column == Column::Zero {
-            1 << 5
+    pub fn write_select(&mut self, value: u8) {
+        self.directions_selected = value & 0x10 == 0;
+        self.buttons_selected = value & 0x20 == 0;
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        match button {
+            Button::Right => self.right = pressed,
+            Button::Left => self.left = pressed,
+            Button::Up => self.up = pressed,
+            Button::Down => self.down = pressed,
+            Button::A => self.a = pressed,
+            Button::B => self.b = pressed,
+            Button::Select => self.select = pressed,
+            Button::Start => self.start = pressed,
+        }
+    }
+
+    // Pressed buttons pull their line low, with both groups selected either one does
+    pub fn to_byte(self) -> u8 {
+        let directions = if self.directions_selected {
+            bit(self.down) << 3 | bit(self.up) << 2 | bit(self.left) << 1 | bit(self.right)
+        } else {
+            0
+        };
+        let buttons = if self.buttons_selected {
+            bit(self.start) << 3 | bit(self.select) << 2 | bit(self.b) << 1 | bit(self.a)
         } else {
-            1 << 4
+            0
         };
-        let bit_4 =
-            bit(!((self.down && self.reading_column_0())
-                || (self.start && self.reading_column_1())))
-                << 3;
-        let bit_3 = bit(
-            !((self.up && self.reading_column_0()) || (self.select && self.reading_column_1()))
-        ) << 2;
-        let bit_2 =
-            bit(!((self.left && self.reading_column_0()) || (self.b && self.reading_column_1())))
-                << 1;
-        let bit_1 =
-            bit(!((self.right && self.reading_column_0()) || (self.a && self.reading_column_1())));
 
-        let row_bits = bit_4 | bit_3 | bit_2 | bit_1;
-        column_bit | row_bits
+        0b11000000
+            | bit(!self.buttons_selected) << 5
+            | bit(!self.directions_selected) << 4
+            | (!(directions | buttons) & 0x0F)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(value: u8) -> Controller {
+        let mut controller = Controller::new();
+        controller.write_select(value);
+        controller.set(Button::Down, true);
+        controller.set(Button::A, true);
+        controller
     }
 
-    fn reading_column_0(&self) -> bool {
-        self.column == Column::Zero
+    #[test]
+    fn select_lines() {
+        // Nothing selected reads all lines high
+        assert_eq!(select(0x30).to_byte(), 0b11111111);
+        // P14 low selects the directions
+        assert_eq!(select(0x20).to_byte(), 0b11100111);
+        // P15 low selects the buttons
+        assert_eq!(select(0x10).to_byte(), 0b11011110);
+        // Both groups pull the lines low together
+        assert_eq!(select(0x00).to_byte(), 0b11000110);
     }
 
-    fn reading_column_1(&self) -> bool {
-        self.column == Column::One
+    #[test]
+    fn release() {
+        let mut controller = select(0x20);
+        controller.set(Button::Down, false);
+        assert_eq!(controller.to_byte(), 0b11101111);
     }
-}
\ No newline at end of file
+}
@@ -1,84 +1,316 @@
 use super::registers::*;
 use super::mem::*;
 use super::instructions::*;
+use super::model::*;
+use super::control::Button;
 
 use super::gpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
-pub struct CPU {
-    registers: Registers,
+use super::postprocess::Frame;
+use super::sgb::{SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+use super::bus::*;
+use super::trace::Tracer;
+use super::disasm::{disassemble, Disassembled};
+
+// Cycles in one frame at normal speed, used to give up when the LCD is turned off
+const CYCLES_PER_FRAME: u32 = 70224;
+
+// NR10-NR51 as the boot ROM leaves them, with channel 1 still on from the start up sound
+const POST_BOOT_SOUND_REGISTERS: [(u16, u8); 21] = [
+    (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0xBF),
+    (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF), (0xFF19, 0xBF),
+    (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0xBF),
+    (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0xBF),
+    (0xFF24, 0x77), (0xFF25, 0xF3), (0xFF26, 0xF1),
+];
+pub struct CPU<B: Bus = MemBus> {
+    pub registers: Registers,
     pub pc: u16,
-    sp: u16,
-    pub bus: MemBus,
+    pub sp: u16,
+    pub bus: B,
+    // Logs every instruction before it runs
+    pub tracer: Option<Tracer>,
     is_halted: bool,
-    interrupts_enabled: bool,
+    is_stopped: bool,
+    halt_bug: bool,
+    pub interrupts_enabled: bool,
+    // EI only takes effect after the instruction following it
+    enable_interrupts_pending: bool,
+    // T-cycles the current step has taken so far
+    cycles: u32,
 }
 
 impl CPU {
     pub fn new(bootrombuffer: Option<Vec<u8>>, gamerombuffer: Vec<u8>) -> CPU {
-        CPU { registers: Registers::new(),
+        let model = Model::from_header(&gamerombuffer);
+        CPU::with_model(bootrombuffer, gamerombuffer, model)
+    }
+
+    // Runs the game on a specific model, e.g. a DMG game on the CGB
+    pub fn with_model(bootrombuffer: Option<Vec<u8>>, gamerombuffer: Vec<u8>, model: Model) -> CPU {
+        let cgb_game = supports_cgb(&gamerombuffer);
+        let mut cpu = CPU { registers: Registers::new(),
             pc: 0x0,
             sp: 0x00,
-            bus: MemBus::new(bootrombuffer, gamerombuffer),
+            bus: MemBus::new(bootrombuffer, gamerombuffer, model),
+            tracer: None,
             is_halted: false,
+            is_stopped: false,
+            halt_bug: false,
             interrupts_enabled: true,
+            enable_interrupts_pending: false,
+            cycles: 0,
+        };
+        if cpu.bus.bootrom.is_none() {
+            cpu.post_boot(cgb_game);
         }
+        cpu
     }
-    pub fn step(&mut self) -> u8 {
-        
-        let mut instruction_byte = self.bus.read_byte(self.pc);
-        
+
+    // Without a boot ROM the game starts right away with the state the boot ROM would have left
+    fn post_boot(&mut self, cgb_game: bool) {
+        match self.bus.model {
+            Model::DMG => {
+                // The boot ROM leaves H and C set unless the header checksum is 0
+                let checksum = self.bus.read(HEADER_CHECKSUM_ADDRESS as u16);
+                self.registers.set_af(if checksum == 0 { 0x0180 } else { 0x01B0 });
+                self.registers.set_bc(0x0013);
+                self.registers.set_de(0x00D8);
+                self.registers.set_hl(0x014D);
+                self.bus.timer.set_divider(0xABCC);
+            }
+            Model::SGB => {
+                self.registers.set_af(0x0100);
+                self.registers.set_bc(0x0014);
+                self.registers.set_de(0x0000);
+                self.registers.set_hl(0xC060);
+                // Pan Docs leaves DIV blank for the SGB and CGB since it depends on how long the boot ROM ran,
+                // these are in the right ballpark which is closer than starting at 0
+                self.bus.timer.set_divider(0xD85C);
+            }
+            Model::CGB => {
+                self.cgb_post_boot(cgb_game);
+                self.bus.timer.set_divider(0x1EA0);
+            }
+        }
+        self.sp = 0xFFFE;
+        self.pc = 0x0100;
+        self.interrupts_enabled = false;
+
+        // P1 with both groups selected, the timer off, a V-Blank pending and the LCD on
+        self.bus.controller.write_select(0xCF);
+        self.bus.serial.write_control(if self.bus.model == Model::CGB { 0x7F } else { 0x7E });
+        self.bus.write(0xFF07, 0xF8);
+        self.bus.write(0xFF0F, 0xE1);
+        for (addr, value) in POST_BOOT_SOUND_REGISTERS.iter() {
+            self.bus.write(*addr, *value);
+        }
+        if self.bus.model == Model::SGB {
+            self.bus.write(0xFF26, 0xF0);
+        }
+        self.bus.write(0xFF40, 0x91);
+        self.bus.write(0xFF47, 0xFC);
+        self.bus.write(0xFF48, 0xFF);
+        self.bus.write(0xFF49, 0xFF);
+        self.bus.gpu.skip_to_boot_hand_off();
+    }
+
+    // The state the CGB boot ROM leaves behind, A = 0x11 is how games detect a CGB
+    fn cgb_post_boot(&mut self, cgb_game: bool) {
+        self.registers.set_af(0x1180);
+        self.registers.set_bc(0x0000);
+        if cgb_game {
+            self.registers.set_de(0xFF56);
+            self.registers.set_hl(0x000D);
+        } else {
+            self.registers.set_de(0x0008);
+            self.registers.set_hl(0x007C);
+            self.bus.gpu.dmg_compatibility = true;
+            self.bus.gpu.object_priority_by_coordinate = true;
+            self.bus.gpu.set_palette(self.bus.gpu.palette);
+        }
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    // Runs the SM83 core on any bus, all registers start at 0
+    pub fn with_bus(bus: B) -> CPU<B> {
+        CPU { registers: Registers::new(),
+            pc: 0x0,
+            sp: 0x00,
+            bus,
+            tracer: None,
+            is_halted: false,
+            is_stopped: false,
+            halt_bug: false,
+            interrupts_enabled: false,
+            enable_interrupts_pending: false,
+            cycles: 0,
+        }
+    }
+
+    // IME as seen after the current instruction, counting an EI that hasn't taken effect yet
+    pub fn ime(&self) -> bool {
+        self.interrupts_enabled || self.enable_interrupts_pending
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.is_stopped
+    }
+
+    // Decodes the instruction at the address without running the rest of the system
+    pub fn disassemble(&mut self, address: u16) -> Disassembled {
+        let bus = &mut self.bus;
+        disassemble(&mut |address| bus.peek(address), address)
+    }
+
+    pub fn step(&mut self) -> u32 {
+        let cycles = self.run_step();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.cycles += cycles as u64;
+        }
+        cycles
+    }
+
+    fn run_step(&mut self) -> u32 {
+        self.cycles = 0;
+
+        // In stop mode the CPU, LCD and timer are all off until a button is pressed
+        if self.is_stopped {
+            if !self.bus.joypad_line_low() {
+                return 4;
+            }
+            self.is_stopped = false;
+        }
+
+        // While halted only time passes for the rest of the system until an interrupt is pending
+        if self.is_halted {
+            if !self.bus.has_interrupt() {
+                self.idle();
+                return self.cycles + self.run_stalled_cycles();
+            }
+            // This happens even with IME=0, the CPU then carries on after the HALT
+            self.is_halted = false;
+            if self.interrupts_enabled {
+                self.handle_interrupts();
+                return self.cycles + self.run_stalled_cycles();
+            }
+        }
+
+        if self.tracer.is_some() {
+            self.trace();
+        }
+
+        let mut instruction_byte = self.read(self.pc);
+
+        // HALT bug: the byte after HALT is read but PC doesn't move past it, so it gets read twice
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
+
         let prefix = instruction_byte == 0xcb;
 
         if prefix {
-            instruction_byte = self.bus.read_byte(self.pc + 1);
+            instruction_byte = self.read(self.pc.wrapping_add(1));
+        }
+
+        if self.enable_interrupts_pending {
+            self.enable_interrupts_pending = false;
+            self.interrupts_enabled = true;
         }
 
-        let (nextpc, mut cycles) = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefix) {
+        let (nextpc, cycles) = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefix) {
             self.execute(instruction)
         } else {
             panic!("Invalid instruction recieved at 0x{:x}", instruction_byte);
         };
-        self.bus.step(cycles);
+        // Whatever the memory accesses didn't take are internal cycles at the end of the instruction
+        while self.cycles < cycles as u32 {
+            self.idle();
+        }
 
         // println!("{} 0x{:x}", prefix, instruction_byte);
 
-        if self.bus.has_interrupt() {
-            self.is_halted = false;
+        self.pc = nextpc;
+
+        self.handle_interrupts();
+        self.cycles + self.run_stalled_cycles()
+    }
+
+    fn trace(&mut self) {
+        let r = &self.registers;
+        let registers = [r.a, r.get_af() as u8, r.b, r.c, r.d, r.e, r.h, r.l];
+        let pc = self.pc;
+        let memory = [0, 1, 2, 3].map(|offset| self.bus.peek(pc.wrapping_add(offset)));
+        let bank = self.bus.bank_at(pc);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.log(registers, self.sp, pc, bank, memory);
         }
+    }
 
-        if !self.is_halted {
-            self.pc = nextpc;
+    // Services the highest priority pending interrupt, which takes 5 M-cycles
+    fn handle_interrupts(&mut self) {
+        if !self.interrupts_enabled || !self.bus.has_interrupt() {
+            return;
         }
+        self.interrupts_enabled = false;
+        self.is_halted = false;
+
+        self.idle();
+        self.idle();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(self.sp, (self.pc >> 8) as u8);
+        // Pushing the upper byte of PC onto IE (SP = 0x0000) can change which interrupt
+        // gets serviced, or cancel it altogether and jump to 0x0000
+        let interrupt = self.bus.pending_interrupt();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(self.sp, (self.pc & 0xFF) as u8);
 
-        let mut interrupted = false;
-        if self.interrupts_enabled {
-            if self.bus.interrupt_enable.vblank && self.bus.interrupt_flag.vblank {
-                interrupted = true;
-                self.bus.interrupt_flag.vblank = false;
-                self.interrupt(VBLANK_VECTOR)
+        self.pc = match interrupt {
+            Some(interrupt) => {
+                self.bus.acknowledge(interrupt);
+                interrupt.vector()
             }
-            if self.bus.interrupt_enable.lcdstat && self.bus.interrupt_flag.lcdstat {
-                interrupted = true;
-                self.bus.interrupt_flag.lcdstat = false;
-                self.interrupt(LCDSTAT_VECTOR)
+            None => 0x0000,
+        };
+        self.idle();
+    }
+
+    // The CPU sits idle while a CGB DMA copies into VRAM, everything else keeps running
+    fn run_stalled_cycles(&mut self) -> u32 {
+        let mut stalled = 0;
+        loop {
+            let cycles = self.bus.take_stalled_cycles();
+            if cycles == 0 {
+                return stalled;
             }
-            if self.bus.interrupt_enable.timer && self.bus.interrupt_flag.timer {
-                interrupted = true;
-                self.bus.interrupt_flag.timer = false;
-                self.interrupt(TIMER_VECTOR)
+            for _ in 0..cycles / 4 {
+                self.bus.tick();
             }
+            stalled += cycles;
         }
+    }
 
-        if interrupted {
-            cycles += 12;
-        }
-        cycles
+    // Every memory access takes one M-cycle during which the rest of the system runs along
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.bus.read(address);
+        self.idle();
+        value
     }
 
-    fn interrupt(&mut self, location: u16) {
-        self.interrupts_enabled = false;
-        self.push(self.pc);
-        self.pc = location;
-        self.bus.step(12);
+    fn write(&mut self, address: u16, value: u8) {
+        self.bus.write(address, value);
+        self.idle();
+    }
+
+    // An M-cycle the CPU spends without touching memory
+    fn idle(&mut self) {
+        self.bus.tick();
+        self.cycles += 4;
     }
 
     fn execute(&mut self, instruction: Instruction) -> (u16, u8) {
@@ -86,33 +318,35 @@ impl CPU {
             Instruction::INC(target) => {
                 match target {
                     IncDecTarget::A => {
-                        self.registers.a = self.registers.a.wrapping_add(1);
+                        self.registers.a = self.inc(self.registers.a);
                     }
                     IncDecTarget::B => {
-                        self.registers.b = self.registers.b.wrapping_add(1);
+                        self.registers.b = self.inc(self.registers.b);
                     }
                     IncDecTarget::C => {
-                        self.registers.c = self.registers.c.wrapping_add(1);
+                        self.registers.c = self.inc(self.registers.c);
                     }
                     IncDecTarget::D => {
-                        self.registers.d = self.registers.d.wrapping_add(1);
+                        self.registers.d = self.inc(self.registers.d);
                     }
                     IncDecTarget::E => {
-                        self.registers.e = self.registers.e.wrapping_add(1);
+                        self.registers.e = self.inc(self.registers.e);
                     }
                     IncDecTarget::F => {
-                        self.registers.f = FlagReg::from(u8::from(self.registers.f).wrapping_add(1));
+                        let f = self.inc(u8::from(self.registers.f));
+                        self.registers.f = FlagReg::from(f);
                     }
                     IncDecTarget::H => {
-                        self.registers.h = self.registers.h.wrapping_add(1);
+                        self.registers.h = self.inc(self.registers.h);
                     }
                     IncDecTarget::L => {
-                        self.registers.l = self.registers.l.wrapping_add(1);
+                        self.registers.l = self.inc(self.registers.l);
                     }
                     IncDecTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let res = self.bus.read_byte(hl).wrapping_add(1);
-                        self.bus.write_byte(hl, res);
+                        let value = self.read(hl);
+                        let res = self.inc(value);
+                        self.write(hl, res);
                     }
                     IncDecTarget::BC => {
                         let bc = self.registers.get_bc();
@@ -141,33 +375,35 @@ impl CPU {
             Instruction::DEC(target) => {
                 match target {
                     IncDecTarget::A => {
-                        self.registers.a = self.registers.a.wrapping_sub(1);
+                        self.registers.a = self.dec(self.registers.a);
                     }
                     IncDecTarget::B => {
-                        self.registers.b = self.registers.b.wrapping_sub(1);
+                        self.registers.b = self.dec(self.registers.b);
                     }
                     IncDecTarget::C => {
-                        self.registers.c = self.registers.c.wrapping_sub(1);
+                        self.registers.c = self.dec(self.registers.c);
                     }
                     IncDecTarget::D => {
-                        self.registers.d = self.registers.d.wrapping_sub(1);
+                        self.registers.d = self.dec(self.registers.d);
                     }
                     IncDecTarget::E => {
-                        self.registers.e = self.registers.e.wrapping_sub(1);
+                        self.registers.e = self.dec(self.registers.e);
                     }
                     IncDecTarget::F => {
-                        self.registers.f = FlagReg::from(u8::from(self.registers.f).wrapping_sub(1));
+                        let f = self.dec(u8::from(self.registers.f));
+                        self.registers.f = FlagReg::from(f);
                     }
                     IncDecTarget::H => {
-                        self.registers.h = self.registers.h.wrapping_sub(1);
+                        self.registers.h = self.dec(self.registers.h);
                     }
                     IncDecTarget::L => {
-                        self.registers.l = self.registers.l.wrapping_sub(1);
+                        self.registers.l = self.dec(self.registers.l);
                     }
                     IncDecTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let res = self.bus.read_byte(hl).wrapping_sub(1);
-                        self.bus.write_byte(hl, res);
+                        let value = self.read(hl);
+                        let res = self.dec(value);
+                        self.write(hl, res);
                     }
                     IncDecTarget::BC => {
                         let bc = self.registers.get_bc();
@@ -194,47 +430,23 @@ impl CPU {
                 (self.pc.wrapping_add(1), cycles)
             }
             Instruction::ADD(target) => {
+                let value = match target {
+                    ArithmeticTarget::A => self.registers.a,
+                    ArithmeticTarget::B => self.registers.b,
+                    ArithmeticTarget::C => self.registers.c,
+                    ArithmeticTarget::D => self.registers.d,
+                    ArithmeticTarget::E => self.registers.e,
+                    ArithmeticTarget::F => u8::from(self.registers.f),
+                    ArithmeticTarget::H => self.registers.h,
+                    ArithmeticTarget::L => self.registers.l,
+                    ArithmeticTarget::D8 => self.read_next_byte(),
+                    ArithmeticTarget::HLI => self.read(self.registers.get_hl()),
+                };
+                self.registers.a = self.add(value);
                 match target {
-                    ArithmeticTarget::A => {
-                        self.registers.a = self.add(self.registers.a);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.a = self.add(self.registers.b);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::C => { 
-                        self.registers.a = self.add(self.registers.c);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.a = self.add(self.registers.d);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.a = self.add(self.registers.e);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::F => {
-                        self.registers.a = self.add(u8::from(self.registers.f));
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.a = self.add(self.registers.h);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.a = self.add(self.registers.l);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::D8 => {
-                        self.registers.a = self.add(self.read_next_byte());
-                        (self.pc.wrapping_add(2),8)
-                    }
-                    ArithmeticTarget::HLI => {
-                        self.registers.a = self.add(self.bus.read_byte(self.registers.get_hl()));
-                        (self.pc.wrapping_add(1), 8)
-                    }
+                    ArithmeticTarget::D8 => (self.pc.wrapping_add(2), 8),
+                    ArithmeticTarget::HLI => (self.pc.wrapping_add(1), 8),
+                    _ => (self.pc.wrapping_add(1), 4)
                 }
             }
             Instruction::ADDHL(target) => {
@@ -249,54 +461,30 @@ impl CPU {
                 
                 self.registers.f.carry = carry;
                 self.registers.f.substract = false;
-                let mask = 0b111_1111_1111;
+                let mask = 0xFFF;
                 self.registers.f.half_carry = (value & mask) + (hl & mask) > mask;
 
                 self.registers.set_hl(result);
                 (self.pc.wrapping_add(1), 8)
             }
             Instruction::ADC(target) => {
+                let value = match target {
+                    ArithmeticTarget::A => self.registers.a,
+                    ArithmeticTarget::B => self.registers.b,
+                    ArithmeticTarget::C => self.registers.c,
+                    ArithmeticTarget::D => self.registers.d,
+                    ArithmeticTarget::E => self.registers.e,
+                    ArithmeticTarget::F => u8::from(self.registers.f),
+                    ArithmeticTarget::H => self.registers.h,
+                    ArithmeticTarget::L => self.registers.l,
+                    ArithmeticTarget::D8 => self.read_next_byte(),
+                    ArithmeticTarget::HLI => self.read(self.registers.get_hl()),
+                };
+                self.registers.a = self.add_with_carry(value);
                 match target {
-                    ArithmeticTarget::A => {
-                        self.registers.a = self.add_with_carry(self.registers.a);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.a = self.add_with_carry(self.registers.b);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::C => { 
-                        self.registers.a = self.add_with_carry(self.registers.c);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.a = self.add_with_carry(self.registers.d);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.a = self.add_with_carry(self.registers.e);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::F => {
-                        self.registers.a = self.add_with_carry(u8::from(self.registers.f));
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.a = self.add_with_carry(self.registers.h);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.a = self.add_with_carry(self.registers.l);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::D8 => {
-                        self.registers.a = self.add_with_carry(self.read_next_byte());
-                        (self.pc.wrapping_add(2), 8)
-                    }
-                    ArithmeticTarget::HLI => {
-                        self.registers.a = self.add_with_carry(self.bus.read_byte(self.registers.get_hl()));
-                        (self.pc.wrapping_add(1),8)
-                    }
+                    ArithmeticTarget::D8 => (self.pc.wrapping_add(2), 8),
+                    ArithmeticTarget::HLI => (self.pc.wrapping_add(1), 8),
+                    _ => (self.pc.wrapping_add(1), 4)
                 }
             }
             Instruction::ADDSP => {
@@ -314,91 +502,43 @@ impl CPU {
                 (self.pc.wrapping_add(2), 16)
             }
             Instruction::SUB(target) => {
+                let value = match target {
+                    ArithmeticTarget::A => self.registers.a,
+                    ArithmeticTarget::B => self.registers.b,
+                    ArithmeticTarget::C => self.registers.c,
+                    ArithmeticTarget::D => self.registers.d,
+                    ArithmeticTarget::E => self.registers.e,
+                    ArithmeticTarget::F => u8::from(self.registers.f),
+                    ArithmeticTarget::H => self.registers.h,
+                    ArithmeticTarget::L => self.registers.l,
+                    ArithmeticTarget::D8 => self.read_next_byte(),
+                    ArithmeticTarget::HLI => self.read(self.registers.get_hl()),
+                };
+                self.registers.a = self.sub(value);
                 match target {
-                    ArithmeticTarget::A => {
-                        self.registers.a = self.sub(self.registers.a);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.a = self.sub(self.registers.b);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::C => { 
-                        self.registers.a = self.sub(self.registers.c);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.a = self.sub(self.registers.d);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.a = self.sub(self.registers.e);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::F => {
-                        self.registers.a = self.sub(u8::from(self.registers.f));
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.a = self.sub(self.registers.h);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.a = self.sub(self.registers.l);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::D8 => {
-                        self.registers.a = self.sub(self.read_next_byte());
-                        (self.pc.wrapping_add(2),8)
-                    }
-                    ArithmeticTarget::HLI => {
-                        self.registers.a = self.sub(self.bus.read_byte(self.registers.get_hl()));
-                        (self.pc.wrapping_add(1), 8)
-                    }
+                    ArithmeticTarget::D8 => (self.pc.wrapping_add(2), 8),
+                    ArithmeticTarget::HLI => (self.pc.wrapping_add(1), 8),
+                    _ => (self.pc.wrapping_add(1), 4)
                 }
             }
             Instruction::SBC(target) => {
+                let value = match target {
+                    ArithmeticTarget::A => self.registers.a,
+                    ArithmeticTarget::B => self.registers.b,
+                    ArithmeticTarget::C => self.registers.c,
+                    ArithmeticTarget::D => self.registers.d,
+                    ArithmeticTarget::E => self.registers.e,
+                    ArithmeticTarget::F => u8::from(self.registers.f),
+                    ArithmeticTarget::H => self.registers.h,
+                    ArithmeticTarget::L => self.registers.l,
+                    ArithmeticTarget::D8 => self.read_next_byte(),
+                    ArithmeticTarget::HLI => self.read(self.registers.get_hl()),
+                };
+                self.registers.a = self.sub_with_carry(value);
                 match target {
-                    ArithmeticTarget::A => {
-                        self.registers.a = self.sub_with_carry(self.registers.a);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.a = self.sub_with_carry(self.registers.b);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::C => { 
-                        self.registers.a = self.sub_with_carry(self.registers.c);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.a = self.sub_with_carry(self.registers.d);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.a = self.sub_with_carry(self.registers.e);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::F => {
-                        self.registers.a = self.sub_with_carry(u8::from(self.registers.f));
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.a = self.sub_with_carry(self.registers.h);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.a = self.sub_with_carry(self.registers.l);
-                        (self.pc.wrapping_add(1), 4)
-                    }
-                    ArithmeticTarget::D8 => {
-                        self.registers.a = self.sub_with_carry(self.read_next_byte());
-                        (self.pc.wrapping_add(2), 8)
-                    }
-                    ArithmeticTarget::HLI => {
-                        self.registers.a = self.sub_with_carry(self.bus.read_byte(self.registers.get_hl()));
-                        (self.pc.wrapping_add(1), 8)
-                    }
+                    ArithmeticTarget::D8 => (self.pc.wrapping_add(2), 8),
+                    ArithmeticTarget::HLI => (self.pc.wrapping_add(1), 8),
+                    _ => (self.pc.wrapping_add(1), 4)
                 }
             }
             Instruction::AND(target) => {
@@ -412,7 +552,7 @@ impl CPU {
                     ArithmeticTarget::H => self.registers.h,
                     ArithmeticTarget::L => self.registers.l,
                     ArithmeticTarget::D8 => self.read_next_byte(),
-                    ArithmeticTarget::HLI => self.bus.read_byte(self.registers.get_hl()),
+                    ArithmeticTarget::HLI => self.read(self.registers.get_hl()),
                 };
                 let n = self.registers.a & value;
                 self.registers.f.zero = n == 0;
@@ -438,12 +578,12 @@ impl CPU {
                     ArithmeticTarget::H => self.registers.h,
                     ArithmeticTarget::L => self.registers.l,
                     ArithmeticTarget::D8 => self.read_next_byte(),
-                    ArithmeticTarget::HLI => self.bus.read_byte(self.registers.get_hl()),
+                    ArithmeticTarget::HLI => self.read(self.registers.get_hl()),
                 };
                 let n = self.registers.a | value;
                 self.registers.f.zero = n == 0;
                 self.registers.f.substract = false;
-                self.registers.f.half_carry = true;
+                self.registers.f.half_carry = false;
                 self.registers.f.carry = false;
                 self.registers.a = n;
                 match target {
@@ -463,12 +603,12 @@ impl CPU {
                     ArithmeticTarget::H => self.registers.h,
                     ArithmeticTarget::L => self.registers.l,
                     ArithmeticTarget::D8 => self.read_next_byte(),
-                    ArithmeticTarget::HLI => self.bus.read_byte(self.registers.get_hl()),
+                    ArithmeticTarget::HLI => self.read(self.registers.get_hl()),
                 };
                 let n = self.registers.a ^ value;
                 self.registers.f.zero = n == 0;
                 self.registers.f.substract = false;
-                self.registers.f.half_carry = true;
+                self.registers.f.half_carry = false;
                 self.registers.f.carry = false;
                 self.registers.a = n;
                 match target {
@@ -488,7 +628,7 @@ impl CPU {
                     ArithmeticTarget::H => self.registers.h,
                     ArithmeticTarget::L => self.registers.l,
                     ArithmeticTarget::D8 => self.read_next_byte(),
-                    ArithmeticTarget::HLI => self.bus.read_byte(self.registers.get_hl()),
+                    ArithmeticTarget::HLI => self.read(self.registers.get_hl()),
                 };
 
                 self.registers.f.zero = self.registers.a == value;
@@ -592,22 +732,21 @@ impl CPU {
             }
             Instruction::BIT(target, bit_position) => {
                 match target {
-                    PreFixTarget::A => self.registers.a = self.bit_test(self.registers.a, bit_position),
-                    PreFixTarget::B => self.registers.b = self.bit_test(self.registers.b, bit_position),
-                    PreFixTarget::C => self.registers.c = self.bit_test(self.registers.c, bit_position),
-                    PreFixTarget::D => self.registers.d = self.bit_test(self.registers.d, bit_position),
-                    PreFixTarget::E => self.registers.e = self.bit_test(self.registers.e, bit_position),
-                    PreFixTarget::H => self.registers.h = self.bit_test(self.registers.h, bit_position),
-                    PreFixTarget::L => self.registers.l = self.bit_test(self.registers.l, bit_position),
+                    PreFixTarget::A => self.bit_test(self.registers.a, bit_position),
+                    PreFixTarget::B => self.bit_test(self.registers.b, bit_position),
+                    PreFixTarget::C => self.bit_test(self.registers.c, bit_position),
+                    PreFixTarget::D => self.bit_test(self.registers.d, bit_position),
+                    PreFixTarget::E => self.bit_test(self.registers.e, bit_position),
+                    PreFixTarget::H => self.bit_test(self.registers.h, bit_position),
+                    PreFixTarget::L => self.bit_test(self.registers.l, bit_position),
                     PreFixTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl);
-                        let result = self.bit_test(value, bit_position);
-                        self.bus.write_byte(hl, result);
+                        let value = self.read(hl);
+                        self.bit_test(value, bit_position);
                     }
                 }
                 match target {
-                    PreFixTarget::HLI => (self.pc.wrapping_add(2), 16),
+                    PreFixTarget::HLI => (self.pc.wrapping_add(2), 12),
                     _ => (self.pc.wrapping_add(2), 8)
                 }
             }
@@ -622,9 +761,9 @@ impl CPU {
                     PreFixTarget::L => self.registers.l = self.reset_bit(self.registers.l, bit_position),
                     PreFixTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl);
+                        let value = self.read(hl);
                         let result = self.reset_bit(value, bit_position);
-                        self.bus.write_byte(hl, result);
+                        self.write(hl, result);
                     }
                 }
                 match target {
@@ -643,9 +782,9 @@ impl CPU {
                     PreFixTarget::L => self.registers.l = self.set_bit(self.registers.l, bit_position),
                     PreFixTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl);
+                        let value = self.read(hl);
                         let result = self.set_bit(value, bit_position);
-                        self.bus.write_byte(hl, result);
+                        self.write(hl, result);
                     }
                 }
                 match target {
@@ -664,9 +803,9 @@ impl CPU {
                     PreFixTarget::L => self.registers.l = self.shift_right_logical(self.registers.l),
                     PreFixTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl);
+                        let value = self.read(hl);
                         let result = self.shift_right_logical(value);
-                        self.bus.write_byte(hl, result);
+                        self.write(hl, result);
                     }
                 }
                 match target {
@@ -685,9 +824,9 @@ impl CPU {
                     PreFixTarget::L => self.registers.l = self.rotate_right_through_carry_set_zero(self.registers.l),
                     PreFixTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl);
+                        let value = self.read(hl);
                         let result = self.rotate_right_through_carry_set_zero(value);
-                        self.bus.write_byte(hl, result);
+                        self.write(hl, result);
                     }
                 }
                 match target {
@@ -706,9 +845,9 @@ impl CPU {
                     PreFixTarget::L => self.registers.l = self.rotate_left_through_carry_set_zero(self.registers.l),
                     PreFixTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl);
+                        let value = self.read(hl);
                         let result = self.rotate_left_through_carry_set_zero(value);
-                        self.bus.write_byte(hl, result);
+                        self.write(hl, result);
                     }
                 }
                 match target {
@@ -727,9 +866,9 @@ impl CPU {
                     PreFixTarget::L => self.registers.l = self.rotate_right_set_zero(self.registers.l),
                     PreFixTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl);
+                        let value = self.read(hl);
                         let result = self.rotate_right_set_zero(value);
-                        self.bus.write_byte(hl, result);
+                        self.write(hl, result);
                     }
                 }
                 match target {
@@ -748,9 +887,9 @@ impl CPU {
                     PreFixTarget::L => self.registers.l = self.rotate_left_set_zero(self.registers.l),
                     PreFixTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl);
+                        let value = self.read(hl);
                         let result = self.rotate_left_set_zero(value);
-                        self.bus.write_byte(hl, result);
+                        self.write(hl, result);
                     }
                 }
                 match target {
@@ -769,9 +908,9 @@ impl CPU {
                     PreFixTarget::L => self.registers.l = self.shift_right_arithmetic(self.registers.l),
                     PreFixTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl);
+                        let value = self.read(hl);
                         let result = self.shift_right_arithmetic(value);
-                        self.bus.write_byte(hl, result);
+                        self.write(hl, result);
                     }
                 }
                 match target {
@@ -790,9 +929,9 @@ impl CPU {
                     PreFixTarget::L => self.registers.l = self.shift_left_arithmetic(self.registers.l),
                     PreFixTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl);
+                        let value = self.read(hl);
                         let result = self.shift_left_arithmetic(value);
-                        self.bus.write_byte(hl, result);
+                        self.write(hl, result);
                     }
                 }
                 match target {
@@ -811,9 +950,9 @@ impl CPU {
                     PreFixTarget::L => self.registers.l = self.swap(self.registers.l),
                     PreFixTarget::HLI => {
                         let hl = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl);
+                        let value = self.read(hl);
                         let result = self.swap(value);
-                        self.bus.write_byte(hl, result);
+                        self.write(hl, result);
                     }
                 }
                 match target {
@@ -856,7 +995,7 @@ impl CPU {
                             LoadByteSource::H => self.registers.h,
                             LoadByteSource::L => self.registers.l,
                             LoadByteSource::D8 => self.read_next_byte(),
-                            LoadByteSource::HLI => self.bus.read_byte(self.registers.get_hl()),
+                            LoadByteSource::HLI => self.read(self.registers.get_hl()),
                         };
                         match target {
                             LoadByteTarget::A => self.registers.a = source_value,
@@ -867,12 +1006,14 @@ impl CPU {
                             LoadByteTarget::H => self.registers.h = source_value,
                             LoadByteTarget::L => self.registers.l = source_value,
                             LoadByteTarget::HLI => {
-                                self.bus.write_byte(self.registers.get_hl(), source_value)
+                                self.write(self.registers.get_hl(), source_value)
                             }
                         };
-                        match source {
-                            LoadByteSource::D8 => (self.pc.wrapping_add(2), 8),
-                            LoadByteSource::HLI => (self.pc.wrapping_add(1), 8),
+                        match (source, target) {
+                            (LoadByteSource::D8, LoadByteTarget::HLI) => (self.pc.wrapping_add(2), 12),
+                            (_, LoadByteTarget::HLI) => (self.pc.wrapping_add(1), 8),
+                            (LoadByteSource::D8, _) => (self.pc.wrapping_add(2), 8),
+                            (LoadByteSource::HLI, _) => (self.pc.wrapping_add(1), 8),
                             _ => (self.pc.wrapping_add(1), 4)
                         }
                     }
@@ -888,21 +1029,24 @@ impl CPU {
                     }
                     LoadType::AFromIndirect(source) => {
                         self.registers.a = match source {
-                            Indirect::BCIndirect => self.bus.read_byte(self.registers.get_bc()),
-                            Indirect::DEIndirect => self.bus.read_byte(self.registers.get_de()),
+                            Indirect::BCIndirect => self.read(self.registers.get_bc()),
+                            Indirect::DEIndirect => self.read(self.registers.get_de()),
                             Indirect::HLIndirectMinus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_sub(1));
-                                self.bus.read_byte(hl)
+                                self.read(hl)
                             }
                             Indirect::HLIndirectPlus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_add(1));
-                                self.bus.read_byte(hl)
+                                self.read(hl)
+                            }
+                            Indirect::WordIndirect => {
+                                let address = self.read_next_word();
+                                self.read(address)
                             }
-                            Indirect::WordIndirect => self.bus.read_byte(self.read_next_word()),
                             Indirect::LastByteIndirect => {
-                                self.bus.read_byte(0xFF00 + self.registers.c as u16)
+                                self.read(0xFF00 + self.registers.c as u16)
                             }
                         };
 
@@ -916,29 +1060,29 @@ impl CPU {
                         match target {
                             Indirect::BCIndirect => {
                                 let bc = self.registers.get_bc();
-                                self.bus.write_byte(bc, a)
+                                self.write(bc, a)
                             }
                             Indirect::DEIndirect => {
                                 let de = self.registers.get_de();
-                                self.bus.write_byte(de, a)
+                                self.write(de, a)
                             }
                             Indirect::HLIndirectMinus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_sub(1));
-                                self.bus.write_byte(hl, a);
+                                self.write(hl, a);
                             }
                             Indirect::HLIndirectPlus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_add(1));
-                                self.bus.write_byte(hl, a);
+                                self.write(hl, a);
                             }
                             Indirect::WordIndirect => {
                                 let word = self.read_next_word();
-                                self.bus.write_byte(word, a);
+                                self.write(word, a);
                             }
                             Indirect::LastByteIndirect => {
                                 let c = self.registers.c as u16;
-                                self.bus.write_byte(0xFF00 + c, a);
+                                self.write(0xFF00 + c, a);
                             }
                         };
 
@@ -949,12 +1093,12 @@ impl CPU {
                     }
                     LoadType::ByteAddressFromA => {
                         let offset = self.read_next_byte() as u16;
-                        self.bus.write_byte(0xFF00 + offset, self.registers.a);
+                        self.write(0xFF00 + offset, self.registers.a);
                         (self.pc.wrapping_add(2), 12)
                     }
                     LoadType::AFromByteAddress => {
                         let offset = self.read_next_byte() as u16;
-                        self.registers.a = self.bus.read_byte(0xFF00 + offset);
+                        self.registers.a = self.read(0xFF00 + offset);
                         (self.pc.wrapping_add(2), 12)
                     }
                     LoadType::SPFromHL => {
@@ -964,10 +1108,9 @@ impl CPU {
                     LoadType::IndirectFromSP => {
                         let address = self.read_next_word();
                         let sp = self.sp;
-                        self.bus.write_byte(address, (sp & 0xFF) as u8);
-                        self.bus
-                            .write_byte(address.wrapping_add(1), ((sp & 0xFF00) >> 8) as u8);
-                            (self.pc.wrapping_add(3), 20)
+                        self.write(address, (sp & 0xFF) as u8);
+                        self.write(address.wrapping_add(1), (sp >> 8) as u8);
+                        (self.pc.wrapping_add(3), 20)
                     }
                     LoadType::HLFromSPN => {
                         let value = self.read_next_byte() as i8 as i16 as u16;
@@ -1021,8 +1164,10 @@ impl CPU {
                     JumpTest::Always => true,
                     _ => panic!("Invalid ret value recieved")
                 };
-                self.ret(jumpcondition);
-
+                // Checking the condition takes an M-cycle of its own
+                if target != JumpTest::Always {
+                    self.idle();
+                }
                 let next_pc = self.ret(jumpcondition);
 
                 let cycles = if jumpcondition && target == JumpTest::Always {
@@ -1040,21 +1185,32 @@ impl CPU {
             }
             Instruction::RST(target) => {
                 self.push(self.pc.wrapping_add(1));
-                (target.to_hex(), 24)
+                (target.to_hex(), 16)
             }
             Instruction::NOP => {
                 (self.pc.wrapping_add(1), 4)
             }
+            Instruction::STOP => {
+                self.is_stopped = self.bus.stop();
+                // STOP is followed by a padding byte which is skipped
+                (self.pc.wrapping_add(2), 4)
+            }
             Instruction::HALT => {
-                self.is_halted = true;
+                if !self.interrupts_enabled && self.bus.has_interrupt() {
+                    // With an interrupt already pending and IME=0 the CPU doesn't halt at all
+                    self.halt_bug = true;
+                } else {
+                    self.is_halted = true;
+                }
                 (self.pc.wrapping_add(1), 4)
             }
             Instruction::DI => {
                 self.interrupts_enabled = false;
+                self.enable_interrupts_pending = false;
                 (self.pc.wrapping_add(1), 4)
             }
             Instruction::EI => {
-                self.interrupts_enabled = true;
+                self.enable_interrupts_pending = true;
                 (self.pc.wrapping_add(1), 4)
             }
         }
@@ -1065,17 +1221,18 @@ impl CPU {
         self.registers.f.zero = value == 0;
         self.registers.f.substract = false;
         self.registers.f.carry = overflow;
-        self.registers.f.half_carry = (self.registers.a & 0xf) + (value & 0xf) > 0xf;
+        self.registers.f.half_carry = (self.registers.a & 0xf) + (n & 0xf) > 0xf;
 
         value
     }
     fn add_with_carry(&mut self, n: u8) -> u8 {
+        let carry_in = self.registers.f.carry as u8;
         let (add1, carry) = self.registers.a.overflowing_add(n); // A + s
-        let (add2, carry2) = add1.overflowing_add(self.registers.f.carry as u8); // A + s + carryflag
+        let (add2, carry2) = add1.overflowing_add(carry_in); // A + s + carryflag
         self.registers.f.zero = add2 == 0;
         self.registers.f.substract = false;
         self.registers.f.carry = carry || carry2;
-        self.registers.f.half_carry = ((self.registers.a & 0xF) + (n & 0xF) + (self.registers.f.carry as u8)) > 0xF;
+        self.registers.f.half_carry = (self.registers.a & 0xF) + (n & 0xF) + carry_in > 0xF;
         add2
     }
     fn sub(&mut self, n: u8) -> u8 {
@@ -1083,26 +1240,41 @@ impl CPU {
         self.registers.f.zero = value == 0;
         self.registers.f.substract = true;
         self.registers.f.carry = overflow;
-        self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF);
+        self.registers.f.half_carry = (self.registers.a & 0xF) < (n & 0xF);
 
         value
     }
     fn sub_with_carry(&mut self, n: u8) -> u8 {
+        let carry_in = self.registers.f.carry as u8;
         let (sub, carry) = self.registers.a.overflowing_sub(n);
-        let (sub2, carry2) = sub.overflowing_sub(self.registers.f.carry as u8);
+        let (sub2, carry2) = sub.overflowing_sub(carry_in);
         self.registers.f.zero = sub2 == 0;
         self.registers.f.substract = true;
         self.registers.f.carry = carry || carry2;
-        self.registers.f.half_carry = (self.registers.a & 0xF) < (n & 0xF) + (self.registers.f.carry as u8);
+        self.registers.f.half_carry = (self.registers.a & 0xF) < (n & 0xF) + carry_in;
         sub2
     }
-    fn bit_test(&mut self, value: u8, bit_position: BitPosition) -> u8 {
+    // INC and DEC leave the carry flag alone
+    fn inc(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+        self.registers.f.zero = result == 0;
+        self.registers.f.substract = false;
+        self.registers.f.half_carry = value & 0xF == 0xF;
+        result
+    }
+    fn dec(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+        self.registers.f.zero = result == 0;
+        self.registers.f.substract = true;
+        self.registers.f.half_carry = value & 0xF == 0;
+        result
+    }
+    fn bit_test(&mut self, value: u8, bit_position: BitPosition) {
         let bit_position: u8 = bit_position.into();
         let result = (value >> bit_position) & 0b1;
         self.registers.f.zero = result == 0;
         self.registers.f.substract = false;
         self.registers.f.half_carry = true;
-        result
     }
     fn reset_bit(&mut self, value: u8, bit_position: BitPosition) -> u8 {
         let bit_position: u8 = bit_position.into();
@@ -1181,41 +1353,37 @@ impl CPU {
         new_value
     }
     fn jump(&mut self, jump: bool) -> (u16, u8) {
+        let address = self.read_next_word();
         if jump {
-            let lsb = self.bus.read_byte(self.pc + 1) as u16;
-            let msb = self.bus.read_byte(self.pc + 2) as u16;
-            (((msb << 8) | lsb), 16) 
+            (address, 16)
         } else {
             (self.pc.wrapping_add(3), 12)
         }
     }
-    fn jump_rel(&self, should_jump: bool) -> (u16, u8) {
+    fn jump_rel(&mut self, should_jump: bool) -> (u16, u8) {
         let next_step = self.pc.wrapping_add(2);
+        let offset = self.read_next_byte() as i8;
         if should_jump {
-            let offset = self.read_next_byte() as i8;
-            let pc = if offset >= 0 {
-                next_step.wrapping_add(offset as u16)
-            } else {
-                next_step.wrapping_sub(offset.abs() as u16)
-            };
-            (pc, 16)
+            (next_step.wrapping_add(offset as u16), 12)
         } else {
-            (next_step, 12)
+            (next_step, 8)
         }
     }
+    // Starts with an internal cycle to decrement SP
     fn push(&mut self, value: u16) {
+        self.idle();
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, ((value & 0xFF00) >> 8) as u8);
+        self.write(self.sp, ((value & 0xFF00) >> 8) as u8);
 
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, (value & 0xFF) as u8);
+        self.write(self.sp, (value & 0xFF) as u8);
     }
 
     fn pop(&mut self) -> u16 {
-        let lsb = self.bus.read_byte(self.sp) as u16;
+        let lsb = self.read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
 
-        let msb = self.bus.read_byte(self.sp) as u16;
+        let msb = self.read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
 
         (msb << 8) | lsb
@@ -1223,9 +1391,10 @@ impl CPU {
 
     fn call(&mut self, jump: bool) -> (u16, u8) {
         let nextpc = self.pc.wrapping_add(3);
+        let address = self.read_next_word();
         if jump {
             self.push(nextpc);
-            (self.read_next_word(), 24)
+            (address, 24)
         } else {
             (nextpc, 12)
         }
@@ -1239,12 +1408,54 @@ impl CPU {
         }
     }
 
-    fn read_next_word(&self) -> u16 {
-        ((self.bus.read_byte(self.pc + 2) as u16) << 8) | (self.bus.read_byte(self.pc + 1) as u16)
+    fn read_next_word(&mut self) -> u16 {
+        let lsb = self.read(self.pc.wrapping_add(1)) as u16;
+        let msb = self.read(self.pc.wrapping_add(2)) as u16;
+        (msb << 8) | lsb
     }
 
-    fn read_next_byte(&self) -> u8 {
-        self.bus.read_byte(self.pc + 1)
+    fn read_next_byte(&mut self) -> u8 {
+        self.read(self.pc.wrapping_add(1))
+    }
+
+}
+
+impl CPU {
+    pub fn press(&mut self, button: Button) {
+        self.bus.press(button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.bus.release(button);
+    }
+
+    // Runs until the LCD has finished a frame, or for one frame's worth of cycles when it is off
+    pub fn run_frame(&mut self) -> Frame {
+        self.run_frame_with(|cpu| Some(cpu.step()))
+    }
+
+    // Like run_frame, but the caller runs every instruction, e.g. a debugger checking breakpoints.
+    // The frame ends early when step returns None.
+    pub fn run_frame_with<F: FnMut(&mut CPU) -> Option<u32>>(&mut self, mut step: F) -> Frame {
+        let limit = if self.bus.double_speed { CYCLES_PER_FRAME * 2 } else { CYCLES_PER_FRAME };
+        let mut cycles = 0;
+        self.bus.frame_ready = false;
+        while !self.bus.frame_ready && cycles < limit {
+            match step(self) {
+                Some(step_cycles) => cycles += step_cycles,
+                None => break,
+            }
+        }
+        self.bus.frame_ready = false;
+        self.frame()
+    }
+
+    // What is on screen right now, including the border on the SGB
+    pub fn frame(&self) -> Frame {
+        match &self.bus.sgb {
+            Some(sgb) => Frame::from_rgba(SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT, &sgb.output[..]),
+            None => Frame::from_rgba(SCREEN_WIDTH, SCREEN_HEIGHT, &self.bus.gpu.canvas_buffer),
+        }
     }
 
     pub const fn getRESH() -> usize {
@@ -1254,3 +1465,236 @@ impl CPU {
         SCREEN_WIDTH
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EI: u8 = 0xFB;
+    const DI: u8 = 0xF3;
+
+    // A DMG with the code in work RAM, the ROM is all NOPs
+    fn cpu(code: &[u8], enabled: u8, requested: u8) -> CPU {
+        let mut cpu = CPU::new(None, vec![0; 0x8000]);
+        for (offset, &byte) in code.iter().enumerate() {
+            cpu.bus.write_byte(0xC000 + offset as u16, byte);
+        }
+        cpu.pc = 0xC000;
+        cpu.bus.interrupt_enable.from_byte(enabled);
+        cpu.bus.interrupt_flag.from_byte(requested);
+        cpu
+    }
+
+    fn pushed(cpu: &CPU) -> u16 {
+        cpu.bus.read_byte(cpu.sp) as u16 | (cpu.bus.read_byte(cpu.sp.wrapping_add(1)) as u16) << 8
+    }
+
+    #[test]
+    fn highest_priority_first() {
+        let mut cpu = cpu(&[], 0x1F, 0b11100);
+        cpu.interrupts_enabled = true;
+        // The NOP and then 5 M-cycles to get to the vector
+        assert_eq!(cpu.step(), 24);
+        assert_eq!(cpu.pc, TIMER_VECTOR);
+        assert_eq!(pushed(&cpu), 0xC001);
+        assert_eq!(cpu.bus.interrupt_flag.to_byte() & 0x1F, 0b11000);
+        assert!(!cpu.interrupts_enabled);
+
+        cpu.interrupts_enabled = true;
+        cpu.step();
+        assert_eq!(cpu.pc, SERIAL_VECTOR);
+    }
+
+    #[test]
+    fn only_enabled_interrupts() {
+        let mut cpu = cpu(&[], 0b10000, 0b00001);
+        cpu.interrupts_enabled = true;
+        cpu.step();
+        assert_eq!(cpu.pc, 0xC001);
+
+        cpu.bus.interrupt_flag.joypad = true;
+        cpu.step();
+        assert_eq!(cpu.pc, JOYPAD_VECTOR);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        let mut cpu = cpu(&[EI, 0x00, 0x00], 0x01, 0x01);
+        cpu.step();
+        assert_eq!(cpu.pc, 0xC001);
+        cpu.step();
+        assert_eq!(cpu.pc, VBLANK_VECTOR);
+        assert_eq!(pushed(&cpu), 0xC002);
+    }
+
+    #[test]
+    fn di_right_after_ei() {
+        let mut cpu = cpu(&[EI, DI, 0x00], 0x01, 0x01);
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0xC003);
+    }
+
+    // With SP at 0x0000 the upper byte of PC goes to IE before the CPU picks the interrupt
+    #[test]
+    fn pushing_onto_ie_cancels() {
+        let mut cpu = cpu(&[], 0x01, 0x01);
+        cpu.sp = 0x0000;
+        cpu.interrupts_enabled = true;
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0000);
+        // 0xC0 enables none of them
+        assert_eq!(cpu.bus.interrupt_enable.to_byte() & 0x1F, 0);
+        assert!(cpu.bus.interrupt_flag.vblank);
+    }
+
+    #[test]
+    fn pushing_onto_ie_picks_another_interrupt() {
+        let mut cpu = cpu(&[], 0b00101, 0b00101);
+        cpu.pc = 0x0400;
+        cpu.sp = 0x0000;
+        cpu.interrupts_enabled = true;
+        cpu.step();
+        assert_eq!(cpu.pc, TIMER_VECTOR);
+        assert!(cpu.bus.interrupt_flag.vblank);
+        assert!(!cpu.bus.interrupt_flag.timer);
+    }
+
+    const HALT: u8 = 0x76;
+    const INC_A: u8 = 0x3C;
+
+    #[test]
+    fn halt_only_lets_time_pass() {
+        let mut cpu = cpu(&[HALT, INC_A], 0, 0);
+        let a = cpu.registers.a;
+        cpu.bus.timer.set_divider(0);
+        cpu.step();
+        assert!(cpu.is_halted());
+        let line = cpu.bus.gpu.line;
+        for _ in 0..200 {
+            assert_eq!(cpu.step(), 4);
+        }
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.pc, 0xC001);
+        assert_eq!(cpu.registers.a, a);
+        // 804 T-cycles in all
+        assert_eq!(cpu.bus.timer.read_divider(), 3);
+        assert_ne!(cpu.bus.gpu.line, line);
+    }
+
+    #[test]
+    fn halt_wakes_without_ime() {
+        let mut cpu = cpu(&[HALT, INC_A], 0b00100, 0);
+        let (a, sp) = (cpu.registers.a, cpu.sp);
+        cpu.step();
+        cpu.step();
+        assert!(cpu.is_halted());
+
+        cpu.bus.interrupt_flag.timer = true;
+        cpu.step();
+        assert!(!cpu.is_halted());
+        // Carries on after the HALT instead of going to the handler, which leaves the interrupt pending
+        assert_eq!(cpu.pc, 0xC002);
+        assert_eq!(cpu.registers.a, a.wrapping_add(1));
+        assert_eq!(cpu.sp, sp);
+        assert!(cpu.bus.interrupt_flag.timer);
+    }
+
+    #[test]
+    fn halt_bug_reads_the_next_byte_twice() {
+        let mut cpu = cpu(&[HALT, INC_A], 0b00001, 0b00001);
+        let a = cpu.registers.a;
+        cpu.step();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.pc, 0xC001);
+        cpu.step();
+        assert_eq!(cpu.pc, 0xC001);
+        cpu.step();
+        assert_eq!(cpu.pc, 0xC002);
+        assert_eq!(cpu.registers.a, a.wrapping_add(2));
+    }
+
+    const STOP: [u8; 2] = [0x10, 0x00];
+
+    #[test]
+    fn stop_switches_speed() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut cpu = CPU::new(None, rom);
+        cpu.bus.write_byte(0xC000, STOP[0]);
+        cpu.bus.write_byte(0xC001, STOP[1]);
+        cpu.pc = 0xC000;
+        cpu.bus.write(0xFF4D, 0x01);
+        assert_eq!(cpu.bus.read(0xFF4D), 0x7F);
+
+        cpu.step();
+        assert!(cpu.bus.double_speed);
+        assert!(!cpu.is_stopped());
+        assert_eq!(cpu.pc, 0xC002);
+        // Bit 7 now says double speed and the switch is no longer armed
+        assert_eq!(cpu.bus.read(0xFF4D), 0xFE);
+        assert_eq!(cpu.bus.read(0xFF04), 0);
+    }
+
+    #[test]
+    fn stop_until_a_button_is_pressed() {
+        let mut cpu = cpu(&[STOP[0], STOP[1], INC_A], 0, 0);
+        let a = cpu.registers.a;
+        // Only the buttons are selected
+        cpu.bus.write(0xFF00, 0x10);
+        cpu.step();
+        assert!(cpu.is_stopped());
+        for _ in 0..100 {
+            assert_eq!(cpu.step(), 4);
+        }
+        // The divider is stopped along with everything else
+        assert_eq!(cpu.bus.read(0xFF04), 0);
+
+        // Not on a selected line
+        cpu.press(Button::Left);
+        cpu.step();
+        assert!(cpu.is_stopped());
+        assert_eq!(cpu.registers.a, a);
+
+        cpu.press(Button::Start);
+        cpu.step();
+        assert!(!cpu.is_stopped());
+        assert_eq!(cpu.registers.a, a.wrapping_add(1));
+        assert_eq!(cpu.pc, 0xC003);
+    }
+
+    #[test]
+    fn post_boot_state() {
+        // AF BC DE HL, SC, NR52 and DIV for each model, the ROM's header checksum is 0
+        let models = [
+            (Model::DMG, [0x0180, 0x0013, 0x00D8, 0x014D], 0x7E, 0xF1, 0xAB),
+            (Model::SGB, [0x0100, 0x0014, 0x0000, 0xC060], 0x7E, 0xF0, 0xD8),
+            (Model::CGB, [0x1180, 0x0000, 0x0008, 0x007C], 0x7F, 0xF1, 0x1E),
+        ];
+        let common = [
+            (0xFF00, 0xCF), (0xFF01, 0x00), (0xFF05, 0x00), (0xFF06, 0x00), (0xFF07, 0xF8), (0xFF0F, 0xE1),
+            (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0xBF),
+            (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF), (0xFF19, 0xBF),
+            (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0xBF),
+            (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0xBF), (0xFF24, 0x77), (0xFF25, 0xF3),
+            (0xFF40, 0x91), (0xFF41, 0x85), (0xFF42, 0x00), (0xFF43, 0x00), (0xFF44, 0x00), (0xFF45, 0x00),
+            (0xFF47, 0xFC), (0xFF48, 0xFF), (0xFF49, 0xFF), (0xFF4A, 0x00), (0xFF4B, 0x00),
+        ];
+        for &(model, registers, serial_control, sound_on, divider) in models.iter() {
+            let mut cpu = CPU::with_model(None, vec![0; 0x8000], model);
+            let actual = [cpu.registers.get_af(), cpu.registers.get_bc(), cpu.registers.get_de(), cpu.registers.get_hl()];
+            assert_eq!(actual, registers, "{:?}", model);
+            assert_eq!((cpu.sp, cpu.pc), (0xFFFE, 0x0100));
+            assert!(!cpu.interrupts_enabled);
+            assert_eq!(cpu.bus.read(0xFF02), serial_control, "{:?}", model);
+            assert_eq!(cpu.bus.read(0xFF26), sound_on, "{:?}", model);
+            assert_eq!(cpu.bus.read(0xFF04), divider, "{:?}", model);
+            for &(addr, value) in common.iter() {
+                assert_eq!(cpu.bus.read(addr), value, "{:?} {:04X}", model, addr);
+            }
+            assert_eq!(cpu.bus.interrupt_enable.to_byte() & 0x1F, 0);
+            assert!(cpu.bus.gpu.mode == crate::gpu::Mode::VerticalBlank);
+        }
+    }
+}
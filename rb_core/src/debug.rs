@@ -0,0 +1,22 @@
+// Addresses a debugger wants to hear about when the CPU reads or writes them
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, address: u16, write: bool) -> bool {
+        (self.start..=self.end).contains(&address) && if write { self.write } else { self.read }
+    }
+}
+
+// The first access that matched a watchpoint since the debugger last looked
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
@@ -0,0 +1,691 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use super::instructions::*;
+
+// Bytes per "db" line of data
+const DATA_LINE_LENGTH: usize = 8;
+
+// One decoded instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct Disassembled {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    // RGBDS syntax, e.g. "ld a, [$FF44]"
+    pub text: String,
+    // T-cycles, for conditional instructions when the condition is met
+    pub cycles: u8,
+    // T-cycles when the condition isn't met
+    pub cycles_not_taken: Option<u8>,
+    // Whether the next instruction can run after this one
+    pub falls_through: bool,
+    // Where a jump, call or RST goes
+    pub target: Option<u16>,
+    // An address in the operands that can be shown as a label, e.g. $C000 in "ld a, [$C000]"
+    pub operand: Option<u16>,
+}
+
+impl Disassembled {
+    pub fn length(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    // Shows the address operand as a label instead, "call $0200" becomes "call UpdateScore"
+    pub fn label_operand(&mut self, label: &str) {
+        if let Some(operand) = self.operand {
+            self.text = self.text.replace(&format!("${:04X}", operand), label);
+        }
+    }
+}
+
+impl fmt::Display for Disassembled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ");
+        write!(f, "{:04X}  {:<9} {}", self.address, bytes, self.text)
+    }
+}
+
+// A line of a listing, recursive descent tells code and data apart
+#[derive(Clone, Debug, PartialEq)]
+pub enum Line {
+    Code(Disassembled),
+    Data(u16, Vec<u8>),
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line::Code(instruction) => instruction.fmt(f),
+            Line::Data(address, bytes) => {
+                let values = bytes.iter().map(|byte| format!("${:02X}", byte)).collect::<Vec<_>>().join(", ");
+                write!(f, "{:04X}  {:<9} db {}", address, "", values)
+            }
+        }
+    }
+}
+
+// Decodes the instruction at the address, memory is anything that can read a byte,
+// e.g. |address| rom[address as usize] or the bus
+pub fn disassemble<F: FnMut(u16) -> u8>(memory: &mut F, address: u16) -> Disassembled {
+    let opcode = memory(address);
+    let prefixed = opcode == 0xCB;
+    let byte = |offset: u16, memory: &mut F| memory(address.wrapping_add(offset));
+    let instruction = if prefixed {
+        Instruction::from_byte(byte(1, memory), true)
+    } else {
+        Instruction::from_byte(opcode, false)
+    };
+
+    let mut decoded = Disassembled {
+        address,
+        bytes: vec![opcode],
+        text: String::new(),
+        cycles: 4,
+        cycles_not_taken: None,
+        falls_through: true,
+        target: None,
+        operand: None,
+    };
+    let instruction = match instruction {
+        Some(instruction) => instruction,
+        None => {
+            // The CPU locks up on the unused opcodes
+            decoded.text = format!("db ${:02X}", opcode);
+            decoded.falls_through = false;
+            return decoded;
+        }
+    };
+    let length = if prefixed { 2 } else { operand_length(&instruction) + 1 };
+    decoded.bytes = (0..length).map(|offset| byte(offset, memory)).collect();
+
+    let d8 = decoded.bytes.get(1).copied().unwrap_or(0);
+    let d16 = u16::from_le_bytes([d8, decoded.bytes.get(2).copied().unwrap_or(0)]);
+    let next = address.wrapping_add(length);
+    let (text, cycles) = match instruction {
+        Instruction::INC(target) => (format!("inc {}", inc_dec(&target)), inc_dec_cycles(&target)),
+        Instruction::DEC(target) => (format!("dec {}", inc_dec(&target)), inc_dec_cycles(&target)),
+        Instruction::ADD(target) => (format!("add a, {}", arithmetic(&target, d8)), arithmetic_cycles(&target)),
+        Instruction::ADC(target) => (format!("adc a, {}", arithmetic(&target, d8)), arithmetic_cycles(&target)),
+        Instruction::SUB(target) => (format!("sub {}", arithmetic(&target, d8)), arithmetic_cycles(&target)),
+        Instruction::SBC(target) => (format!("sbc a, {}", arithmetic(&target, d8)), arithmetic_cycles(&target)),
+        Instruction::AND(target) => (format!("and {}", arithmetic(&target, d8)), arithmetic_cycles(&target)),
+        Instruction::OR(target) => (format!("or {}", arithmetic(&target, d8)), arithmetic_cycles(&target)),
+        Instruction::XOR(target) => (format!("xor {}", arithmetic(&target, d8)), arithmetic_cycles(&target)),
+        Instruction::CP(target) => (format!("cp {}", arithmetic(&target, d8)), arithmetic_cycles(&target)),
+        Instruction::ADDHL(target) => {
+            let register = match target {
+                ADDHLTarget::BC => "bc",
+                ADDHLTarget::DE => "de",
+                ADDHLTarget::HL => "hl",
+                ADDHLTarget::SP => "sp",
+            };
+            (format!("add hl, {}", register), 8)
+        }
+        Instruction::ADDSP => (format!("add sp, {}", signed(d8)), 16),
+        Instruction::CCF => ("ccf".to_string(), 4),
+        Instruction::SCF => ("scf".to_string(), 4),
+        Instruction::RRA => ("rra".to_string(), 4),
+        Instruction::RLA => ("rla".to_string(), 4),
+        Instruction::RRCA => ("rrca".to_string(), 4),
+        Instruction::RLCA => ("rlca".to_string(), 4),
+        Instruction::CPL => ("cpl".to_string(), 4),
+        Instruction::DAA => ("daa".to_string(), 4),
+
+        Instruction::BIT(target, position) => {
+            let cycles = if matches!(target, PreFixTarget::HLI) { 12 } else { 8 };
+            (format!("bit {}, {}", u8::from(position), prefixed_target(&target)), cycles)
+        }
+        Instruction::RES(target, position) => {
+            (format!("res {}, {}", u8::from(position), prefixed_target(&target)), prefixed_cycles(&target))
+        }
+        Instruction::SET(target, position) => {
+            (format!("set {}, {}", u8::from(position), prefixed_target(&target)), prefixed_cycles(&target))
+        }
+        Instruction::SRL(target) => (format!("srl {}", prefixed_target(&target)), prefixed_cycles(&target)),
+        Instruction::RR(target) => (format!("rr {}", prefixed_target(&target)), prefixed_cycles(&target)),
+        Instruction::RL(target) => (format!("rl {}", prefixed_target(&target)), prefixed_cycles(&target)),
+        Instruction::RRC(target) => (format!("rrc {}", prefixed_target(&target)), prefixed_cycles(&target)),
+        Instruction::RLC(target) => (format!("rlc {}", prefixed_target(&target)), prefixed_cycles(&target)),
+        Instruction::SRA(target) => (format!("sra {}", prefixed_target(&target)), prefixed_cycles(&target)),
+        Instruction::SLA(target) => (format!("sla {}", prefixed_target(&target)), prefixed_cycles(&target)),
+        Instruction::SWAP(target) => (format!("swap {}", prefixed_target(&target)), prefixed_cycles(&target)),
+
+        Instruction::JP(test) => {
+            decoded.target = Some(d16);
+            decoded.operand = Some(d16);
+            branch(&mut decoded, "jp", test, format!("${:04X}", d16), (16, 12))
+        }
+        Instruction::JR(test) => {
+            let destination = next.wrapping_add(d8 as i8 as u16);
+            decoded.target = Some(destination);
+            decoded.operand = Some(destination);
+            branch(&mut decoded, "jr", test, format!("${:04X}", destination), (12, 8))
+        }
+        Instruction::JPI => {
+            decoded.falls_through = false;
+            ("jp hl".to_string(), 4)
+        }
+        Instruction::CALL(test) => {
+            decoded.target = Some(d16);
+            decoded.operand = Some(d16);
+            let (text, cycles) = branch(&mut decoded, "call", test, format!("${:04X}", d16), (24, 12));
+            // The routine usually comes back
+            decoded.falls_through = true;
+            (text, cycles)
+        }
+        Instruction::RET(JumpTest::Always) => {
+            decoded.falls_through = false;
+            ("ret".to_string(), 16)
+        }
+        Instruction::RET(test) => {
+            decoded.cycles_not_taken = Some(8);
+            (format!("ret {}", condition(test)), 20)
+        }
+        Instruction::RETI => {
+            decoded.falls_through = false;
+            ("reti".to_string(), 16)
+        }
+        Instruction::RST(location) => {
+            decoded.target = Some(location.to_hex());
+            (format!("rst ${:02X}", location.to_hex()), 16)
+        }
+
+        Instruction::LD(load) => {
+            decoded.operand = load_operand(&load, d8, d16);
+            load_text(load, d8, d16)
+        }
+        Instruction::PUSH(target) => (format!("push {}", stack(&target)), 16),
+        Instruction::POP(target) => (format!("pop {}", stack(&target)), 12),
+
+        Instruction::HALT => ("halt".to_string(), 4),
+        Instruction::STOP => ("stop".to_string(), 4),
+        Instruction::NOP => ("nop".to_string(), 4),
+        Instruction::DI => ("di".to_string(), 4),
+        Instruction::EI => ("ei".to_string(), 4),
+    };
+    decoded.text = text;
+    decoded.cycles = cycles;
+    decoded
+}
+
+// Decodes instruction after instruction without looking at what they do
+pub fn linear_sweep<F: FnMut(u16) -> u8>(memory: &mut F, range: RangeInclusive<u16>) -> Vec<Disassembled> {
+    let mut instructions = Vec::new();
+    let mut address = *range.start() as u32;
+    while address <= *range.end() as u32 {
+        let instruction = disassemble(memory, address as u16);
+        address += instruction.length() as u32;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+// Follows jumps, calls and RSTs from the entry points to find which bytes in the range are
+// code. Everything that is never reached, like graphics or tables, becomes data. Jumps out of
+// the range, e.g. from a switchable bank into bank 0, aren't followed.
+pub fn recursive_descent<F: FnMut(u16) -> u8>(memory: &mut F, range: RangeInclusive<u16>, entry_points: &[u16]) -> Vec<Line> {
+    let mut code = BTreeMap::new();
+    let mut covered = vec![false; *range.end() as usize - *range.start() as usize + 1];
+    let mut pending = entry_points.iter().rev().copied().collect::<Vec<_>>();
+
+    while let Some(mut address) = pending.pop() {
+        loop {
+            if !range.contains(&address) || covered[(address - range.start()) as usize] {
+                break;
+            }
+            let instruction = disassemble(memory, address);
+            let end = address as u32 + instruction.length() as u32 - 1;
+            // Stop before decoding an instruction that runs into another one or out of the range
+            if end > *range.end() as u32
+                || (address as u32..=end).any(|byte| covered[(byte - *range.start() as u32) as usize])
+            {
+                break;
+            }
+            for byte in address as u32..=end {
+                covered[(byte - *range.start() as u32) as usize] = true;
+            }
+            if let Some(target) = instruction.target {
+                pending.push(target);
+            }
+            let falls_through = instruction.falls_through;
+            address = instruction.address.wrapping_add(instruction.length());
+            code.insert(instruction.address, instruction);
+            if !falls_through {
+                break;
+            }
+        }
+    }
+
+    let mut lines = Vec::new();
+    let mut data: Vec<u8> = Vec::new();
+    let mut data_start = *range.start();
+    let mut address = *range.start() as u32;
+    while address <= *range.end() as u32 {
+        match code.remove(&(address as u16)) {
+            Some(instruction) => {
+                if !data.is_empty() {
+                    lines.push(Line::Data(data_start, std::mem::take(&mut data)));
+                }
+                address += instruction.length() as u32;
+                lines.push(Line::Code(instruction));
+            }
+            None => {
+                if data.is_empty() {
+                    data_start = address as u16;
+                }
+                data.push(memory(address as u16));
+                if data.len() == DATA_LINE_LENGTH {
+                    lines.push(Line::Data(data_start, std::mem::take(&mut data)));
+                }
+                address += 1;
+            }
+        }
+    }
+    if !data.is_empty() {
+        lines.push(Line::Data(data_start, data));
+    }
+    lines
+}
+
+fn operand_length(instruction: &Instruction) -> u16 {
+    match instruction {
+        Instruction::ADD(ArithmeticTarget::D8)
+        | Instruction::ADC(ArithmeticTarget::D8)
+        | Instruction::SUB(ArithmeticTarget::D8)
+        | Instruction::SBC(ArithmeticTarget::D8)
+        | Instruction::AND(ArithmeticTarget::D8)
+        | Instruction::OR(ArithmeticTarget::D8)
+        | Instruction::XOR(ArithmeticTarget::D8)
+        | Instruction::CP(ArithmeticTarget::D8)
+        | Instruction::ADDSP
+        | Instruction::JR(_)
+        | Instruction::LD(LoadType::Byte(_, LoadByteSource::D8))
+        | Instruction::LD(LoadType::AFromByteAddress)
+        | Instruction::LD(LoadType::ByteAddressFromA)
+        | Instruction::LD(LoadType::HLFromSPN)
+        // STOP is followed by a byte the CPU skips
+        | Instruction::STOP => 1,
+        Instruction::JP(_)
+        | Instruction::CALL(_)
+        | Instruction::LD(LoadType::Word(_))
+        | Instruction::LD(LoadType::AFromIndirect(Indirect::WordIndirect))
+        | Instruction::LD(LoadType::IndirectFromA(Indirect::WordIndirect))
+        | Instruction::LD(LoadType::IndirectFromSP) => 2,
+        _ => 0,
+    }
+}
+
+fn branch(decoded: &mut Disassembled, mnemonic: &str, test: JumpTest, operand: String, cycles: (u8, u8)) -> (String, u8) {
+    if test == JumpTest::Always {
+        decoded.falls_through = false;
+        (format!("{} {}", mnemonic, operand), cycles.0)
+    } else {
+        decoded.cycles_not_taken = Some(cycles.1);
+        (format!("{} {}, {}", mnemonic, condition(test), operand), cycles.0)
+    }
+}
+
+fn load_operand(load: &LoadType, d8: u8, d16: u16) -> Option<u16> {
+    match load {
+        LoadType::Word(_)
+        | LoadType::AFromIndirect(Indirect::WordIndirect)
+        | LoadType::IndirectFromA(Indirect::WordIndirect)
+        | LoadType::IndirectFromSP => Some(d16),
+        LoadType::AFromByteAddress | LoadType::ByteAddressFromA => Some(0xFF00 | d8 as u16),
+        _ => None,
+    }
+}
+
+fn load_text(load: LoadType, d8: u8, d16: u16) -> (String, u8) {
+    match load {
+        LoadType::Byte(target, source) => {
+            let target_name = match target {
+                LoadByteTarget::A => "a",
+                LoadByteTarget::B => "b",
+                LoadByteTarget::C => "c",
+                LoadByteTarget::D => "d",
+                LoadByteTarget::E => "e",
+                LoadByteTarget::H => "h",
+                LoadByteTarget::L => "l",
+                LoadByteTarget::HLI => "[hl]",
+            };
+            let source_name = match source {
+                LoadByteSource::A => "a".to_string(),
+                LoadByteSource::B => "b".to_string(),
+                LoadByteSource::C => "c".to_string(),
+                LoadByteSource::D => "d".to_string(),
+                LoadByteSource::E => "e".to_string(),
+                LoadByteSource::H => "h".to_string(),
+                LoadByteSource::L => "l".to_string(),
+                LoadByteSource::HLI => "[hl]".to_string(),
+                LoadByteSource::D8 => format!("${:02X}", d8),
+            };
+            let cycles = match (target, source) {
+                (LoadByteTarget::HLI, LoadByteSource::D8) => 12,
+                (LoadByteTarget::HLI, _) | (_, LoadByteSource::HLI) | (_, LoadByteSource::D8) => 8,
+                _ => 4,
+            };
+            (format!("ld {}, {}", target_name, source_name), cycles)
+        }
+        LoadType::Word(target) => {
+            let register = match target {
+                LoadWordTarget::BC => "bc",
+                LoadWordTarget::DE => "de",
+                LoadWordTarget::HL => "hl",
+                LoadWordTarget::SP => "sp",
+            };
+            (format!("ld {}, ${:04X}", register, d16), 12)
+        }
+        LoadType::AFromIndirect(Indirect::LastByteIndirect) => ("ldh a, [c]".to_string(), 8),
+        LoadType::IndirectFromA(Indirect::LastByteIndirect) => ("ldh [c], a".to_string(), 8),
+        LoadType::AFromIndirect(indirect) => {
+            let cycles = if matches!(indirect, Indirect::WordIndirect) { 16 } else { 8 };
+            (format!("ld a, {}", indirect_text(&indirect, d16)), cycles)
+        }
+        LoadType::IndirectFromA(indirect) => {
+            let cycles = if matches!(indirect, Indirect::WordIndirect) { 16 } else { 8 };
+            (format!("ld {}, a", indirect_text(&indirect, d16)), cycles)
+        }
+        LoadType::AFromByteAddress => (format!("ldh a, [${:04X}]", 0xFF00 | d8 as u16), 12),
+        LoadType::ByteAddressFromA => (format!("ldh [${:04X}], a", 0xFF00 | d8 as u16), 12),
+        LoadType::SPFromHL => ("ld sp, hl".to_string(), 8),
+        LoadType::HLFromSPN => {
+            let offset = d8 as i8;
+            let sign = if offset < 0 { '-' } else { '+' };
+            (format!("ld hl, sp{}${:02X}", sign, offset.unsigned_abs()), 12)
+        }
+        LoadType::IndirectFromSP => (format!("ld [${:04X}], sp", d16), 20),
+    }
+}
+
+fn indirect_text(indirect: &Indirect, d16: u16) -> String {
+    match indirect {
+        Indirect::BCIndirect => "[bc]".to_string(),
+        Indirect::DEIndirect => "[de]".to_string(),
+        Indirect::HLIndirectMinus => "[hl-]".to_string(),
+        Indirect::HLIndirectPlus => "[hl+]".to_string(),
+        Indirect::WordIndirect => format!("[${:04X}]", d16),
+        Indirect::LastByteIndirect => "[c]".to_string(),
+    }
+}
+
+fn condition(test: JumpTest) -> &'static str {
+    match test {
+        JumpTest::NotZero => "nz",
+        JumpTest::Zero => "z",
+        JumpTest::NotCarry => "nc",
+        JumpTest::Carry => "c",
+        JumpTest::Always => "",
+    }
+}
+
+fn signed(value: u8) -> String {
+    let value = value as i8;
+    if value < 0 {
+        format!("-${:02X}", value.unsigned_abs())
+    } else {
+        format!("${:02X}", value)
+    }
+}
+
+fn arithmetic(target: &ArithmeticTarget, d8: u8) -> String {
+    match target {
+        ArithmeticTarget::A => "a".to_string(),
+        ArithmeticTarget::B => "b".to_string(),
+        ArithmeticTarget::C => "c".to_string(),
+        ArithmeticTarget::D => "d".to_string(),
+        ArithmeticTarget::E => "e".to_string(),
+        ArithmeticTarget::F => "f".to_string(),
+        ArithmeticTarget::H => "h".to_string(),
+        ArithmeticTarget::L => "l".to_string(),
+        ArithmeticTarget::D8 => format!("${:02X}", d8),
+        ArithmeticTarget::HLI => "[hl]".to_string(),
+    }
+}
+
+fn arithmetic_cycles(target: &ArithmeticTarget) -> u8 {
+    match target {
+        ArithmeticTarget::D8 | ArithmeticTarget::HLI => 8,
+        _ => 4,
+    }
+}
+
+fn inc_dec(target: &IncDecTarget) -> &'static str {
+    match target {
+        IncDecTarget::A => "a",
+        IncDecTarget::B => "b",
+        IncDecTarget::C => "c",
+        IncDecTarget::D => "d",
+        IncDecTarget::E => "e",
+        IncDecTarget::F => "f",
+        IncDecTarget::H => "h",
+        IncDecTarget::L => "l",
+        IncDecTarget::HLI => "[hl]",
+        IncDecTarget::BC => "bc",
+        IncDecTarget::DE => "de",
+        IncDecTarget::HL => "hl",
+        IncDecTarget::SP => "sp",
+    }
+}
+
+fn inc_dec_cycles(target: &IncDecTarget) -> u8 {
+    match target {
+        IncDecTarget::HLI => 12,
+        IncDecTarget::BC | IncDecTarget::DE | IncDecTarget::HL | IncDecTarget::SP => 8,
+        _ => 4,
+    }
+}
+
+fn prefixed_target(target: &PreFixTarget) -> &'static str {
+    match target {
+        PreFixTarget::A => "a",
+        PreFixTarget::B => "b",
+        PreFixTarget::C => "c",
+        PreFixTarget::D => "d",
+        PreFixTarget::E => "e",
+        PreFixTarget::H => "h",
+        PreFixTarget::L => "l",
+        PreFixTarget::HLI => "[hl]",
+    }
+}
+
+fn prefixed_cycles(target: &PreFixTarget) -> u8 {
+    match target {
+        PreFixTarget::HLI => 16,
+        _ => 8,
+    }
+}
+
+fn stack(target: &StackTarget) -> &'static str {
+    match target {
+        StackTarget::AF => "af",
+        StackTarget::BC => "bc",
+        StackTarget::DE => "de",
+        StackTarget::HL => "hl",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatBus;
+    use crate::cpu::CPU;
+
+    fn decode(bytes: &[u8], address: u16) -> Disassembled {
+        let mut memory = |at: u16| bytes.get(at.wrapping_sub(address) as usize).copied().unwrap_or(0);
+        disassemble(&mut memory, address)
+    }
+
+    // Runs the instruction on a flat bus, returns the T-cycles it took and where PC ended up
+    fn run(bytes: &[u8], flags: u8) -> (u8, u16) {
+        let mut bus = FlatBus::new();
+        bus.memory[0x100..0x100 + bytes.len()].copy_from_slice(bytes);
+        let mut cpu = CPU::with_bus(bus);
+        cpu.pc = 0x100;
+        cpu.sp = 0xD000;
+        cpu.registers.set_af(flags as u16);
+        let cycles = cpu.step();
+        (cycles as u8, cpu.pc)
+    }
+
+    // Taken and not taken both show up with one of the flag settings
+    fn check_against_cpu(bytes: &[u8]) {
+        let decoded = decode(bytes, 0x100);
+        let mut expected = vec![decoded.cycles];
+        expected.extend(decoded.cycles_not_taken);
+        let mut seen = Vec::new();
+        for flags in [0x00, 0xF0] {
+            let (cycles, pc) = run(bytes, flags);
+            assert!(expected.contains(&cycles), "{} took {} cycles", decoded.text, cycles);
+            if !seen.contains(&cycles) {
+                seen.push(cycles);
+            }
+            let conditional = decoded.cycles_not_taken.is_some();
+            let taken = !conditional || cycles == decoded.cycles;
+            match decoded.target {
+                Some(target) if taken => assert_eq!(pc, target, "{} goes to {:04X}", decoded.text, target),
+                // Returns and JP HL go wherever
+                _ if taken && (conditional || !decoded.falls_through) => {}
+                _ => assert_eq!(pc, 0x100 + decoded.length(), "{} is {} bytes long", decoded.text, decoded.length()),
+            }
+        }
+        if decoded.cycles_not_taken.is_some() {
+            assert_eq!(seen.len(), 2, "{} always took {:?} cycles", decoded.text, seen);
+        }
+    }
+
+    #[test]
+    fn length_and_cycles_match_the_cpu() {
+        for opcode in 0..=0xFFu8 {
+            let bytes = [opcode, 0x34, 0x12];
+            if opcode == 0xCB || decode(&bytes, 0x100).text.starts_with("db") {
+                continue;
+            }
+            check_against_cpu(&bytes);
+        }
+    }
+
+    #[test]
+    fn prefixed_length_and_cycles_match_the_cpu() {
+        for opcode in 0..=0xFFu8 {
+            let decoded = decode(&[0xCB, opcode], 0x100);
+            assert_eq!(decoded.length(), 2, "{}", decoded.text);
+            check_against_cpu(&[0xCB, opcode]);
+        }
+    }
+
+    #[test]
+    fn rgbds_syntax() {
+        let cases: &[(&[u8], &str)] = &[
+            (&[0xE0, 0x44], "ldh [$FF44], a"),
+            (&[0xF0, 0x44], "ldh a, [$FF44]"),
+            (&[0xE2], "ldh [c], a"),
+            (&[0xF2], "ldh a, [c]"),
+            (&[0x22], "ld [hl+], a"),
+            (&[0x3A], "ld a, [hl-]"),
+            (&[0xFA, 0x00, 0xC0], "ld a, [$C000]"),
+            (&[0x08, 0x00, 0xC0], "ld [$C000], sp"),
+            (&[0x21, 0x34, 0x12], "ld hl, $1234"),
+            (&[0xF8, 0xFB], "ld hl, sp-$05"),
+            (&[0xF8, 0x05], "ld hl, sp+$05"),
+            (&[0xE8, 0xFB], "add sp, -$05"),
+            (&[0x80], "add a, b"),
+            (&[0x8E], "adc a, [hl]"),
+            (&[0x90], "sub b"),
+            (&[0xFE, 0x10], "cp $10"),
+            (&[0xCB, 0x7C], "bit 7, h"),
+            (&[0xCB, 0x36], "swap [hl]"),
+            (&[0xFF], "rst $38"),
+            (&[0xC2, 0x00, 0x02], "jp nz, $0200"),
+            (&[0xE9], "jp hl"),
+            (&[0xDC, 0x00, 0x02], "call c, $0200"),
+            (&[0xC8], "ret z"),
+            (&[0xD3], "db $D3"),
+        ];
+        for (bytes, text) in cases {
+            assert_eq!(decode(bytes, 0x100).text, *text);
+        }
+        // Relative jumps show where they land
+        assert_eq!(decode(&[0x20, 0x02], 0x0120).text, "jr nz, $0124");
+        assert_eq!(decode(&[0x18, 0xFE], 0x0150).text, "jr $0150");
+    }
+
+    #[test]
+    fn operands_become_labels() {
+        let mut call = decode(&[0xCD, 0x00, 0x02], 0x100);
+        assert_eq!(call.operand, Some(0x0200));
+        call.label_operand("UpdateScore");
+        assert_eq!(call.text, "call UpdateScore");
+        let mut load = decode(&[0xE0, 0x80], 0x100);
+        load.label_operand("hFrameCounter");
+        assert_eq!(load.text, "ldh [hFrameCounter], a");
+        assert_eq!(decode(&[0x3E, 0x02], 0x100).operand, None);
+    }
+
+    // Calls a routine that jumps through a table of two handlers with JP HL
+    const JUMP_TABLE: [u8; 0x20] = [
+        0x21, 0x0A, 0x00, // 0000  ld hl, $000A
+        0xCD, 0x10, 0x00, // 0003  call $0010
+        0x18, 0xFE, //       0006  jr $0006
+        0x00, 0x00, //       0008
+        0x14, 0x00, 0x18, 0x00, // 000A  dw $0014, $0018
+        0x00, 0x00, //       000E
+        0x2A, //             0010  ld a, [hl+]
+        0x66, //             0011  ld h, [hl]
+        0x6F, //             0012  ld l, a
+        0xE9, //             0013  jp hl
+        0x3C, 0xC9, //       0014  inc a, ret
+        0x00, 0x00, //       0016
+        0x3D, 0xC9, //       0018  dec a, ret
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    fn listing(entry_points: &[u16]) -> Vec<String> {
+        let mut memory = |address: u16| JUMP_TABLE[address as usize];
+        recursive_descent(&mut memory, 0x0000..=0x001F, entry_points)
+            .iter()
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn recursive_descent_splits_code_and_data() {
+        // Where JP HL goes isn't known, the handlers stay data
+        assert_eq!(
+            listing(&[0x0000]),
+            [
+                "0000  21 0A 00  ld hl, $000A",
+                "0003  CD 10 00  call $0010",
+                "0006  18 FE     jr $0006",
+                "0008            db $00, $00, $14, $00, $18, $00, $00, $00",
+                "0010  2A        ld a, [hl+]",
+                "0011  66        ld h, [hl]",
+                "0012  6F        ld l, a",
+                "0013  E9        jp hl",
+                "0014            db $3C, $C9, $00, $00, $3D, $C9, $00, $00",
+                "001C            db $00, $00, $00, $00",
+            ]
+        );
+        // Until they are given as entry points
+        assert_eq!(
+            listing(&[0x0000, 0x0014, 0x0018])[8..],
+            [
+                "0014  3C        inc a",
+                "0015  C9        ret",
+                "0016            db $00, $00",
+                "0018  3D        dec a",
+                "0019  C9        ret",
+                "001A            db $00, $00, $00, $00, $00, $00",
+            ]
+        );
+    }
+
+    #[test]
+    fn linear_sweep_decodes_everything() {
+        let mut memory = |address: u16| JUMP_TABLE[address as usize];
+        let instructions = linear_sweep(&mut memory, 0x0000..=0x0009);
+        let addresses = instructions.iter().map(|instruction| instruction.address).collect::<Vec<_>>();
+        assert_eq!(addresses, [0x0000, 0x0003, 0x0006, 0x0008, 0x0009]);
+    }
+}
@@ -0,0 +1,200 @@
+use super::postprocess::*;
+
+// The dot matrix of the DMG screen, every pixel is drawn as a block with a darker gap
+// on its right and bottom edge where the LCD has no liquid crystal
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LcdGrid {
+    pub scale: usize,
+    // How bright the gaps are compared to the pixel, 0 is black
+    pub gap_brightness: f32,
+}
+
+impl Default for LcdGrid {
+    fn default() -> Self {
+        LcdGrid {
+            scale: 3,
+            gap_brightness: 0.75,
+        }
+    }
+}
+
+impl PostProcess for LcdGrid {
+    fn process(&mut self, frame: Frame) -> Frame {
+        let scale = self.scale.max(2);
+        let mut output = Frame::new(frame.width * scale, frame.height * scale);
+        for y in 0..output.height {
+            for x in 0..output.width {
+                let source = ((y / scale) * frame.width + x / scale) * 4;
+                let destination = (y * output.width + x) * 4;
+                let gap = x % scale == scale - 1 || y % scale == scale - 1;
+                let brightness = if gap { self.gap_brightness } else { 1.0 };
+                for channel in 0..3 {
+                    output.pixels[destination + channel] = (frame.pixels[source + channel] as f32 * brightness) as u8;
+                }
+                output.pixels[destination + 3] = frame.pixels[source + 3];
+            }
+        }
+        output
+    }
+}
+
+// The CGB screen is a lot less saturated than a modern monitor, games were coloured for that.
+// Mixes the channels like the LCD does and darkens it a bit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ColorCorrection {
+    pub matrix: [[f32; 3]; 3],
+}
+
+impl Default for ColorCorrection {
+    fn default() -> Self {
+        ColorCorrection {
+            matrix: [
+                [26.0 / 32.0, 4.0 / 32.0, 2.0 / 32.0],
+                [0.0, 24.0 / 32.0, 8.0 / 32.0],
+                [6.0 / 32.0, 4.0 / 32.0, 22.0 / 32.0],
+            ],
+        }
+    }
+}
+
+impl PostProcess for ColorCorrection {
+    fn process(&mut self, mut frame: Frame) -> Frame {
+        for pixel in frame.pixels.chunks_mut(4) {
+            let rgb = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+            for (channel, row) in self.matrix.iter().enumerate() {
+                let value = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2];
+                pixel[channel] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        frame
+    }
+}
+
+// A cheap CRT look: darker lines between the scanlines and a screen bulging out in the middle.
+// Works best after an upscaler so every Game Boy line covers several rows.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Crt {
+    // How much darker every other row gets, 0 turns scanlines off
+    pub scanline_strength: f32,
+    // 0 is flat, 0.1 is about an old television
+    pub curvature: f32,
+}
+
+impl Default for Crt {
+    fn default() -> Self {
+        Crt {
+            scanline_strength: 0.3,
+            curvature: 0.06,
+        }
+    }
+}
+
+impl PostProcess for Crt {
+    fn process(&mut self, frame: Frame) -> Frame {
+        let mut output = Frame::new(frame.width, frame.height);
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let destination = (y * frame.width + x) * 4;
+                let (source_x, source_y) = match self.curve(x, y, frame.width, frame.height) {
+                    Some(position) => position,
+                    None => {
+                        // Outside the bent screen
+                        output.pixels[destination + 3] = 255;
+                        continue;
+                    }
+                };
+                let source = (source_y * frame.width + source_x) * 4;
+                let brightness = if y % 2 == 1 { 1.0 - self.scanline_strength } else { 1.0 };
+                for channel in 0..3 {
+                    output.pixels[destination + channel] = (frame.pixels[source + channel] as f32 * brightness) as u8;
+                }
+                output.pixels[destination + 3] = frame.pixels[source + 3];
+            }
+        }
+        output
+    }
+}
+
+impl Crt {
+    // Barrel distortion, pixels further from the centre are pulled in from further out
+    fn curve(&self, x: usize, y: usize, width: usize, height: usize) -> Option<(usize, usize)> {
+        let u = (x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+        let v = (y as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+        let distortion = 1.0 + self.curvature * (u * u + v * v);
+        let (u, v) = (u * distortion, v * distortion);
+        if u.abs() > 1.0 || v.abs() > 1.0 {
+            return None;
+        }
+        let source_x = ((u + 1.0) / 2.0 * width as f32) as usize;
+        let source_y = ((v + 1.0) / 2.0 * height as f32) as usize;
+        Some((source_x.min(width - 1), source_y.min(height - 1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(width: usize, height: usize, rgba: [u8; 4]) -> Frame {
+        let mut frame = Frame::new(width, height);
+        for pixel in frame.pixels.chunks_mut(4) {
+            pixel.copy_from_slice(&rgba);
+        }
+        frame
+    }
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> &[u8] {
+        let offset = (y * frame.width + x) * 4;
+        &frame.pixels[offset..offset + 4]
+    }
+
+    #[test]
+    fn lcd_grid() {
+        let mut grid = LcdGrid { scale: 3, gap_brightness: 0.5 };
+        let output = grid.process(filled(2, 1, [200, 100, 50, 0xFF]));
+        assert_eq!((output.width, output.height), (6, 3));
+        assert_eq!(pixel(&output, 0, 0), [200, 100, 50, 0xFF]);
+        assert_eq!(pixel(&output, 4, 1), [200, 100, 50, 0xFF]);
+        // The last row and column of every block is the gap
+        assert_eq!(pixel(&output, 2, 0), [100, 50, 25, 0xFF]);
+        assert_eq!(pixel(&output, 3, 2), [100, 50, 25, 0xFF]);
+        assert_eq!(pixel(&output, 5, 2), [100, 50, 25, 0xFF]);
+
+        // Anything smaller than 2 would be nothing but gaps
+        let mut grid = LcdGrid { scale: 1, gap_brightness: 0.0 };
+        assert_eq!(grid.process(filled(1, 1, [0xFF; 4])).width, 2);
+    }
+
+    #[test]
+    fn color_correction() {
+        let mut correction = ColorCorrection::default();
+        assert_eq!(correction.process(filled(1, 1, [0xFF; 4])).pixels, [0xFF; 4]);
+        assert_eq!(correction.process(filled(1, 1, [0, 0, 0, 0xFF])).pixels, [0, 0, 0, 0xFF]);
+        // Red bleeds into blue, 26/32 and 6/32 of 255
+        assert_eq!(correction.process(filled(1, 1, [0xFF, 0, 0, 0x80])).pixels, [207, 0, 48, 0x80]);
+    }
+
+    #[test]
+    fn crt_scanlines() {
+        let mut crt = Crt { scanline_strength: 0.5, curvature: 0.0 };
+        let output = crt.process(filled(2, 4, [200, 100, 50, 0xFF]));
+        assert_eq!((output.width, output.height), (2, 4));
+        assert_eq!(pixel(&output, 0, 0), [200, 100, 50, 0xFF]);
+        assert_eq!(pixel(&output, 1, 1), [100, 50, 25, 0xFF]);
+        assert_eq!(pixel(&output, 1, 2), [200, 100, 50, 0xFF]);
+    }
+
+    #[test]
+    fn crt_curvature() {
+        let mut crt = Crt { scanline_strength: 0.0, curvature: 0.1 };
+        let mut frame = filled(40, 40, [0xFF; 4]);
+        // The centre barely moves, a dark pixel there stays where it is
+        frame.pixels[(20 * 40 + 19) * 4] = 0;
+        let output = crt.process(frame);
+        // The corners fall outside the bent screen and are black
+        assert_eq!(pixel(&output, 0, 0), [0, 0, 0, 0xFF]);
+        assert_eq!(pixel(&output, 39, 39), [0, 0, 0, 0xFF]);
+        assert_eq!(pixel(&output, 20, 20), [0xFF; 4]);
+        assert_eq!(pixel(&output, 19, 20)[0], 0);
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use super::bus::Bus;
+use super::cpu::CPU;
+use super::debug::Watchpoint;
+
+// GDB knows nothing about the SM83, the registers are described to it in this order
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rusty-boi.sm83">
+    <reg name="af" bitsize="16" type="int16"/>
+    <reg name="bc" bitsize="16" type="int16"/>
+    <reg name="de" bitsize="16" type="int16"/>
+    <reg name="hl" bitsize="16" type="int16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+const REGISTER_COUNT: usize = 6;
+// Instructions to run between checks for a Ctrl-C from the client
+const INTERRUPT_POLL_STEPS: u32 = 1024;
+const INTERRUPT: u8 = 0x03;
+
+// Signals in stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+#[derive(Copy, Clone, PartialEq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+// A GDB remote serial protocol server for one client, it runs the CPU instruction by instruction
+// like the terminal debugger does. Addresses above 0xFFFF reach banks that aren't switched in,
+// 0x3D000 is 0xD000 in WRAM bank 3.
+pub struct GdbStub {
+    // None once the client has detached, the game then keeps running without the stub
+    stream: Option<TcpStream>,
+    acknowledge: bool,
+    breakpoints: HashSet<u16>,
+    watchpoints: Vec<(WatchKind, Watchpoint)>,
+    running: bool,
+    single_step: bool,
+    // Lets the instruction at a breakpoint run when continuing from it
+    resuming: bool,
+    steps_since_poll: u32,
+    last_stop: String,
+    // Set when the client asks to kill the target
+    pub quit: bool,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> GdbStub {
+        let _ = stream.set_nodelay(true);
+        GdbStub {
+            stream: Some(stream),
+            acknowledge: true,
+            breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+            running: false,
+            single_step: false,
+            resuming: false,
+            steps_since_poll: 0,
+            last_stop: format!("S{:02x}", SIGTRAP),
+            quit: false,
+        }
+    }
+
+    // Waits for GDB to connect, e.g. "127.0.0.1:2345"
+    pub fn listen(address: &str) -> Result<GdbStub, String> {
+        let listener = TcpListener::bind(address).map_err(|e| format!("Could not listen on {}: {}", address, e))?;
+        let (stream, _) = listener.accept().map_err(|e| format!("Could not accept a GDB connection: {}", e))?;
+        Ok(GdbStub::new(stream))
+    }
+
+    // Runs one instruction, or talks to GDB while the target is stopped. None once GDB killed it.
+    pub fn step(&mut self, cpu: &mut CPU) -> Option<u32> {
+        if self.stream.is_none() {
+            return Some(cpu.step());
+        }
+        if !self.running {
+            if let Err(e) = self.serve(cpu) {
+                eprintln!("GDB connection lost: {}", e);
+                self.detach(cpu);
+            }
+            if self.quit {
+                return None;
+            }
+            if self.stream.is_none() {
+                return Some(cpu.step());
+            }
+        }
+
+        if !std::mem::take(&mut self.resuming) && self.breakpoints.contains(&cpu.pc) {
+            self.stop(format!("T{:02x}swbreak:;", SIGTRAP));
+            return Some(0);
+        }
+
+        let cycles = cpu.step();
+
+        if let Some(hit) = cpu.bus.watch_hit.take() {
+            let kind = self
+                .watchpoints
+                .iter()
+                .find(|(_, watchpoint)| watchpoint.matches(hit.address, hit.write))
+                .map_or(WatchKind::Access, |(kind, _)| *kind);
+            let name = match kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            self.stop(format!("T{:02x}{}:{:x};", SIGTRAP, name, hit.address));
+        } else if self.single_step {
+            self.stop(format!("S{:02x}", SIGTRAP));
+        } else {
+            self.steps_since_poll += 1;
+            if self.steps_since_poll >= INTERRUPT_POLL_STEPS {
+                self.steps_since_poll = 0;
+                self.poll_interrupt(cpu);
+            }
+        }
+        Some(cycles)
+    }
+
+    fn stop(&mut self, reply: String) {
+        self.running = false;
+        if let Err(e) = self.send(&reply) {
+            eprintln!("GDB connection lost: {}", e);
+            self.stream = None;
+        }
+        self.last_stop = reply;
+    }
+
+    fn detach(&mut self, cpu: &mut CPU) {
+        self.stream = None;
+        self.running = false;
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+        cpu.bus.watchpoints.clear();
+    }
+
+    // Checks for a Ctrl-C without waiting for one
+    fn poll_interrupt(&mut self, cpu: &mut CPU) {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return,
+        };
+        let mut byte = [0];
+        let _ = stream.set_nonblocking(true);
+        let result = stream.read(&mut byte);
+        let _ = stream.set_nonblocking(false);
+        match result {
+            Ok(1) if byte[0] == INTERRUPT => self.stop(format!("S{:02x}", SIGINT)),
+            Ok(0) => self.detach(cpu),
+            Err(e) if e.kind() != ErrorKind::WouldBlock => self.detach(cpu),
+            _ => {}
+        }
+    }
+
+    // Answers packets until GDB resumes the target, detaches or kills it
+    fn serve(&mut self, cpu: &mut CPU) -> Result<(), String> {
+        while !self.running && !self.quit && self.stream.is_some() {
+            let packet = self.receive()?;
+            let reply = self.handle(&packet, cpu);
+            if let Some(reply) = reply {
+                self.send(&reply)?;
+            }
+            if packet.starts_with('D') {
+                self.detach(cpu);
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the reply, None for packets that don't get one
+    fn handle(&mut self, packet: &str, cpu: &mut CPU) -> Option<String> {
+        let (command, arguments) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => {
+                let registers = (0..REGISTER_COUNT).map(|register| register_hex(read_register(cpu, register)));
+                registers.collect()
+            }
+            "G" => {
+                let values = (0..REGISTER_COUNT)
+                    .map(|register| arguments.get(register * 4..register * 4 + 4).and_then(parse_register_hex))
+                    .collect::<Option<Vec<_>>>();
+                match values {
+                    Some(values) => {
+                        for (register, value) in values.into_iter().enumerate() {
+                            write_register(cpu, register, value);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "p" => match usize::from_str_radix(arguments, 16) {
+                Ok(register) if register < REGISTER_COUNT => register_hex(read_register(cpu, register)),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = arguments.split_once('=').and_then(|(register, value)| {
+                    Some((usize::from_str_radix(register, 16).ok()?, parse_register_hex(value)?))
+                });
+                match parsed {
+                    Some((register, value)) if register < REGISTER_COUNT => {
+                        write_register(cpu, register, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_address_length(arguments) {
+                Some((address, length)) => {
+                    let bytes = (0..length)
+                        .map(|offset| peek(cpu, address.checked_add(offset)?))
+                        .collect::<Option<Vec<_>>>();
+                    match bytes {
+                        Some(bytes) => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+                        None => "E01".to_string(),
+                    }
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let written = arguments.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_address_length(range)?;
+                    let bytes = parse_hex_bytes(data)?;
+                    if bytes.len() as u32 != length {
+                        return None;
+                    }
+                    let written = bytes.iter().enumerate().all(|(offset, &value)| {
+                        address.checked_add(offset as u32).is_some_and(|address| poke(cpu, address, value))
+                    });
+                    Some(written)
+                });
+                if written == Some(true) { "OK".to_string() } else { "E01".to_string() }
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", arguments, cpu),
+            "c" | "s" => {
+                if let Ok(address) = u16::from_str_radix(arguments, 16) {
+                    cpu.pc = address;
+                }
+                self.running = true;
+                self.single_step = command == "s";
+                self.resuming = true;
+                return None;
+            }
+            "D" => "OK".to_string(),
+            "k" => {
+                self.quit = true;
+                return None;
+            }
+            "H" | "T" => "OK".to_string(),
+            _ => self.query(packet),
+        };
+        Some(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=1000;qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+".to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_address_length(range) {
+                Some((offset, length)) => {
+                    let start = (offset as usize).min(TARGET_XML.len());
+                    let end = (start + length as usize).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+                    format!("{}{}", more, &TARGET_XML[start..end])
+                }
+                None => "E01".to_string(),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.acknowledge = false;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            // Everything else isn't supported, which GDB expects an empty reply for
+            _ => String::new(),
+        }
+    }
+
+    // Z0/Z1 are breakpoints, Z2 to Z4 write, read and access watchpoints
+    fn breakpoint(&mut self, insert: bool, arguments: &str, cpu: &mut CPU) -> String {
+        let mut fields = arguments.split(',');
+        let kind = fields.next();
+        let address = fields.next().and_then(|address| u16::from_str_radix(address, 16).ok());
+        let length = fields.next().and_then(|length| u16::from_str_radix(length, 16).ok());
+        let (address, length) = match (address, length) {
+            (Some(address), Some(length)) => (address, length.max(1)),
+            _ => return "E01".to_string(),
+        };
+        let kind = match kind {
+            Some("0") | Some("1") => {
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return "OK".to_string();
+            }
+            Some("2") => WatchKind::Write,
+            Some("3") => WatchKind::Read,
+            Some("4") => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let watchpoint = Watchpoint {
+            start: address,
+            end: address.saturating_add(length - 1),
+            read: kind != WatchKind::Write,
+            write: kind != WatchKind::Read,
+        };
+        if insert {
+            self.watchpoints.push((kind, watchpoint));
+        } else {
+            self.watchpoints.retain(|&(other_kind, other)| (other_kind, other) != (kind, watchpoint));
+        }
+        cpu.bus.watchpoints = self.watchpoints.iter().map(|(_, watchpoint)| *watchpoint).collect();
+        "OK".to_string()
+    }
+
+    fn receive(&mut self) -> Result<String, String> {
+        let stream = self.stream.as_mut().ok_or("Not connected")?;
+        loop {
+            let mut byte = [0];
+            // Acknowledgements and stray Ctrl-Cs while stopped are skipped
+            loop {
+                read_byte(stream, &mut byte)?;
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                read_byte(stream, &mut byte)?;
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            read_byte(stream, &mut checksum[..1])?;
+            read_byte(stream, &mut checksum[1..])?;
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+            let valid = expected == Some(data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+            if self.acknowledge {
+                stream.write_all(if valid { b"+" } else { b"-" }).map_err(|e| e.to_string())?;
+            }
+            if valid || !self.acknowledge {
+                return Ok(String::from_utf8_lossy(&data).to_string());
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<(), String> {
+        let stream = self.stream.as_mut().ok_or("Not connected")?;
+        let data = escape(data);
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        let packet = format!("${}#{:02x}", data, checksum);
+        stream.write_all(packet.as_bytes()).map_err(|e| e.to_string())
+    }
+}
+
+fn read_byte(stream: &mut TcpStream, byte: &mut [u8]) -> Result<(), String> {
+    match stream.read(byte) {
+        Ok(0) => Err("GDB disconnected".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// $, #, } and * (run-length encoding) have to be escaped in replies
+fn escape(data: &str) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    for c in data.chars() {
+        if matches!(c, '$' | '#' | '}' | '*') {
+            escaped.push('}');
+            escaped.push((c as u8 ^ 0x20) as char);
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn read_register(cpu: &CPU, register: usize) -> u16 {
+    match register {
+        0 => cpu.registers.get_af(),
+        1 => cpu.registers.get_bc(),
+        2 => cpu.registers.get_de(),
+        3 => cpu.registers.get_hl(),
+        4 => cpu.sp,
+        _ => cpu.pc,
+    }
+}
+
+fn write_register(cpu: &mut CPU, register: usize, value: u16) {
+    match register {
+        0 => cpu.registers.set_af(value),
+        1 => cpu.registers.set_bc(value),
+        2 => cpu.registers.set_de(value),
+        3 => cpu.registers.set_hl(value),
+        4 => cpu.sp = value,
+        _ => cpu.pc = value,
+    }
+}
+
+// Registers go over the wire in target byte order, little endian
+fn register_hex(value: u16) -> String {
+    format!("{:02x}{:02x}", value as u8, value >> 8)
+}
+
+fn parse_register_hex(text: &str) -> Option<u16> {
+    let bytes = parse_hex_bytes(text)?;
+    match bytes[..] {
+        [low, high] => Some(u16::from_le_bytes([low, high])),
+        _ => None,
+    }
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_address_length(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u32::from_str_radix(address, 16).ok()?, u32::from_str_radix(length, 16).ok()?))
+}
+
+fn peek(cpu: &mut CPU, address: u32) -> Option<u8> {
+    match address {
+        0..=0xFFFF => Some(cpu.bus.peek(address as u16)),
+        _ => cpu.bus.peek_banked((address >> 16) as usize, address as u16),
+    }
+}
+
+fn poke(cpu: &mut CPU, address: u32, value: u8) -> bool {
+    match address {
+        0..=0xFFFF => cpu.bus.poke(address as u16, value),
+        _ => cpu.bus.poke_banked((address >> 16) as usize, address as u16, value),
+    }
+}
@@ -1,15 +1,25 @@
 pub const VRAM_BEGIN: usize = 0x8000;
 pub const VRAM_END: usize = 0x9FFF;
-const VRAM_SIZE: usize = VRAM_END - VRAM_BEGIN + 1;
+pub const VRAM_BANK_SIZE: usize = VRAM_END - VRAM_BEGIN + 1;
+const VRAM_SIZE: usize = VRAM_BANK_SIZE * 2;
 
 pub const OAM_BEGIN: usize = 0xFE00;
 pub const OAM_END: usize = 0xFE9F;
 pub const OAM_SIZE: usize = OAM_END - OAM_BEGIN + 1;
 
+use super::palette::*;
+use super::model::Model;
+
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
 
 const NUMBER_OF_OBJECTS: usize = 40;
+const OBJECTS_PER_LINE: usize = 10;
+const NUMBER_OF_TILES: usize = 384;
+pub const PALETTE_RAM_SIZE: usize = 64;
+
+// The boot ROM hands over with this many T-cycles of line 153 left
+const BOOT_HAND_OFF_CYCLES_LEFT: u16 = 56;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TileMap {
@@ -49,6 +59,8 @@ pub struct ObjectData {
     xflip: bool,
     yflip: bool,
     priority: bool,
+    bank: usize,
+    cgb_palette: usize,
 }
 
 impl Default for ObjectData {
@@ -61,11 +73,13 @@ impl Default for ObjectData {
             xflip: Default::default(),
             yflip: Default::default(),
             priority: Default::default(),
+            bank: 0,
+            cgb_palette: 0,
         }
     }
 }
 #[derive(Copy, Clone)]
-enum ObjectPalette {
+pub enum ObjectPalette {
     Zero,
     One,
 }
@@ -87,12 +101,23 @@ pub enum BackgroundAndWindowDataSelect {
     X8800,
 }
 
+// A DMG shade, the palette decides what it looks like on screen
 #[derive(Copy, Clone)]
 pub enum Color {
-    White = 255,
-    LightGray = 192,
-    DarkGray = 96,
-    Black = 0,
+    White,
+    LightGray,
+    DarkGray,
+    Black,
+}
+impl std::convert::From<Color> for u8 {
+    fn from(color: Color) -> Self {
+        match color {
+            Color::White => 0,
+            Color::LightGray => 1,
+            Color::DarkGray => 2,
+            Color::Black => 3,
+        }
+    }
 }
 impl std::convert::From<u8> for Color {
     fn from(n: u8) -> Self {
@@ -127,7 +152,7 @@ impl InterruptRequest {
         };
     }
 }
-#[derive (Clone, Copy)]
+#[derive (Clone, Copy, PartialEq)]
 pub enum Mode {
     HorizontalBlank,
     VerticalBlank,
@@ -145,6 +170,7 @@ impl std::convert::From<Mode> for u8 {
     }
 }
 
+#[derive(Copy, Clone)]
 pub struct BackgroundColors(Color, Color, Color, Color);
 
 impl BackgroundColors {
@@ -158,6 +184,12 @@ impl BackgroundColors {
     }
 }
 
+impl std::convert::From<BackgroundColors> for u8 {
+    fn from(colors: BackgroundColors) -> Self {
+        u8::from(colors.3) << 6 | u8::from(colors.2) << 4 | u8::from(colors.1) << 2 | u8::from(colors.0)
+    }
+}
+
 impl std::convert::From<u8> for BackgroundColors {
     fn from(value: u8) -> Self {
         BackgroundColors(
@@ -173,12 +205,23 @@ pub struct Window {
     pub x: u8,
     pub y: u8,
 }
+// What the background or window left at a pixel, objects need it to resolve priority
+#[derive(Copy, Clone, Default)]
+struct BackgroundPixel {
+    value: Tilepixelvalues,
+    priority: bool,
+    shade: u8,
+}
+
 pub struct GPU {
     pub vram: [u8; VRAM_SIZE],
+    pub vram_bank: usize,
     pub oam: [u8; OAM_SIZE],
     pub object_data: [ObjectData; NUMBER_OF_OBJECTS],
     pub canvas_buffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
-    tile_set: [Tile; 384],
+    // The DMG shade (0-3) of every pixel, which is what the SGB colourises
+    pub shade_buffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    tile_set: [[Tile; NUMBER_OF_TILES]; 2],
     pub viewport_x_offset: u8,
     pub viewport_y_offset: u8,
     pub background_display_enabled: bool,
@@ -199,23 +242,37 @@ pub struct GPU {
     pub hblank_interrupt_enabled: bool,
     pub line_equals_line_check: bool,
     pub line_check: u8,
+    // Objects are see through in colour 0, it is only kept so OBP0 and OBP1 read back what was written
+    pub obj_0_color_0: Color,
     pub obj_0_color_1: Color,
     pub obj_0_color_2: Color,
     pub obj_0_color_3: Color,
+    pub obj_1_color_0: Color,
     pub obj_1_color_1: Color,
     pub obj_1_color_2: Color,
     pub obj_1_color_3: Color,
     pub window: Window,
+    window_line: u8,
+    pub palette: Palette,
+    pub model: Model,
+    pub dmg_compatibility: bool,
+    pub background_palette_ram: [u8; PALETTE_RAM_SIZE],
+    pub background_palette_index: u8,
+    pub object_palette_ram: [u8; PALETTE_RAM_SIZE],
+    pub object_palette_index: u8,
+    pub object_priority_by_coordinate: bool,
 }
 
 impl GPU {
-    pub fn new() -> GPU {
+    pub fn new(model: Model) -> GPU {
         GPU { 
             vram: [0; VRAM_SIZE],
+            vram_bank: 0,
             oam: [0; OAM_SIZE],
             object_data: [Default::default(); NUMBER_OF_OBJECTS],
             canvas_buffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
-            tile_set: [empty_tile(); 384],
+            shade_buffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            tile_set: [[empty_tile(); NUMBER_OF_TILES]; 2],
             viewport_x_offset: 0,
             viewport_y_offset: 0,
             background_display_enabled: true,
@@ -236,16 +293,54 @@ impl GPU {
             hblank_interrupt_enabled: false,
             line_equals_line_check: false,
             line_check: 0,
+            obj_0_color_0: Color::White,
             obj_0_color_1: Color::LightGray,
             obj_0_color_2: Color::DarkGray,
             obj_0_color_3: Color::Black,
+            obj_1_color_0: Color::White,
             obj_1_color_1: Color::LightGray,
             obj_1_color_2: Color::DarkGray,
             obj_1_color_3: Color::Black,
             window: Window { x: 0, y: 0 },
+            window_line: 0,
+            palette: Palette::default(),
+            model,
+            dmg_compatibility: false,
+            // The CGB boot ROM fills the palettes, without one everything starts out white
+            background_palette_ram: [0xFF; PALETTE_RAM_SIZE],
+            background_palette_index: 0,
+            object_palette_ram: [0xFF; PALETTE_RAM_SIZE],
+            object_palette_index: 0,
+            object_priority_by_coordinate: model != Model::CGB,
         }
     }
 
+    // Where the boot ROM leaves the LCD: on and near the end of line 153, where LY already reads 0
+    pub fn skip_to_boot_hand_off(&mut self) {
+        self.lcd_display_enabled = true;
+        self.mode = Mode::VerticalBlank;
+        self.line = 153;
+        self.cycles = 456 - BOOT_HAND_OFF_CYCLES_LEFT;
+        self.line_equals_line_check = self.line_check == 0;
+    }
+
+    // LY, which already reads 0 after the first M-cycle of line 153
+    pub fn read_line(&self) -> u8 {
+        if self.line == 153 && self.cycles >= 4 {
+            0
+        } else {
+            self.line
+        }
+    }
+
+    pub fn read_object_palette(&self, palette: ObjectPalette) -> u8 {
+        let colors = match palette {
+            ObjectPalette::Zero => [self.obj_0_color_0, self.obj_0_color_1, self.obj_0_color_2, self.obj_0_color_3],
+            ObjectPalette::One => [self.obj_1_color_0, self.obj_1_color_1, self.obj_1_color_2, self.obj_1_color_3],
+        };
+        u8::from(colors[3]) << 6 | u8::from(colors[2]) << 4 | u8::from(colors[1]) << 2 | u8::from(colors[0])
+    }
+
     pub fn step(&mut self, cycles: u8) -> InterruptRequest {
         let mut request = InterruptRequest::None;
         if !self.lcd_display_enabled {
@@ -262,6 +357,7 @@ impl GPU {
 
                     if self.line >= 144 {
                         self.mode = Mode::VerticalBlank;
+                        self.window_line = 0;
                         request.add(InterruptRequest::VBlank);
                         if self.vblank_interrupt_enabled {
                             request.add(InterruptRequest::LCDStat)
@@ -311,14 +407,16 @@ impl GPU {
 
 
     pub fn read_vram(&self, addr: usize) -> u8 {
-        self.vram[addr]
+        self.vram[self.vram_bank * VRAM_BANK_SIZE + addr]
     }
     pub fn write_vram(&mut self, addr: usize, val: u8) {
-        self.vram[addr] = val;
+        let bank = self.vram_bank;
+        let bank_offset = bank * VRAM_BANK_SIZE;
+        self.vram[bank_offset + addr] = val;
 
         if addr >= 0x1800 { return }
 
-        let normalized_addr = addr & 0xfffe;
+        let normalized_addr = bank_offset + (addr & 0xfffe);
 
         let b1 = self.vram[normalized_addr];
         let b2 = self.vram[normalized_addr + 1];
@@ -339,7 +437,7 @@ impl GPU {
                 (false, false) => Tilepixelvalues::Zero,
             };
 
-            self.tile_set[tileindex][row][pixel] = value;
+            self.tile_set[bank][tileindex][row][pixel] = value;
         }
     }
 
@@ -366,70 +464,71 @@ impl GPU {
                 data.xflip = (value & 0x20) != 0;
                 data.yflip = (value & 0x40) != 0;
                 data.priority = (value & 0x80) == 0;
+                data.bank = ((value >> 3) & 0b1) as usize;
+                data.cgb_palette = (value & 0b111) as usize;
             }
         }
     }
 
+    // BCPD/OCPD write to the palette byte selected by BCPS/OCPS
+    pub fn write_background_palette_data(&mut self, value: u8) {
+        write_palette_data(&mut self.background_palette_ram, &mut self.background_palette_index, value);
+    }
+
+    pub fn write_object_palette_data(&mut self, value: u8) {
+        write_palette_data(&mut self.object_palette_ram, &mut self.object_palette_index, value);
+    }
+
+    // Changes the DMG colours, DMG games running on the CGB get them loaded into
+    // the colour palettes the way the boot ROM would
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        if self.model == Model::CGB && self.dmg_compatibility {
+            load_palette(&mut self.background_palette_ram, 0, &palette.background);
+            load_palette(&mut self.object_palette_ram, 0, &palette.object_0);
+            load_palette(&mut self.object_palette_ram, 1, &palette.object_1);
+        }
+    }
+
+    fn cgb_mode(&self) -> bool {
+        self.model == Model::CGB && !self.dmg_compatibility
+    }
+
     fn render_scan_line(&mut self) {
-        let mut scan_line: [Tilepixelvalues; SCREEN_WIDTH] = [Default::default(); SCREEN_WIDTH];
-        if self.background_display_enabled {
-            // The x index of the current tile
-            let mut tile_x_index = self.viewport_x_offset / 8;
-            // The current scan line's y-offset in the entire background space is a combination
-            // of both the line inside the view port we're currently on and the amount of the view port is scrolled
-            let tile_y_index = self.line.wrapping_add(self.viewport_y_offset);
-            // The current tile we're on is equal to the total y offset broken up into 8 pixel chunks
-            // and multipled by the width of the entire background (i.e. 32 tiles)
-            let tile_offset = (tile_y_index as u16 / 8) * 32u16;
-
-            // Where is our tile map defined?
-            let background_tile_map = if self.background_tile_map == TileMap::X9800 {
-                0x9800
-            } else {
-                0x9C00
-            };
-            // Munge this so that the beginning of VRAM is index 0
-            let tile_map_begin = background_tile_map - VRAM_BEGIN;
-            // Where we are in the tile map is the beginning of the tile map
-            // plus the current tile's offset
-            let tile_map_offset = tile_map_begin + tile_offset as usize;
-
-            // When line and scrollY are zero we just start at the top of the tile
-            // If they're non-zero we must index into the tile cycling through 0 - 7
-            let row_y_offset = tile_y_index % 8;
-            let mut pixel_x_index = self.viewport_x_offset % 8;
-
-            if self.background_and_window_data_select == BackgroundAndWindowDataSelect::X8800 {
-                panic!("TODO: support 0x8800 background and window data select");
-            }
+        let mut scan_line: [BackgroundPixel; SCREEN_WIDTH] = [Default::default(); SCREEN_WIDTH];
+        let mut colors: [Rgb; SCREEN_WIDTH] = [self.dmg_background_rgb(Color::White); SCREEN_WIDTH];
+        let mut shades = [Color::White as u8; SCREEN_WIDTH];
+        let cgb_mode = self.cgb_mode();
+
+        // In CGB mode the background can't be turned off, the LCDC bit instead
+        // takes away its priority over objects
+        if self.background_display_enabled || cgb_mode {
+            let window_visible = self.window_display_enabled && self.line >= self.window.y;
+            let mut window_drawn = false;
 
-            let mut canvas_buffer_offset = self.line as usize * SCREEN_WIDTH * 4;
-            // Start at the beginning of the line and go pixel by pixel
             for line_x in 0..SCREEN_WIDTH {
-                // Grab the tile index specified in the tile map
-                let tile_index = self.vram[tile_map_offset + tile_x_index as usize];
-
-                let tile_value = self.tile_set[tile_index as usize][row_y_offset as usize]
-                    [pixel_x_index as usize];
-                let color = self.tile_value_to_background_color(&tile_value);
-
-                self.canvas_buffer[canvas_buffer_offset] = color as u8;
-                self.canvas_buffer[canvas_buffer_offset + 1] = color as u8;
-                self.canvas_buffer[canvas_buffer_offset + 2] = color as u8;
-                self.canvas_buffer[canvas_buffer_offset + 3] = 255;
-                canvas_buffer_offset += 4;
-                scan_line[line_x] = tile_value;
-                // Loop through the 8 pixels within the tile
-                pixel_x_index = (pixel_x_index + 1) % 8;
-
-                // Check if we've fully looped through the tile
-                if pixel_x_index == 0 {
-                    // Now increase the tile x_offset by 1
-                    tile_x_index = tile_x_index + 1;
-                }
-                if self.background_and_window_data_select == BackgroundAndWindowDataSelect::X8800 {
-                    panic!("TODO: support 0x8800 background and window data select");
-                }
+                // The window's x position is offset by 7 pixels
+                let in_window = window_visible && line_x + 7 >= self.window.x as usize;
+                let (tile_map, x, y) = if in_window {
+                    window_drawn = true;
+                    (self.window_tile_map, (line_x + 7 - self.window.x as usize) as u8, self.window_line)
+                } else {
+                    (
+                        self.background_tile_map,
+                        (line_x as u8).wrapping_add(self.viewport_x_offset),
+                        self.line.wrapping_add(self.viewport_y_offset),
+                    )
+                };
+                let (pixel, rgb) = self.background_pixel(tile_map, x, y, cgb_mode);
+                scan_line[line_x] = pixel;
+                colors[line_x] = rgb;
+                shades[line_x] = pixel.shade;
+            }
+
+            // The window keeps its own line counter so it continues where it left off
+            // when it's hidden for some lines
+            if window_drawn {
+                self.window_line += 1;
             }
         }
 
@@ -439,51 +538,119 @@ impl GPU {
             } else {
                 8
             };
-            for object in self.object_data.iter() {
-                let line = self.line as i16;
-                if object.y <= line && object.y + object_height > line {
-                    let pixel_y_offset = line - object.y;
-                    let tile_index = if object_height == 16 && (!object.yflip && pixel_y_offset > 7)
-                        || (object.yflip && pixel_y_offset <= 7)
-                    {
-                        object.tile + 1
-                    } else {
-                        object.tile
-                    };
+            let line = self.line as i16;
+
+            // Only the first 10 objects in OAM that are on this line get drawn
+            let mut visible = [0usize; OBJECTS_PER_LINE];
+            let mut count = 0;
+            for (index, object) in self.object_data.iter().enumerate() {
+                if count < OBJECTS_PER_LINE && object.y <= line && object.y + object_height > line {
+                    visible[count] = index;
+                    count += 1;
+                }
+            }
+            let visible = &mut visible[..count];
+            if self.object_priority_by_coordinate {
+                // The object with the smaller x wins, ties go to the one first in OAM.
+                // CGB mode only looks at the OAM position.
+                visible.sort_by_key(|&index| self.object_data[index].x);
+            }
 
-                    let tile = self.tile_set[tile_index as usize];
-                    let tile_row = if object.yflip {
-                        tile[(7 - (pixel_y_offset % 8)) as usize]
+            // An object's non transparent pixels hide lower priority objects even
+            // when the background ends up being drawn over it
+            let mut drawn = [false; SCREEN_WIDTH];
+            for &index in visible.iter() {
+                let object = self.object_data[index];
+                let pixel_y_offset = line - object.y;
+                let row = if object.yflip {
+                    object_height - 1 - pixel_y_offset
+                } else {
+                    pixel_y_offset
+                } as usize;
+                let tile_index = if object_height == 16 {
+                    (object.tile & 0xFE) as usize + row / 8
+                } else {
+                    object.tile as usize
+                };
+                let bank = if cgb_mode { object.bank } else { 0 };
+                let tile_row = self.tile_set[bank][tile_index][row % 8];
+
+                for x in 0..8i16 {
+                    let x_offset = object.x + x;
+                    if x_offset < 0 || x_offset >= SCREEN_WIDTH as i16 {
+                        continue;
+                    }
+                    let x_offset = x_offset as usize;
+                    let pixel_x_offset = if object.xflip { 7 - x } else { x } as usize;
+                    let pixel = tile_row[pixel_x_offset];
+                    if drawn[x_offset] || pixel == Tilepixelvalues::Zero {
+                        continue;
+                    }
+                    drawn[x_offset] = true;
+
+                    let background = scan_line[x_offset];
+                    let object_visible = if cgb_mode {
+                        !self.background_display_enabled
+                            || background.value == Tilepixelvalues::Zero
+                            || (object.priority && !background.priority)
                     } else {
-                        tile[(pixel_y_offset % 8) as usize]
+                        object.priority || background.value == Tilepixelvalues::Zero
                     };
-
-                    let canvas_y_offset = line as i32 * SCREEN_WIDTH as i32;
-                    let mut canvas_offset = ((canvas_y_offset + object.x as i32) * 4) as usize;
-                    for x in 0..8i16 {
-                        let pixel_x_offset = if object.xflip { (7 - x) } else { x } as usize;
-                        let x_offset = object.x + x;
-                        let pixel = tile_row[pixel_x_offset];
-                        if x_offset >= 0
-                            && x_offset < SCREEN_WIDTH as i16
-                            && pixel != Tilepixelvalues::Zero
-                            && (object.priority
-                                || scan_line[x_offset as usize] == Tilepixelvalues::Zero)
-                        {
-                            let color = self.tile_value_to_background_color(&pixel);
-
-                            self.canvas_buffer[canvas_offset + 0] = color as u8;
-                            self.canvas_buffer[canvas_offset + 1] = color as u8;
-                            self.canvas_buffer[canvas_offset + 2] = color as u8;
-                            self.canvas_buffer[canvas_offset + 3] = 255;
-                        }
-                        canvas_offset += 4;
+                    if object_visible {
+                        colors[x_offset] = self.object_rgb(&object, &pixel, cgb_mode);
+                        shades[x_offset] = self.object_color(&object, &pixel) as u8;
                     }
                 }
             }
         }
 
-        if self.window_display_enabled {}
+        let shade_offset = self.line as usize * SCREEN_WIDTH;
+        self.shade_buffer[shade_offset..shade_offset + SCREEN_WIDTH].copy_from_slice(&shades);
+
+        let mut canvas_buffer_offset = self.line as usize * SCREEN_WIDTH * 4;
+        for rgb in colors.iter() {
+            self.canvas_buffer[canvas_buffer_offset..canvas_buffer_offset + 3].copy_from_slice(rgb);
+            self.canvas_buffer[canvas_buffer_offset + 3] = 255;
+            canvas_buffer_offset += 4;
+        }
+    }
+
+    fn background_pixel(&self, tile_map: TileMap, x: u8, y: u8, cgb_mode: bool) -> (BackgroundPixel, Rgb) {
+        // Where is our tile map defined?
+        let tile_map_begin = if tile_map == TileMap::X9800 {
+            0x9800
+        } else {
+            0x9C00
+        } - VRAM_BEGIN;
+        // The map is 32 tiles wide
+        let tile_map_offset = tile_map_begin + (y as usize / 8) * 32 + x as usize / 8;
+        let tile_number = self.vram[tile_map_offset];
+
+        // In CGB mode VRAM bank 1 holds an attribute byte for every entry of the tile map
+        let attributes = if cgb_mode {
+            self.vram[VRAM_BANK_SIZE + tile_map_offset]
+        } else {
+            0
+        };
+
+        let tile_index = match self.background_and_window_data_select {
+            BackgroundAndWindowDataSelect::X8000 => tile_number as usize,
+            // 0x8800 addressing uses signed tile numbers relative to 0x9000
+            BackgroundAndWindowDataSelect::X8800 => (256 + tile_number as i8 as i16) as usize,
+        };
+        let row = if attributes & 0x40 != 0 { 7 - y % 8 } else { y % 8 };
+        let column = if attributes & 0x20 != 0 { 7 - x % 8 } else { x % 8 };
+        let bank = ((attributes >> 3) & 0b1) as usize;
+
+        let value = self.tile_set[bank][tile_index][row as usize][column as usize];
+        let color = self.tile_value_to_background_color(&value);
+        let rgb = if cgb_mode {
+            cgb_color(&self.background_palette_ram, (attributes & 0b111) as usize, value as usize)
+        } else {
+            self.dmg_background_rgb(color)
+        };
+
+        (BackgroundPixel { value, priority: attributes & 0x80 != 0, shade: color as u8 }, rgb)
     }
     
     fn tile_value_to_background_color(&self, tile_value: &Tilepixelvalues) -> Color {
@@ -495,6 +662,45 @@ impl GPU {
         }
     }
 
+    fn dmg_background_rgb(&self, color: Color) -> Rgb {
+        if self.model == Model::CGB {
+            // DMG games on the CGB look their shades up in the first colour palette
+            cgb_color(&self.background_palette_ram, 0, color as usize)
+        } else {
+            self.palette.background[color as usize]
+        }
+    }
+
+    fn object_rgb(&self, object: &ObjectData, tile_value: &Tilepixelvalues, cgb_mode: bool) -> Rgb {
+        if cgb_mode {
+            return cgb_color(&self.object_palette_ram, object.cgb_palette, *tile_value as usize);
+        }
+
+        let (rgb, cgb_palette) = match object.palette {
+            ObjectPalette::Zero => (&self.palette.object_0, 0),
+            ObjectPalette::One => (&self.palette.object_1, 1),
+        };
+        let color = self.object_color(object, tile_value);
+        if self.model == Model::CGB {
+            cgb_color(&self.object_palette_ram, cgb_palette, color as usize)
+        } else {
+            rgb[color as usize]
+        }
+    }
+
+    fn object_color(&self, object: &ObjectData, tile_value: &Tilepixelvalues) -> Color {
+        let colors = match object.palette {
+            ObjectPalette::Zero => [self.obj_0_color_1, self.obj_0_color_2, self.obj_0_color_3],
+            ObjectPalette::One => [self.obj_1_color_1, self.obj_1_color_2, self.obj_1_color_3],
+        };
+        // Color 0 is transparent for objects so it never reaches here
+        match tile_value {
+            Tilepixelvalues::Zero | Tilepixelvalues::One => colors[0],
+            Tilepixelvalues::Two => colors[1],
+            Tilepixelvalues::Three => colors[2],
+        }
+    }
+
     fn set_equal_lines_check(&mut self, request: &mut InterruptRequest) {
         let line_equals_line_check = self.line == self.line_check;
         if line_equals_line_check && self.line_equals_line_check_interrupt_enabled {
@@ -503,4 +709,88 @@ impl GPU {
         self.line_equals_line_check = line_equals_line_check;
     }
 
-}
\ No newline at end of file
+}
+
+fn write_palette_data(palette_ram: &mut [u8; PALETTE_RAM_SIZE], index: &mut u8, value: u8) {
+    palette_ram[(*index & 0x3F) as usize] = value;
+    if *index & 0x80 != 0 {
+        *index = 0x80 | (index.wrapping_add(1) & 0x3F);
+    }
+}
+
+// Colour palette RAM holds 8 palettes of 4 colours
+fn cgb_color(palette_ram: &[u8; PALETTE_RAM_SIZE], palette: usize, index: usize) -> Rgb {
+    let offset = palette * 8 + index * 2;
+    from_rgb555(palette_ram[offset] as u16 | (palette_ram[offset + 1] as u16) << 8)
+}
+
+fn load_palette(palette_ram: &mut [u8; PALETTE_RAM_SIZE], palette: usize, colors: &[Rgb; 4]) {
+    for (index, rgb) in colors.iter().enumerate() {
+        let color = to_rgb555(*rgb);
+        let offset = palette * 8 + index * 2;
+        palette_ram[offset] = color as u8;
+        palette_ram[offset + 1] = (color >> 8) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKGROUND: Rgb = [255, 0, 0];
+    const OBJECT: Rgb = [0, 255, 0];
+
+    // A CGB background of colour 1 everywhere with one object of colour 3 in the top left corner
+    fn cgb_gpu(tile_attributes: u8, object_attributes: u8) -> GPU {
+        let mut gpu = GPU::new(Model::CGB);
+        gpu.background_and_window_data_select = BackgroundAndWindowDataSelect::X8000;
+        for row in 0..8 {
+            gpu.write_vram(row * 2, 0xFF);
+            gpu.write_vram(16 + row * 2, 0xFF);
+            gpu.write_vram(16 + row * 2 + 1, 0xFF);
+        }
+        gpu.vram_bank = 1;
+        gpu.write_vram(0x1800, tile_attributes);
+        gpu.vram_bank = 0;
+        for (index, &value) in [16, 8, 1, object_attributes].iter().enumerate() {
+            gpu.write_oam(index, value);
+        }
+        load_palette(&mut gpu.background_palette_ram, 0, &[[0; 3], BACKGROUND, [0; 3], [0; 3]]);
+        load_palette(&mut gpu.object_palette_ram, 0, &[[0; 3], [0; 3], [0; 3], OBJECT]);
+        gpu
+    }
+
+    fn first_pixel(gpu: &mut GPU) -> Rgb {
+        gpu.render_scan_line();
+        [gpu.canvas_buffer[0], gpu.canvas_buffer[1], gpu.canvas_buffer[2]]
+    }
+
+    #[test]
+    fn object_priority_attribute() {
+        assert_eq!(first_pixel(&mut cgb_gpu(0, 0)), OBJECT);
+        // Behind background colours 1-3
+        assert_eq!(first_pixel(&mut cgb_gpu(0, 0x80)), BACKGROUND);
+    }
+
+    #[test]
+    fn tile_priority_attribute() {
+        let mut gpu = cgb_gpu(0x80, 0);
+        assert_eq!(first_pixel(&mut gpu), BACKGROUND);
+        // The tile next to it doesn't have priority
+        assert_eq!(gpu.canvas_buffer[8 * 4..8 * 4 + 3], BACKGROUND);
+    }
+
+    #[test]
+    fn lcdc_bit_0_overrides_background_priority() {
+        for &(tile_attributes, object_attributes) in [(0x80, 0), (0, 0x80), (0x80, 0x80)].iter() {
+            let mut gpu = cgb_gpu(tile_attributes, object_attributes);
+            gpu.background_display_enabled = false;
+            assert_eq!(first_pixel(&mut gpu), OBJECT);
+        }
+        // The background is still drawn where there are no objects
+        let mut gpu = cgb_gpu(0, 0);
+        gpu.background_display_enabled = false;
+        gpu.render_scan_line();
+        assert_eq!(gpu.canvas_buffer[8 * 4..8 * 4 + 3], BACKGROUND);
+    }
+}
@@ -0,0 +1,55 @@
+pub const HDMA_BLOCK_SIZE: u16 = 0x10;
+
+// CGB DMA from ROM/RAM into VRAM, controlled through HDMA1-HDMA5 (0xFF51-0xFF55)
+pub struct Hdma {
+    pub source: u16,
+    pub destination: u16,
+    // Number of 16 byte blocks left minus one, as read back from HDMA5
+    pub remaining: u8,
+    pub hblank_active: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma {
+            source: 0,
+            destination: 0,
+            remaining: 0x7F,
+            hblank_active: false,
+        }
+    }
+
+    pub fn write_source_high(&mut self, value: u8) {
+        self.source = (value as u16) << 8 | (self.source & 0xFF);
+    }
+
+    // The lower four bits of both addresses are ignored
+    pub fn write_source_low(&mut self, value: u8) {
+        self.source = (self.source & 0xFF00) | (value & 0xF0) as u16;
+    }
+
+    // The destination is always in VRAM
+    pub fn write_destination_high(&mut self, value: u8) {
+        self.destination = 0x8000 | ((value & 0x1F) as u16) << 8 | (self.destination & 0xFF);
+    }
+
+    pub fn write_destination_low(&mut self, value: u8) {
+        self.destination = (self.destination & 0xFF00) | (value & 0xF0) as u16;
+    }
+
+    // Bit 7 reads 0 while a H-Blank DMA is running, the rest is the remaining length
+    pub fn read_control(&self) -> u8 {
+        (if self.hblank_active { 0 } else { 1 }) << 7 | self.remaining
+    }
+
+    pub fn finish_block(&mut self) {
+        self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.destination = 0x8000 | (self.destination.wrapping_add(HDMA_BLOCK_SIZE) & 0x1FF0);
+        if self.remaining == 0 {
+            self.remaining = 0x7F;
+            self.hblank_active = false;
+        } else {
+            self.remaining -= 1;
+        }
+    }
+}
@@ -56,6 +56,7 @@ pub enum Instruction {
 
     // Control Instructions
     HALT,
+    STOP,
     NOP,
     DI,
     EI,
@@ -879,6 +880,7 @@ impl Instruction {
 
             0x00 => Some(Instruction::NOP),
             0x76 => Some(Instruction::HALT),
+            0x10 => Some(Instruction::STOP),
             0xf3 => Some(Instruction::DI),
             0xfb => Some(Instruction::EI),
             _ => None,
@@ -1,3 +1,27 @@
+use super::mem::*;
+
+// In order of priority, VBlank is serviced first
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank,
+    LCDStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    pub fn vector(&self) -> u16 {
+        match self {
+            Interrupt::VBlank => VBLANK_VECTOR,
+            Interrupt::LCDStat => LCDSTAT_VECTOR,
+            Interrupt::Timer => TIMER_VECTOR,
+            Interrupt::Serial => SERIAL_VECTOR,
+            Interrupt::Joypad => JOYPAD_VECTOR,
+        }
+    }
+}
+
 pub struct InterruptFlags {
     pub vblank: bool,
     pub lcdstat: bool,
@@ -25,6 +49,16 @@ impl InterruptFlags {
         self.joypad = (byte & 0b10000) == 0b10000;
     }
 
+    pub fn clear(&mut self, interrupt: Interrupt) {
+        match interrupt {
+            Interrupt::VBlank => self.vblank = false,
+            Interrupt::LCDStat => self.lcdstat = false,
+            Interrupt::Timer => self.timer = false,
+            Interrupt::Serial => self.serial = false,
+            Interrupt::Joypad => self.joypad = false,
+        }
+    }
+
     pub fn to_byte(&self) -> u8 {
         0b11100000 | // unused bits always read as 1s
                ((if self.joypad { 1 } else { 0 }) << 4) |
@@ -1,13 +1,44 @@
 
 mod mem;
+mod bus;
 mod registers;
 mod cpu;
 mod instructions;
 mod gpu;
 mod control;
 mod interrupts;
+mod palette;
+mod model;
+mod hdma;
+mod timer;
+mod serial;
+mod sgb;
+mod postprocess;
+mod upscale;
+mod filters;
+mod png;
+mod trace;
+mod disasm;
+mod debug;
+mod gdb;
+mod symbols;
 
 pub use cpu::CPU;
+pub use bus::{Access, Bus, FlatBus};
+pub use interrupts::Interrupt;
+pub use registers::{Registers, FlagReg};
+pub use palette::Palette;
+pub use model::Model;
+pub use control::Button;
+pub use sgb::{SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT};
+pub use postprocess::*;
+pub use upscale::Upscaler;
+pub use filters::*;
+pub use trace::{Tracer, TraceStart};
+pub use debug::{Watchpoint, WatchHit};
+pub use gdb::GdbStub;
+pub use symbols::{parse_location, Symbols};
+pub use disasm::{disassemble, linear_sweep, recursive_descent, Disassembled, Line};
 
 pub fn alliswell() {
     println!("This works");
@@ -6,6 +6,11 @@ pub const BOOT_ROM_BEGIN: usize = 0x00;
 pub const BOOT_ROM_END: usize = 0xFF;
 pub const BOOT_ROM_SIZE: usize = BOOT_ROM_END - BOOT_ROM_BEGIN + 1;
 
+// The CGB boot ROM leaves a hole for the cartridge header
+pub const CGB_BOOT_ROM_BEGIN: usize = 0x200;
+pub const CGB_BOOT_ROM_END: usize = 0x8FF;
+pub const CGB_BOOT_ROM_SIZE: usize = CGB_BOOT_ROM_END + 1;
+
 pub const ROM_BANK_0_BEGIN: usize = 0x0000;
 pub const ROM_BANK_0_END: usize = 0x3FFF;
 pub const ROM_BANK_0_SIZE: usize = ROM_BANK_0_END - ROM_BANK_0_BEGIN + 1;
@@ -16,7 +21,9 @@ pub const ROM_BANK_N_SIZE: usize = ROM_BANK_N_END - ROM_BANK_N_BEGIN + 1;
 
 pub const WORKING_RAM_BEGIN: usize = 0xC000;
 pub const WORKING_RAM_END: usize = 0xDFFF;
-pub const WORKING_RAM_SIZE: usize = WORKING_RAM_END - WORKING_RAM_BEGIN + 1;
+pub const WORKING_RAM_BANK_SIZE: usize = 0x1000;
+// The CGB has 8 banks, bank 0 is always at 0xC000 and 0xD000 switches between 1-7
+pub const WORKING_RAM_SIZE: usize = WORKING_RAM_BANK_SIZE * 8;
 
 pub const ECHO_RAM_BEGIN: usize = 0xE000;
 pub const ECHO_RAM_END: usize = 0xFDFF;
@@ -36,14 +43,33 @@ pub const EXTERNAL_RAM_BEGIN: usize = 0xA000;
 pub const EXTERNAL_RAM_END: usize = 0xBFFF;
 pub const EXTERNAL_RAM_SIZE: usize = EXTERNAL_RAM_END - EXTERNAL_RAM_BEGIN + 1;
 
+pub const SOUND_REGISTERS_BEGIN: usize = 0xFF10;
+pub const SOUND_REGISTERS_END: usize = 0xFF3F;
+pub const SOUND_REGISTERS_SIZE: usize = SOUND_REGISTERS_END - SOUND_REGISTERS_BEGIN + 1;
+
+// Bits of NR10-NR52 that always read as 1, the wave RAM reads back as written
+const SOUND_READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
 pub const INTERRUPT_ENABLE_REGISTER: usize = 0xFFFF;
 
 pub const VBLANK_VECTOR: u16 = 0x40;
 pub const LCDSTAT_VECTOR: u16 = 0x48;
 pub const TIMER_VECTOR: u16 = 0x50;
+pub const SERIAL_VECTOR: u16 = 0x58;
+pub const JOYPAD_VECTOR: u16 = 0x60;
 
 use super::gpu::*;
 use super::control::*;
+use super::model::Model;
+use super::hdma::*;
+use super::sgb::*;
+use super::timer::*;
+use super::serial::*;
+use super::bus::Bus;
+use super::debug::{WatchHit, Watchpoint};
 
 fn bit(condition: bool) -> u8 {
     if condition {
@@ -54,7 +80,7 @@ fn bit(condition: bool) -> u8 {
 }
 pub struct MemBus {
     // memory: [u8; RAM_SIZE],
-    pub bootrom: Option<[u8; BOOT_ROM_SIZE]>,
+    pub bootrom: Option<Vec<u8>>,
     rom_bank_0: [u8; ROM_BANK_0_SIZE],
     rom_bank_n: [u8; ROM_BANK_N_SIZE],
     working_ram: [u8; WORKING_RAM_SIZE],
@@ -64,18 +90,33 @@ pub struct MemBus {
     pub controller: Controller,
     pub interrupt_enable: InterruptFlags,
     pub interrupt_flag: InterruptFlags,
+    pub model: Model,
+    working_ram_bank: usize,
+    pub double_speed: bool,
+    pub speed_switch_requested: bool,
+    pub hdma: Hdma,
+    // CPU cycles left where the CPU is halted while a DMA copies into VRAM
+    pub stalled_cycles: u32,
+    pub sgb: Option<Sgb>,
+    // Set at the start of every V-Blank, cleared by whoever picks up the frame
+    pub frame_ready: bool,
+    pub timer: Timer,
+    pub serial: Serial,
+    // Checked on every CPU read and write, DMA and the PPU don't trigger them
+    pub watchpoints: Vec<Watchpoint>,
+    pub watch_hit: Option<WatchHit>,
+    // Nothing plays sound yet, the registers just hold what was written
+    sound_registers: [u8; SOUND_REGISTERS_SIZE],
 }
 
 impl MemBus {
-    pub fn new(bootrombuffer: Option<Vec<u8>>, gamerombuffer: Vec<u8>) -> MemBus {
-        let boot_rom = bootrombuffer.map(|bootrombuffer| {
-            if bootrombuffer.len() != BOOT_ROM_SIZE {
-                panic!("BootROM size wrong\n expected {} bytes got {} bytes", BOOT_ROM_SIZE, bootrombuffer.len());
+    pub fn new(bootrombuffer: Option<Vec<u8>>, gamerombuffer: Vec<u8>, model: Model) -> MemBus {
+        let expected_size = model.boot_rom_size();
+        if let Some(bootrombuffer) = &bootrombuffer {
+            if bootrombuffer.len() != expected_size {
+                panic!("BootROM size wrong\n expected {} bytes got {} bytes", expected_size, bootrombuffer.len());
             }
-            let mut boot_rom = [0; BOOT_ROM_SIZE];
-            boot_rom.copy_from_slice(&bootrombuffer);
-            boot_rom
-        });
+        }
 
         let mut rom_bank0 = [0; ROM_BANK_0_SIZE];
         for i in 0..ROM_BANK_0_SIZE {
@@ -86,22 +127,52 @@ impl MemBus {
             rom_bankn[i] = gamerombuffer[ROM_BANK_0_SIZE + i];
         }
         MemBus {
-            bootrom: boot_rom,
+            bootrom: bootrombuffer,
             working_ram: [0; WORKING_RAM_SIZE],
             rom_bank_0: rom_bank0,
             rom_bank_n: rom_bankn,
             zero_page: [0; ZERO_PAGE_SIZE],
             external_ram: [0; EXTERNAL_RAM_SIZE],
-            gpu: GPU::new(),
+            gpu: GPU::new(model),
             controller: Controller::new(),
             interrupt_enable: InterruptFlags::new(),
             interrupt_flag: InterruptFlags::new(),
+            model,
+            working_ram_bank: 1,
+            double_speed: false,
+            speed_switch_requested: false,
+            hdma: Hdma::new(),
+            stalled_cycles: 0,
+            sgb: if model == Model::SGB { Some(Sgb::new()) } else { None },
+            frame_ready: false,
+            timer: Timer::new(),
+            serial: Serial::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            sound_registers: [0; SOUND_REGISTERS_SIZE],
         }
     }
     pub fn step(&mut self, cycles: u8) {
         // self.gpu.step(cycles);
 
-        let (vblank, lcd) = match self.gpu.step(cycles) {
+        // The LCD keeps its pace when the CPU runs at double speed
+        let gpu_cycles = if self.double_speed { cycles / 2 } else { cycles };
+
+        // The timer runs off the CPU clock so it speeds up along with it
+        if self.timer.step(cycles as u32) {
+            self.interrupt_flag.timer = true;
+        }
+        if self.serial.step(cycles as u32) {
+            self.interrupt_flag.serial = true;
+        }
+
+        let mode = self.gpu.mode;
+        let request = self.gpu.step(gpu_cycles);
+        if self.hdma.hblank_active && mode != Mode::HorizontalBlank && self.gpu.mode == Mode::HorizontalBlank {
+            self.hdma_transfer_block();
+        }
+
+        let (vblank, lcd) = match request {
             InterruptRequest::Both => (true, true),
             InterruptRequest::VBlank => (true, false),
             InterruptRequest::LCDStat => (false, true),
@@ -110,6 +181,10 @@ impl MemBus {
 
         if vblank {
             self.interrupt_flag.vblank = true;
+            if let Some(sgb) = &mut self.sgb {
+                sgb.frame_completed(&self.gpu);
+            }
+            self.frame_ready = true;
         }
         if lcd {
             self.interrupt_flag.lcdstat = true;
@@ -117,12 +192,185 @@ impl MemBus {
             
     }
 
+    // Performed by STOP after KEY1 asked for it
+    pub fn switch_speed(&mut self) {
+        self.double_speed = !self.double_speed;
+        self.speed_switch_requested = false;
+        self.reset_divider();
+    }
+
+    // Writing DIV and executing STOP both clear the divider
+    pub fn reset_divider(&mut self) {
+        self.timer.reset_divider();
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.update_joypad(|controller| controller.set(button, true));
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.update_joypad(|controller| controller.set(button, false));
+    }
+
+    // The joypad interrupt is requested whenever one of the input lines goes from high to low
+    fn update_joypad<F: FnOnce(&mut Controller)>(&mut self, update: F) {
+        let before = self.controller.to_byte();
+        update(&mut self.controller);
+        if before & !self.controller.to_byte() & 0x0F != 0 {
+            self.interrupt_flag.joypad = true;
+        }
+    }
+
+    // STOP ends as soon as a button on one of the selected joypad lines is held
+    pub fn joypad_line_low(&self) -> bool {
+        self.controller.to_byte() & 0x0F != 0x0F
+    }
+
+    fn hdma_transfer_block(&mut self) {
+        for offset in 0..HDMA_BLOCK_SIZE {
+            let value = self.read_dma_source(self.hdma.source.wrapping_add(offset));
+            let destination = self.hdma.destination.wrapping_add(offset) as usize;
+            self.gpu.write_vram(destination - VRAM_BEGIN, value);
+        }
+        self.hdma.finish_block();
+        // Every block takes 8 M-cycles, twice as many when running at double speed
+        self.stalled_cycles += if self.double_speed { 64 } else { 32 };
+    }
+
+    fn read_dma_source(&self, addr: u16) -> u8 {
+        match addr as usize {
+            // There is no memory bank controller to put RAM here yet
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END => 0xFF,
+            _ => self.read_byte(addr),
+        }
+    }
+
+    fn write_hdma_control(&mut self, value: u8) {
+        if self.hdma.hblank_active && value & 0x80 == 0 {
+            // Clearing bit 7 stops a running H-Blank DMA
+            self.hdma.hblank_active = false;
+            return;
+        }
+        self.hdma.remaining = value & 0x7F;
+        if value & 0x80 == 0 {
+            // General purpose DMA copies everything at once
+            for _ in 0..=self.hdma.remaining {
+                self.hdma_transfer_block();
+            }
+        } else {
+            self.hdma.hblank_active = true;
+            // With the LCD off there are no H-Blanks, one block gets copied right away
+            if !self.gpu.lcd_display_enabled {
+                self.hdma_transfer_block();
+            }
+        }
+    }
+
+    pub fn take_stalled_cycles(&mut self) -> u32 {
+        std::mem::take(&mut self.stalled_cycles)
+    }
+
+    // Changes memory for a debugger without triggering watchpoints. ROM, external RAM and unknown
+    // I/O registers are left alone, false when nothing was written.
+    pub fn poke(&mut self, address: u16, value: u8) -> bool {
+        match address as usize {
+            ROM_BANK_0_BEGIN..=ROM_BANK_N_END | EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END => false,
+            IO_REGISTERS_BEGIN..=IO_REGISTERS_END => self.try_write_io_register(address as usize, value),
+            _ => {
+                self.write_byte(address, value);
+                true
+            }
+        }
+    }
+
+    // Reads from a bank whether or not it is switched in, for debuggers. Only what the bus has
+    // can be reached: ROM banks 0 and 1 (there is no memory bank controller yet), both VRAM
+    // banks and the 8 WRAM banks.
+    pub fn peek_banked(&mut self, bank: usize, address: u16) -> Option<u8> {
+        self.with_bank(bank, address, |bus| bus.peek(address))
+    }
+
+    pub fn poke_banked(&mut self, bank: usize, address: u16, value: u8) -> bool {
+        self.with_bank(bank, address, |bus| bus.poke(address, value)).unwrap_or(false)
+    }
+
+    fn with_bank<T>(&mut self, bank: usize, address: u16, access: impl FnOnce(&mut MemBus) -> T) -> Option<T> {
+        match address as usize {
+            ROM_BANK_0_BEGIN..=ROM_BANK_0_END if bank == 0 => Some(access(self)),
+            ROM_BANK_N_BEGIN..=ROM_BANK_N_END if bank == 1 => Some(access(self)),
+            VRAM_BEGIN..=VRAM_END if bank < 2 => {
+                let switched_in = std::mem::replace(&mut self.gpu.vram_bank, bank);
+                let result = access(self);
+                self.gpu.vram_bank = switched_in;
+                Some(result)
+            }
+            WORKING_RAM_BEGIN..=WORKING_RAM_END => {
+                let switchable = address as usize >= WORKING_RAM_BEGIN + WORKING_RAM_BANK_SIZE;
+                match (switchable, bank) {
+                    (false, 0) => Some(access(self)),
+                    (true, 1..=7) => {
+                        let switched_in = std::mem::replace(&mut self.working_ram_bank, bank);
+                        let result = access(self);
+                        self.working_ram_bank = switched_in;
+                        Some(result)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn check_watchpoints(&mut self, address: u16, value: u8, write: bool) {
+        if self.watch_hit.is_none() && self.watchpoints.iter().any(|watchpoint| watchpoint.matches(address, write)) {
+            self.watch_hit = Some(WatchHit { address, value, write });
+        }
+    }
+
+    fn cgb_mode(&self) -> bool {
+        self.model == Model::CGB && !self.gpu.dmg_compatibility
+    }
+
+    // Once a DMG game is running in compatibility mode only the boot ROM's colours are used
+    fn palettes_writable(&self) -> bool {
+        self.cgb_mode() || (self.model == Model::CGB && self.bootrom.is_some())
+    }
+
+    fn working_ram_offset(&self, addr: usize) -> usize {
+        let offset = (addr - WORKING_RAM_BEGIN) & (2 * WORKING_RAM_BANK_SIZE - 1);
+        if offset < WORKING_RAM_BANK_SIZE {
+            offset
+        } else {
+            self.working_ram_bank * WORKING_RAM_BANK_SIZE + offset - WORKING_RAM_BANK_SIZE
+        }
+    }
+
+    fn boot_rom_byte(&self, addr: usize) -> Option<u8> {
+        let boot_rom = self.bootrom.as_ref()?;
+        match addr {
+            BOOT_ROM_BEGIN..=BOOT_ROM_END => Some(boot_rom[addr]),
+            CGB_BOOT_ROM_BEGIN..=CGB_BOOT_ROM_END if boot_rom.len() == CGB_BOOT_ROM_SIZE => {
+                Some(boot_rom[addr])
+            }
+            _ => None,
+        }
+    }
+
     pub fn has_interrupt(&self) -> bool {
-        (self.interrupt_enable.vblank && self.interrupt_flag.vblank)
-            || (self.interrupt_enable.lcdstat && self.interrupt_flag.lcdstat)
-            || (self.interrupt_enable.timer && self.interrupt_flag.timer)
-            || (self.interrupt_enable.serial && self.interrupt_flag.serial)
-            || (self.interrupt_enable.joypad && self.interrupt_flag.joypad)
+        self.pending_interrupt().is_some()
+    }
+
+    // The enabled and requested interrupt with the highest priority
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.interrupt_enable.to_byte() & self.interrupt_flag.to_byte() & 0x1F;
+        match pending.trailing_zeros() {
+            0 => Some(Interrupt::VBlank),
+            1 => Some(Interrupt::LCDStat),
+            2 => Some(Interrupt::Timer),
+            3 => Some(Interrupt::Serial),
+            4 => Some(Interrupt::Joypad),
+            _ => None,
+        }
     }
     
     pub fn read_byte(&self, addr: u16) -> u8 {
@@ -132,20 +380,16 @@ impl MemBus {
             // panic!("reading dmg")
         // }
         let addr = addr as usize;
+        if let Some(byte) = self.boot_rom_byte(addr) {
+            return byte;
+        }
         match addr {
-            BOOT_ROM_BEGIN..=BOOT_ROM_END => {
-                // return self.rom_bank_0[addr];
-                if let Some(boot_rom) = self.bootrom {
-                    boot_rom[addr]
-                } else {
-                    self.rom_bank_0[addr]
-                }
-            }
             ROM_BANK_0_BEGIN..=ROM_BANK_0_END => self.rom_bank_0[addr],
             ROM_BANK_N_BEGIN..=ROM_BANK_N_END => self.rom_bank_n[addr - ROM_BANK_N_BEGIN],
-            VRAM_BEGIN..=VRAM_END => self.gpu.vram[addr - VRAM_BEGIN],
-            WORKING_RAM_BEGIN..=WORKING_RAM_END => self.working_ram[addr - WORKING_RAM_BEGIN],
-            ECHO_RAM_BEGIN..=ECHO_RAM_END => self.working_ram[addr - ECHO_RAM_BEGIN],
+            VRAM_BEGIN..=VRAM_END => self.gpu.read_vram(addr - VRAM_BEGIN),
+            WORKING_RAM_BEGIN..=WORKING_RAM_END | ECHO_RAM_BEGIN..=ECHO_RAM_END => {
+                self.working_ram[self.working_ram_offset(addr)]
+            }
             OAM_BEGIN..=OAM_END => self.gpu.oam[addr - OAM_BEGIN],
             UNUSED_BEGIN..=UNUSED_END => {0}
             IO_REGISTERS_BEGIN..=IO_REGISTERS_END => self.read_io_register(addr),
@@ -170,8 +414,8 @@ impl MemBus {
             VRAM_BEGIN..=VRAM_END => {
                 self.gpu.write_vram(addr - VRAM_BEGIN, val);
             }
-            WORKING_RAM_BEGIN..=WORKING_RAM_END => {
-                self.working_ram[addr - WORKING_RAM_BEGIN] = val;
+            WORKING_RAM_BEGIN..=WORKING_RAM_END | ECHO_RAM_BEGIN..=ECHO_RAM_END => {
+                self.working_ram[self.working_ram_offset(addr)] = val;
             }
             OAM_BEGIN..=OAM_END => {
                 self.gpu.write_oam(addr - OAM_BEGIN, val);
@@ -192,12 +436,34 @@ impl MemBus {
     }
     
     fn read_io_register(&self, addr: usize) -> u8 {
-        match addr {
-            0xFF00 => self.controller.to_byte(),
-            0xFF01 => 0, // TODO: serial
-            0xFF02 => 0, // TODO: serial
-            0xFF04 => 0,
-            0xFF0F => 0,
+        match self.try_read_io_register(addr) {
+            Some(value) => value,
+            None => panic!("Reading from an unknown I/O register {:x}", addr),
+        }
+    }
+
+    fn try_read_io_register(&self, addr: usize) -> Option<u8> {
+        let value = match addr {
+            0xFF00 => match &self.sgb {
+                Some(sgb) => match sgb.joypad_id() {
+                    Some(id) => 0x30 | id,
+                    // Only the first joypad is connected, the others read as nothing pressed
+                    None if sgb.current_player != 0 => self.controller.to_byte() | 0x0F,
+                    None => self.controller.to_byte(),
+                },
+                None => self.controller.to_byte(),
+            },
+            0xFF01 => self.serial.data,
+            0xFF02 => self.serial.read_control(),
+            0xFF04 => self.timer.read_divider(),
+            0xFF05 => self.timer.counter,
+            0xFF06 => self.timer.modulo,
+            0xFF07 => self.timer.read_control(),
+            0xFF0F => self.interrupt_flag.to_byte(),
+            SOUND_REGISTERS_BEGIN..=SOUND_REGISTERS_END => {
+                let offset = addr - SOUND_REGISTERS_BEGIN;
+                self.sound_registers[offset] | SOUND_READ_MASKS.get(offset).copied().unwrap_or(0)
+            }
             0xFF40 => {
                 // LCD Control
                 bit(self.gpu.lcd_display_enabled) << 7
@@ -226,58 +492,65 @@ impl MemBus {
                 // Scroll Y Position
                 self.gpu.viewport_y_offset
             }
+            0xFF43 => {
+                // Scroll X Position
+                self.gpu.viewport_x_offset
+            }
             0xFF44 => {
                 // Current Line
-                self.gpu.line
+                self.gpu.read_line()
             }
-            0xFF47 => {
-                0b11111111
+            0xFF45 => self.gpu.line_check,
+            0xFF47 => self.gpu.background_colors.into(),
+            0xFF48 => self.gpu.read_object_palette(ObjectPalette::Zero),
+            0xFF49 => self.gpu.read_object_palette(ObjectPalette::One),
+            0xFF4A => self.gpu.window.y,
+            0xFF4B => self.gpu.window.x,
+            0xFF4D | 0xFF4F | 0xFF68..=0xFF6C | 0xFF70 if self.model != Model::CGB => 0xFF,
+            // HDMA only exists in CGB mode, like for the writes
+            0xFF51..=0xFF55 if !self.cgb_mode() => 0xFF,
+            0xFF4D => {
+                // Prepare Speed Switch
+                bit(self.double_speed) << 7 | 0b01111110 | bit(self.speed_switch_requested)
             }
-            _ => panic!("Reading from an unknown I/O register {:x}", addr),
-        }
+            0xFF4F => 0b11111110 | self.gpu.vram_bank as u8,
+            0xFF51..=0xFF54 => 0xFF,
+            0xFF55 => self.hdma.read_control(),
+            0xFF68 => 0b01000000 | self.gpu.background_palette_index,
+            0xFF69 => self.gpu.background_palette_ram[(self.gpu.background_palette_index & 0x3F) as usize],
+            0xFF6A => 0b01000000 | self.gpu.object_palette_index,
+            0xFF6B => self.gpu.object_palette_ram[(self.gpu.object_palette_index & 0x3F) as usize],
+            0xFF6C => 0b11111110 | bit(self.gpu.object_priority_by_coordinate),
+            0xFF70 => 0b11111000 | self.working_ram_bank as u8,
+            _ => return None,
+        };
+        Some(value)
     }
 
     fn write_io_register(&mut self, addr: usize, value: u8) {
+        if !self.try_write_io_register(addr, value) {
+            panic!("Writting '0b{:b}' to an unknown I/O register {:x}", value, addr);
+        }
+    }
+
+    // False for registers that don't exist
+    fn try_write_io_register(&mut self, addr: usize, value: u8) -> bool {
         match addr {
             0xFF00 => {
-                self.controller.column = if (value & 0x20) == 0 {
-                    Column::One
-                } else {
-                    Column::Zero
-                };
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_joypad(value);
+                }
+                self.update_joypad(|controller| controller.write_select(value));
             }
-            0xFF01 => { /* Serial Transfer */ }
-            0xFF02 => { /* Serial Transfer Control */ }
-            0xFF04 => { /* TODO */ },
-            0xFF05 => { /* TODO */ }
-            0xFF06 => { /* TODO */ }
-            0xFF07 => { /* TODO */ }
-            0xFF0F => { /* TODO */ },
-            0xFF10 => { /* Channel 1 Sweep register */ }
-            0xFF11 => { /* Channel 1 Sound Length and Wave */ }
-            0xFF12 => { /* Channel 1 Sound Control */ }
-            0xFF13 => { /* Channel 1 Frequency lo */ }
-            0xFF14 => { /* Channel 1 Control */ }
-            0xFF16 => { /* Channel 2 Sound Control */ }
-            0xFF17 => { /* Channel 2 Sound Control */ }
-            0xFF18 => { /* Channel 2 Sound Control */ }
-            0xFF19 => { /* Channel 2 Frequency hi data*/ }
-            0xFF1A => { /* Channel 3 Sound on/off */ }
-            0xFF1B => { /* Channel 3 Sound on/off */ }
-            0xFF1C => { /* Channel 3 Sound on/off */ }
-            0xFF1D => { /* Channel 3 Sound on/off */ }
-            0xFF1E => { /* Channel 3 Sound on/off */ }
-            0xFF20 => { /* Channel 4 Volumn */ }
-            0xFF21 => { /* Channel 4 Volumn */ }
-            0xFF22 => { /* Channel 4 Volumn */ }
-            0xFF23 => { /* Channel 4 Counter/consecutive */ }
-            0xFF24 => { /* Sound  Volume */ }
-            0xFF25 => { /* Sound output terminal selection */ }
-            0xFF26 => { /* Sound on/off */ }
-            0xff30 | 0xff31 | 0xff32 | 0xff33 | 0xff34 | 0xff35 | 0xff36 | 0xff37 | 0xff38
-            | 0xff39 | 0xff3a | 0xff3b | 0xff3c | 0xff3d | 0xff3e | 0xff3f => {
-                //Wave Pattern RAM
-
+            0xFF01 => self.serial.data = value,
+            0xFF02 => self.serial.write_control(value),
+            0xFF04 => self.reset_divider(),
+            0xFF05 => self.timer.write_counter(value),
+            0xFF06 => self.timer.modulo = value,
+            0xFF07 => self.timer.write_control(value),
+            0xFF0F => self.interrupt_flag.from_byte(value),
+            SOUND_REGISTERS_BEGIN..=SOUND_REGISTERS_END => {
+                self.sound_registers[addr - SOUND_REGISTERS_BEGIN] = value;
             }
             0xFF40 => {
                 // LCD Control
@@ -332,7 +605,7 @@ impl MemBus {
                 for offset in 0..150 {
                     self.write_byte(
                         dma_destination + offset,
-                        self.read_byte(dma_source + offset),
+                        self.read_dma_source(dma_source + offset),
                     )
                 }
             }
@@ -344,11 +617,13 @@ impl MemBus {
                 self.gpu.obj_0_color_3 = (value >> 6).into();
                 self.gpu.obj_0_color_2 = ((value >> 4) & 0b11).into();
                 self.gpu.obj_0_color_1 = ((value >> 2) & 0b11).into();
+                self.gpu.obj_0_color_0 = (value & 0b11).into();
             }
             0xFF49 => {
                 self.gpu.obj_1_color_3 = (value >> 6).into();
                 self.gpu.obj_1_color_2 = ((value >> 4) & 0b11).into();
                 self.gpu.obj_1_color_1 = ((value >> 2) & 0b11).into();
+                self.gpu.obj_1_color_0 = (value & 0b11).into();
             }
             0xFF4A => {
                 self.gpu.window.y = value;
@@ -356,17 +631,327 @@ impl MemBus {
             0xFF4B => {
                 self.gpu.window.x = value;
             }
+            0xFF4C => {
+                // The CGB boot ROM switches into DMG compatibility mode here before it unmaps itself
+                if self.model == Model::CGB && self.bootrom.is_some() {
+                    self.gpu.dmg_compatibility = value & 0b100 != 0;
+                }
+            }
+            0xFF4D => {
+                if self.model == Model::CGB {
+                    self.speed_switch_requested = value & 0b1 == 0b1;
+                }
+            }
+            0xFF4F => {
+                // VRAM Bank
+                if self.cgb_mode() {
+                    self.gpu.vram_bank = (value & 0b1) as usize;
+                }
+            }
             0xFF50 => {
                 // Unmap boot ROM
                 self.bootrom = None;
             }
-            0xFF70..=0xFF7f => {
+            0xFF51..=0xFF55 if !self.cgb_mode() => { /* HDMA only exists in CGB mode */ }
+            0xFF51 => self.hdma.write_source_high(value),
+            0xFF52 => self.hdma.write_source_low(value),
+            0xFF53 => self.hdma.write_destination_high(value),
+            0xFF54 => self.hdma.write_destination_low(value),
+            0xFF55 => self.write_hdma_control(value),
+            0xFF68 => {
+                if self.palettes_writable() {
+                    self.gpu.background_palette_index = value & 0b10111111;
+                }
+            }
+            0xFF69 => {
+                if self.palettes_writable() {
+                    self.gpu.write_background_palette_data(value);
+                }
+            }
+            0xFF6A => {
+                if self.palettes_writable() {
+                    self.gpu.object_palette_index = value & 0b10111111;
+                }
+            }
+            0xFF6B => {
+                if self.palettes_writable() {
+                    self.gpu.write_object_palette_data(value);
+                }
+            }
+            0xFF6C => {
+                // Object priority mode, also set by the boot ROM
+                if self.model == Model::CGB && self.bootrom.is_some() {
+                    self.gpu.object_priority_by_coordinate = value & 0b1 == 0b1;
+                }
+            }
+            0xFF70 => {
+                // WRAM Bank, 0 selects bank 1
+                if self.cgb_mode() {
+                    self.working_ram_bank = ((value & 0b111) as usize).max(1);
+                }
+            }
+            0xFF71..=0xFF7F => {
                 // Writing to here does nothing
             }
-            _ => panic!(
-                "Writting '0b{:b}' to an unknown I/O register {:x}",
-                value, addr
-            ),
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl Bus for MemBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.read_byte(address);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, value, false);
+        }
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, value, true);
+        }
+        self.write_byte(address, value);
+    }
+
+    fn bank_at(&self, address: u16) -> usize {
+        match address as usize {
+            // Bank 1 until there is a memory bank controller
+            ROM_BANK_N_BEGIN..=ROM_BANK_N_END => 1,
+            VRAM_BEGIN..=VRAM_END => self.gpu.vram_bank,
+            WORKING_RAM_BEGIN..=WORKING_RAM_END if address as usize >= WORKING_RAM_BEGIN + WORKING_RAM_BANK_SIZE => {
+                self.working_ram_bank
+            }
+            _ => 0,
+        }
+    }
+
+    // Unmapped memory reads as 0xFF instead of panicking, debuggers look everywhere
+    fn peek(&mut self, address: u16) -> u8 {
+        match address as usize {
+            // There is no memory bank controller to put RAM here yet
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END => 0xFF,
+            IO_REGISTERS_BEGIN..=IO_REGISTERS_END => self.try_read_io_register(address as usize).unwrap_or(0xFF),
+            _ => self.read_byte(address),
+        }
+    }
+
+    fn tick(&mut self) {
+        self.step(4);
+    }
+
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        MemBus::pending_interrupt(self)
+    }
+
+    fn acknowledge(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag.clear(interrupt);
+    }
+
+    // On the CGB a STOP after arming KEY1 switches between normal and double speed,
+    // otherwise it waits for a button press unless one is already held
+    fn stop(&mut self) -> bool {
+        if self.speed_switch_requested {
+            self.switch_speed();
+            false
+        } else {
+            self.reset_divider();
+            !self.joypad_line_low()
+        }
+    }
+
+    fn joypad_line_low(&self) -> bool {
+        MemBus::joypad_line_low(self)
+    }
+
+    fn take_stalled_cycles(&mut self) -> u32 {
+        MemBus::take_stalled_cycles(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn membus(model: Model) -> MemBus {
+        MemBus::new(None, vec![0; ROM_BANK_0_SIZE + ROM_BANK_N_SIZE], model)
+    }
+
+    #[test]
+    fn hdma_registers_follow_cgb_mode() {
+        let mut bus = membus(Model::CGB);
+        bus.gpu.lcd_display_enabled = true;
+        bus.write_io_register(0xFF55, 0x85);
+        assert_eq!(bus.read_io_register(0xFF55), 0x05);
+
+        // A DMG game on the CGB neither starts nor sees a transfer
+        let mut bus = membus(Model::CGB);
+        bus.gpu.lcd_display_enabled = true;
+        bus.gpu.dmg_compatibility = true;
+        bus.write_io_register(0xFF55, 0x85);
+        assert!(!bus.hdma.hblank_active);
+        bus.hdma.hblank_active = true;
+        assert_eq!(bus.read_io_register(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn joypad_interrupt() {
+        let mut bus = membus(Model::DMG);
+        // Only the buttons are selected, pressing a direction changes no line
+        bus.write_io_register(0xFF00, 0x10);
+        bus.press(Button::Left);
+        assert!(!bus.interrupt_flag.joypad);
+        bus.press(Button::Start);
+        assert!(bus.interrupt_flag.joypad);
+
+        // Selecting the directions while Left is held pulls a line low too
+        bus.interrupt_flag.joypad = false;
+        bus.release(Button::Start);
+        assert!(!bus.interrupt_flag.joypad);
+        bus.write_io_register(0xFF00, 0x20);
+        assert!(bus.interrupt_flag.joypad);
+        assert!(bus.joypad_line_low());
+    }
+
+    #[test]
+    fn working_ram_banks() {
+        let mut bus = membus(Model::CGB);
+        bus.write_byte(0xC000, 0xC0);
+        bus.write_byte(0xD000, 0x01);
+        bus.write_io_register(0xFF70, 2);
+        bus.write_byte(0xD000, 0x02);
+        assert_eq!(bus.read_byte(0xD000), 0x02);
+
+        // Bank 0 selects bank 1
+        bus.write_io_register(0xFF70, 0);
+        assert_eq!(bus.read_io_register(0xFF70), 0xF9);
+        assert_eq!(bus.read_byte(0xD000), 0x01);
+        bus.write_io_register(0xFF70, 2);
+        assert_eq!(bus.read_byte(0xD000), 0x02);
+        // The lower half and echo RAM don't move
+        assert_eq!(bus.read_byte(0xC000), 0xC0);
+        assert_eq!(bus.read_byte(0xF000), 0x02);
+
+        // DMG games on the CGB only ever see bank 1
+        let mut bus = membus(Model::CGB);
+        bus.gpu.dmg_compatibility = true;
+        bus.write_byte(0xD000, 0x01);
+        bus.write_io_register(0xFF70, 2);
+        assert_eq!(bus.read_byte(0xD000), 0x01);
+    }
+
+    #[test]
+    fn palette_auto_increment() {
+        let mut bus = membus(Model::CGB);
+        bus.write_io_register(0xFF68, 0x80 | 0x3E);
+        for value in [0x11, 0x22, 0x33].iter() {
+            bus.write_io_register(0xFF69, *value);
+        }
+        // Wraps around to the start of palette RAM
+        assert_eq!(bus.read_io_register(0xFF68), 0xC1);
+        assert_eq!(bus.gpu.background_palette_ram[0x3E], 0x11);
+        assert_eq!(bus.gpu.background_palette_ram[0x3F], 0x22);
+        assert_eq!(bus.gpu.background_palette_ram[0x00], 0x33);
+
+        // Reads never move the index
+        bus.write_io_register(0xFF68, 0x80 | 0x3F);
+        assert_eq!(bus.read_io_register(0xFF69), 0x22);
+        assert_eq!(bus.read_io_register(0xFF69), 0x22);
+        assert_eq!(bus.read_io_register(0xFF68), 0xFF);
+
+        // Without bit 7 the same byte gets written over
+        bus.write_io_register(0xFF68, 0x05);
+        bus.write_io_register(0xFF69, 0x44);
+        bus.write_io_register(0xFF69, 0x55);
+        assert_eq!(bus.read_io_register(0xFF68), 0x45);
+        assert_eq!(bus.read_io_register(0xFF69), 0x55);
+    }
+
+    // Points HDMA1-HDMA4 at a source and VRAM at 0x8000
+    fn hdma_from(bus: &mut MemBus, source: u16) {
+        bus.write_io_register(0xFF51, (source >> 8) as u8);
+        bus.write_io_register(0xFF52, source as u8);
+        bus.write_io_register(0xFF53, 0x00);
+        bus.write_io_register(0xFF54, 0x00);
+    }
+
+    fn fill_work_ram(bus: &mut MemBus) {
+        for offset in 0..0x80u16 {
+            bus.write_byte(0xC000 + offset, offset as u8 + 1);
+        }
+    }
+
+    fn copied(bus: &MemBus) -> usize {
+        (0..0x80).take_while(|&offset| bus.gpu.read_vram(offset) == offset as u8 + 1).count()
+    }
+
+    fn run_until_hblank(bus: &mut MemBus) {
+        loop {
+            let mode = bus.gpu.mode;
+            bus.step(4);
+            if mode != Mode::HorizontalBlank && bus.gpu.mode == Mode::HorizontalBlank {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn general_purpose_dma() {
+        for &(double_speed, stalled) in [(false, 96), (true, 192)].iter() {
+            let mut bus = membus(Model::CGB);
+            bus.double_speed = double_speed;
+            fill_work_ram(&mut bus);
+            hdma_from(&mut bus, 0xC000);
+            // 3 blocks of 16 bytes, all copied before the CPU carries on
+            bus.write_io_register(0xFF55, 0x02);
+            assert_eq!(copied(&bus), 48);
+            assert_eq!(bus.take_stalled_cycles(), stalled);
+            assert_eq!(bus.read_io_register(0xFF55), 0xFF);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn hblank_dma() {
+        let mut bus = membus(Model::CGB);
+        bus.gpu.lcd_display_enabled = true;
+        fill_work_ram(&mut bus);
+        hdma_from(&mut bus, 0xC000);
+        bus.write_io_register(0xFF55, 0x81);
+        assert_eq!(copied(&bus), 0);
+        assert_eq!(bus.read_io_register(0xFF55), 0x01);
+
+        run_until_hblank(&mut bus);
+        assert_eq!(copied(&bus), 16);
+        assert_eq!(bus.read_io_register(0xFF55), 0x00);
+        run_until_hblank(&mut bus);
+        assert_eq!(copied(&bus), 32);
+        assert_eq!(bus.read_io_register(0xFF55), 0xFF);
+        run_until_hblank(&mut bus);
+        assert_eq!(copied(&bus), 32);
+    }
+
+    #[test]
+    fn cancel_hblank_dma() {
+        let mut bus = membus(Model::CGB);
+        bus.gpu.lcd_display_enabled = true;
+        fill_work_ram(&mut bus);
+        hdma_from(&mut bus, 0xC000);
+        bus.write_io_register(0xFF55, 0x83);
+        run_until_hblank(&mut bus);
+
+        bus.write_io_register(0xFF55, 0x00);
+        // Bit 7 set again with what was left of the length
+        assert_eq!(bus.read_io_register(0xFF55), 0x82);
+        run_until_hblank(&mut bus);
+        assert_eq!(copied(&bus), 16);
+    }
+
+    #[test]
+    fn dma_from_external_ram() {
+        let mut bus = membus(Model::CGB);
+        hdma_from(&mut bus, 0xA000);
+        bus.write_io_register(0xFF55, 0x00);
+        assert!((0..16).all(|offset| bus.gpu.read_vram(offset) == 0xFF));
+    }
+}
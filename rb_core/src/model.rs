@@ -0,0 +1,43 @@
+use super::mem::{BOOT_ROM_SIZE, CGB_BOOT_ROM_SIZE};
+
+pub const CGB_FLAG_ADDRESS: usize = 0x143;
+pub const SGB_FLAG_ADDRESS: usize = 0x146;
+pub const OLD_LICENSEE_CODE_ADDRESS: usize = 0x14B;
+pub const HEADER_CHECKSUM_ADDRESS: usize = 0x14D;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Model {
+    DMG,
+    SGB,
+    CGB,
+}
+
+impl Model {
+    // Picks the hardware to emulate from the cartridge header
+    pub fn from_header(rom: &[u8]) -> Model {
+        if supports_cgb(rom) {
+            Model::CGB
+        } else if supports_sgb(rom) {
+            Model::SGB
+        } else {
+            Model::DMG
+        }
+    }
+
+    pub fn boot_rom_size(&self) -> usize {
+        match self {
+            Model::DMG | Model::SGB => BOOT_ROM_SIZE,
+            Model::CGB => CGB_BOOT_ROM_SIZE,
+        }
+    }
+}
+
+// Bit 7 of the CGB flag is set both for CGB enhanced (0x80) and CGB only (0xC0) games
+pub fn supports_cgb(rom: &[u8]) -> bool {
+    rom.get(CGB_FLAG_ADDRESS).is_some_and(|flag| flag & 0x80 != 0)
+}
+
+// The SGB only accepts packets from games with the SGB flag and the old licensee code 0x33
+pub fn supports_sgb(rom: &[u8]) -> bool {
+    rom.get(SGB_FLAG_ADDRESS) == Some(&0x03) && rom.get(OLD_LICENSEE_CODE_ADDRESS) == Some(&0x33)
+}
@@ -0,0 +1,183 @@
+use std::io::Read;
+
+pub type Rgb = [u8; 3];
+
+// Maps the four DMG shades (white, light gray, dark gray, black) to the RGB
+// values written into the canvas buffer, separately for each layer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub background: [Rgb; 4],
+    pub object_0: [Rgb; 4],
+    pub object_1: [Rgb; 4],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::grey()
+    }
+}
+
+impl Palette {
+    pub const PRESETS: [&'static str; 4] = ["grey", "dmg", "pocket", "light"];
+
+    pub fn uniform(colors: [Rgb; 4]) -> Palette {
+        Palette {
+            background: colors,
+            object_0: colors,
+            object_1: colors,
+        }
+    }
+
+    pub fn grey() -> Palette {
+        Palette::uniform([[255, 255, 255], [192, 192, 192], [96, 96, 96], [0, 0, 0]])
+    }
+
+    // The green tinted screen of the original DMG
+    pub fn classic_green() -> Palette {
+        Palette::uniform([[0x9B, 0xBC, 0x0F], [0x8B, 0xAC, 0x0F], [0x30, 0x62, 0x30], [0x0F, 0x38, 0x0F]])
+    }
+
+    pub fn pocket() -> Palette {
+        Palette::uniform([[0xC4, 0xCF, 0xA1], [0x8B, 0x95, 0x6D], [0x4D, 0x53, 0x3C], [0x1F, 0x1F, 0x1F]])
+    }
+
+    // The electroluminescent backlight of the Game Boy Light
+    pub fn light() -> Palette {
+        Palette::uniform([[0x00, 0xB5, 0x81], [0x00, 0x9A, 0x71], [0x00, 0x69, 0x4A], [0x00, 0x4F, 0x3B]])
+    }
+
+    pub fn from_preset(name: &str) -> Option<Palette> {
+        match name.to_ascii_lowercase().as_str() {
+            "grey" | "gray" => Some(Palette::grey()),
+            "dmg" | "green" => Some(Palette::classic_green()),
+            "pocket" | "mgb" => Some(Palette::pocket()),
+            "light" => Some(Palette::light()),
+            _ => None,
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Palette, String> {
+        let mut file = std::fs::File::open(path)
+            .map_err(|e| format!("Could not open palette file {}: {}", path, e))?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)
+            .map_err(|e| format!("Could not read palette file {}: {}", path, e))?;
+        Palette::parse(&text)
+    }
+
+    // Palette files hold one layer per line, each followed by four hex colours
+    // from lightest to darkest shade:
+    //
+    //     # comment
+    //     bg   = #9BBC0F #8BAC0F #306230 #0F380F
+    //     obj0 = E0F8D0 88C070 346856 081820
+    //     obj1 = E0F8D0 88C070 346856 081820
+    //
+    // Object layers that are left out use the background colours.
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        let mut background = None;
+        let mut object_0 = None;
+        let mut object_1 = None;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Line {}: expected '<layer> = <colours>'", number + 1))?;
+            let colors = parse_colors(value).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+            match key.trim().to_ascii_lowercase().as_str() {
+                "bg" => background = Some(colors),
+                "obj0" => object_0 = Some(colors),
+                "obj1" => object_1 = Some(colors),
+                other => return Err(format!("Line {}: unknown layer '{}'", number + 1, other)),
+            }
+        }
+
+        let background = background.ok_or("Palette file has no 'bg' line")?;
+        Ok(Palette {
+            background,
+            object_0: object_0.unwrap_or(background),
+            object_1: object_1.unwrap_or(background),
+        })
+    }
+}
+
+fn parse_colors(value: &str) -> Result<[Rgb; 4], String> {
+    let colors: Vec<&str> = value.split_whitespace().collect();
+    if colors.len() != 4 {
+        return Err(format!("expected 4 colours got {}", colors.len()));
+    }
+    let mut result = [[0; 3]; 4];
+    for (i, color) in colors.iter().enumerate() {
+        let hex = color.trim_start_matches('#');
+        let rgb = u32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|_| hex.len() == 6 && hex.bytes().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| format!("'{}' is not an RRGGBB colour", color))?;
+        result[i] = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+    }
+    Ok(result)
+}
+
+// CGB and SGB colours are little endian RGB555
+pub fn from_rgb555(color: u16) -> Rgb {
+    let expand = |value: u16| ((value << 3) | (value >> 2)) as u8;
+    [expand(color & 0x1F), expand((color >> 5) & 0x1F), expand((color >> 10) & 0x1F)]
+}
+
+pub fn to_rgb555(rgb: Rgb) -> u16 {
+    (rgb[0] as u16 >> 3) | (rgb[1] as u16 >> 3) << 5 | (rgb[2] as u16 >> 3) << 10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_layers() {
+        let text = "
+            # comment
+            bg   = #9BBC0F #8BAC0F #306230 #0F380F
+            OBJ1 = e0f8d0 88c070 346856 081820
+        ";
+        let palette = Palette::parse(text).unwrap();
+        assert_eq!(palette.background, Palette::classic_green().background);
+        assert_eq!(palette.object_0, palette.background);
+        assert_eq!(palette.object_1, [[0xE0, 0xF8, 0xD0], [0x88, 0xC0, 0x70], [0x34, 0x68, 0x56], [0x08, 0x18, 0x20]]);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let error = |text| Palette::parse(text).unwrap_err();
+        assert_eq!(error("obj0 = 000000 000000 000000 000000"), "Palette file has no 'bg' line");
+        assert_eq!(error("bg 000000 000000 000000 000000"), "Line 1: expected '<layer> = <colours>'");
+        assert_eq!(error("\nbg = 000000 000000 000000"), "Line 2: expected 4 colours got 3");
+        assert_eq!(error("bg = 000000 00000 000000 000000"), "Line 1: '00000' is not an RRGGBB colour");
+        assert_eq!(error("bg = 000000 +00000 000000 000000"), "Line 1: '+00000' is not an RRGGBB colour");
+        assert_eq!(error("bg = 000000 00000G 000000 000000"), "Line 1: '00000G' is not an RRGGBB colour");
+        assert_eq!(error("win = 000000 000000 000000 000000"), "Line 1: unknown layer 'win'");
+    }
+
+    #[test]
+    fn presets() {
+        for name in Palette::PRESETS {
+            assert!(Palette::from_preset(name).is_some(), "{}", name);
+        }
+        assert_eq!(Palette::from_preset("Gray"), Some(Palette::grey()));
+        assert_eq!(Palette::from_preset("sepia"), None);
+    }
+
+    #[test]
+    fn rgb555() {
+        assert_eq!(from_rgb555(0x7FFF), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(from_rgb555(0x001F), [0xFF, 0, 0]);
+        assert_eq!(from_rgb555(0x03E0), [0, 0xFF, 0]);
+        assert_eq!(from_rgb555(0x7C00), [0, 0, 0xFF]);
+        for color in [0x0000, 0x1234, 0x7FFF, 0x5555] {
+            assert_eq!(to_rgb555(from_rgb555(color)), color);
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use super::postprocess::Frame;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+// Largest block deflate can store without compressing
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+// Order the code length code lengths of a dynamic Huffman block are stored in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// Writes the frame as an 8 bit RGBA PNG. The image data is stored uncompressed,
+// which keeps this small and is fine for screenshots of a 160x144 screen.
+pub fn encode(frame: &Frame) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&(frame.width as u32).to_be_bytes());
+    header.extend_from_slice(&(frame.height as u32).to_be_bytes());
+    // 8 bits per channel, RGBA, default compression, filtering and no interlacing
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    // Every row starts with filter type 0
+    let mut raw = Vec::with_capacity((frame.width * 4 + 1) * frame.height);
+    for row in frame.pixels.chunks(frame.width * 4) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0x78, 0x01];
+    let blocks = data.chunks(STORED_BLOCK_SIZE).collect::<Vec<_>>();
+    for (index, block) in blocks.iter().enumerate() {
+        let last = index == blocks.len() - 1;
+        output.push(if last { 1 } else { 0 });
+        let length = block.len() as u16;
+        output.extend_from_slice(&length.to_le_bytes());
+        output.extend_from_slice(&(!length).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+    if blocks.is_empty() {
+        output.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+// Reads a non-interlaced PNG with 8 bit channels, or a palette or greyscale image with fewer bits
+// per pixel, into an RGBA frame. That covers what we write and the usual reference screenshots.
+pub fn decode(bytes: &[u8]) -> Result<Frame, String> {
+    if !bytes.starts_with(&SIGNATURE) {
+        return Err("Not a PNG file".to_string());
+    }
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut transparency: &[u8] = &[];
+    let mut compressed = Vec::new();
+    let mut position = SIGNATURE.len();
+    while position + 8 <= bytes.len() {
+        let length = u32::from_be_bytes(bytes[position..position + 4].try_into().unwrap()) as usize;
+        let kind = &bytes[position + 4..position + 8];
+        let data = bytes
+            .get(position + 8..position + 8 + length)
+            .ok_or_else(|| format!("{} chunk is cut off", String::from_utf8_lossy(kind)))?;
+        match kind {
+            b"IHDR" => header = Some(data),
+            b"PLTE" => palette = data,
+            b"tRNS" => transparency = data,
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            _ => {}
+        }
+        // Skips the CRC as well
+        position += length + 12;
+    }
+
+    let header = match header {
+        Some(header) if header.len() == 13 => header,
+        _ => return Err("Missing image header".to_string()),
+    };
+    let width = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let height = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    let (depth, colour_type, interlace) = (header[8] as usize, header[9], header[12]);
+    let channels = match colour_type {
+        0 | 3 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => return Err(format!("Unknown colour type {}", colour_type)),
+    };
+    if interlace != 0 {
+        return Err("Interlaced images are not supported".to_string());
+    }
+    if depth != 8 && !(matches!(colour_type, 0 | 3) && matches!(depth, 1 | 2 | 4)) {
+        return Err(format!("{} bit colour type {} is not supported", depth, colour_type));
+    }
+
+    let raw = inflate(compressed.get(2..).ok_or("Missing image data")?)?;
+    let stride = (width * channels * depth).div_ceil(8);
+    let bytes_per_pixel = (channels * depth).div_ceil(8).max(1);
+    if raw.len() < (stride + 1) * height {
+        return Err("Not enough image data".to_string());
+    }
+
+    let mut frame = Frame::new(width, height);
+    let mut previous = vec![0; stride];
+    for y in 0..height {
+        let filter = raw[y * (stride + 1)];
+        let mut row = raw[y * (stride + 1) + 1..(y + 1) * (stride + 1)].to_vec();
+        unfilter(filter, &mut row, &previous, bytes_per_pixel)?;
+        for x in 0..width {
+            let sample = |channel: usize| match depth {
+                8 => row[x * channels + channel],
+                _ => {
+                    let bit = x * depth;
+                    (row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8
+                }
+            };
+            let rgba = match colour_type {
+                0 => {
+                    let grey = (sample(0) as usize * 255 / ((1 << depth) - 1)) as u8;
+                    [grey, grey, grey, 0xFF]
+                }
+                2 => [sample(0), sample(1), sample(2), 0xFF],
+                3 => {
+                    let index = sample(0) as usize;
+                    let colour = palette
+                        .get(index * 3..index * 3 + 3)
+                        .ok_or_else(|| format!("Palette index {} out of range", index))?;
+                    [colour[0], colour[1], colour[2], transparency.get(index).copied().unwrap_or(0xFF)]
+                }
+                4 => [sample(0), sample(0), sample(0), sample(1)],
+                _ => [sample(0), sample(1), sample(2), sample(3)],
+            };
+            frame.pixels[(y * width + x) * 4..(y * width + x) * 4 + 4].copy_from_slice(&rgba);
+        }
+        previous = row;
+    }
+    Ok(frame)
+}
+
+fn unfilter(filter: u8, row: &mut [u8], previous: &[u8], bytes_per_pixel: usize) -> Result<(), String> {
+    for i in 0..row.len() {
+        let left = if i >= bytes_per_pixel { row[i - bytes_per_pixel] } else { 0 };
+        let up = previous[i];
+        let up_left = if i >= bytes_per_pixel { previous[i - bytes_per_pixel] } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            4 => {
+                let estimate = left as i16 + up as i16 - up_left as i16;
+                let (a, b, c) = ((estimate - left as i16).abs(), (estimate - up as i16).abs(), (estimate - up_left as i16).abs());
+                if a <= b && a <= c {
+                    left
+                } else if b <= c {
+                    up
+                } else {
+                    up_left
+                }
+            }
+            _ => return Err(format!("Unknown filter type {}", filter)),
+        };
+        row[i] = row[i].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+// Reads deflate data least significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, count: u8) -> Result<u32, String> {
+        let mut value = 0;
+        for bit in 0..count {
+            let byte = *self.data.get(self.position / 8).ok_or("Image data ends early")?;
+            value |= ((byte >> (self.position % 8)) as u32 & 1) << bit;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+}
+
+// Canonical Huffman code, decoded one bit at a time by counting codes of every length
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols = Vec::new();
+        for length in 1..16 {
+            for (symbol, _) in lengths.iter().enumerate().filter(|(_, &l)| l as usize == length) {
+                symbols.push(symbol as u16);
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("Bad Huffman code".to_string())
+    }
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader { data, position: 0 };
+    let mut output = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let start = reader.position / 8;
+                let header = data.get(start..start + 4).ok_or("Image data ends early")?;
+                let length = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let block = data.get(start + 4..start + 4 + length).ok_or("Image data ends early")?;
+                output.extend_from_slice(block);
+                reader.position = (start + 4 + length) * 8;
+            }
+            1 => {
+                let mut lengths = [0; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                inflate_block(&mut reader, &mut output, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            }
+            2 => {
+                let literal_count = reader.bits(5)? as usize + 257;
+                let distance_count = reader.bits(5)? as usize + 1;
+                let code_length_count = reader.bits(4)? as usize + 4;
+                let mut code_lengths = [0; 19];
+                for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+                    code_lengths[index] = reader.bits(3)? as u8;
+                }
+                let code_lengths = Huffman::new(&code_lengths);
+                let mut lengths = Vec::new();
+                while lengths.len() < literal_count + distance_count {
+                    let (value, repeat) = match code_lengths.decode(&mut reader)? {
+                        16 => (*lengths.last().ok_or("Nothing to repeat")?, 3 + reader.bits(2)?),
+                        17 => (0, 3 + reader.bits(3)?),
+                        18 => (0, 11 + reader.bits(7)?),
+                        length => (length as u8, 1),
+                    };
+                    lengths.extend(std::iter::repeat_n(value, repeat as usize));
+                }
+                let literals = Huffman::new(&lengths[..literal_count]);
+                let distances = Huffman::new(&lengths[literal_count..literal_count + distance_count]);
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            }
+            _ => return Err("Bad deflate block type".to_string()),
+        }
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+fn inflate_block(reader: &mut BitReader, output: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                if index >= LENGTH_BASE.len() {
+                    return Err("Bad length code".to_string());
+                }
+                let length = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index])? as usize;
+                let index = distances.decode(reader)? as usize;
+                if index >= DISTANCE_BASE.len() {
+                    return Err("Bad distance code".to_string());
+                }
+                let distance = DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index])? as usize;
+                if distance > output.len() {
+                    return Err("Distance goes back too far".to_string());
+                }
+                for _ in 0..length {
+                    output.push(output[output.len() - distance]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Made with zlib, a 5x5 RGB image with row y filtered with filter type y, once with
+    // fixed and once with dynamic Huffman codes
+    const FIXED: [u8; 133] = [
+        0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52,
+        0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x05, 0x08, 0x02, 0x00, 0x00, 0x00, 0x02, 0x0D, 0xB1,
+        0xB2, 0x00, 0x00, 0x00, 0x4C, 0x49, 0x44, 0x41, 0x54, 0x78, 0x01, 0x63, 0x60, 0x60, 0x60, 0x30,
+        0x62, 0x60, 0x48, 0x61, 0x60, 0x98, 0xC6, 0xC0, 0x70, 0x82, 0x81, 0x81, 0x91, 0xC1, 0x06, 0xC8,
+        0x17, 0x84, 0x23, 0x26, 0x20, 0x9F, 0xC1, 0x46, 0x90, 0xC1, 0x46, 0x89, 0xC1, 0xC6, 0x98, 0xC1,
+        0xC6, 0x85, 0x99, 0xA1, 0x82, 0x41, 0x52, 0x4E, 0x49, 0x52, 0x4E, 0x5B, 0x52, 0xCE, 0x58, 0x52,
+        0xCE, 0x86, 0x05, 0x24, 0xCF, 0x20, 0xC8, 0xC0, 0xA0, 0xC4, 0xC0, 0x60, 0xCC, 0xC0, 0xE0, 0x02,
+        0x00, 0x50, 0x3C, 0x08, 0x13, 0xA7, 0x9E, 0x52, 0xAC, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E,
+        0x44, 0xAE, 0x42, 0x60, 0x82,
+    ];
+    const DYNAMIC: [u8; 126] = [
+        0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52,
+        0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x05, 0x08, 0x02, 0x00, 0x00, 0x00, 0x02, 0x0D, 0xB1,
+        0xB2, 0x00, 0x00, 0x00, 0x45, 0x49, 0x44, 0x41, 0x54, 0x78, 0xDA, 0x3D, 0xC9, 0xAB, 0x11, 0x80,
+        0x30, 0x10, 0x40, 0xC1, 0xE5, 0xD3, 0x40, 0x64, 0xCC, 0x89, 0x58, 0x1C, 0xB1, 0x27, 0x29, 0x85,
+        0x22, 0xA8, 0x84, 0x9A, 0x28, 0x8B, 0x60, 0x98, 0x79, 0x66, 0xE7, 0xC1, 0xCE, 0xC9, 0xCD, 0xC3,
+        0x24, 0x87, 0xCB, 0xDF, 0x3C, 0x2C, 0x8B, 0x6C, 0xB2, 0xCB, 0x63, 0x71, 0xA9, 0xD1, 0x6A, 0x6C,
+        0x35, 0x7A, 0x8D, 0x5C, 0xBF, 0xAF, 0xD0, 0xE8, 0x1C, 0x2F, 0x50, 0x3C, 0x08, 0x13, 0x24, 0x89,
+        0xAC, 0x0C, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82,
+    ];
+    // 2 bit palette image of 5x2 with colour 0 see through
+    const PALETTE: [u8; 108] = [
+        0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52,
+        0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x02, 0x02, 0x03, 0x00, 0x00, 0x00, 0xED, 0x04, 0xFE,
+        0xCE, 0x00, 0x00, 0x00, 0x0C, 0x50, 0x4C, 0x54, 0x45, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00,
+        0xFF, 0x00, 0x00, 0x00, 0xFF, 0x9B, 0xC0, 0x13, 0xDC, 0x00, 0x00, 0x00, 0x01, 0x74, 0x52, 0x4E,
+        0x53, 0x00, 0x40, 0xE6, 0xD8, 0x66, 0x00, 0x00, 0x00, 0x0E, 0x49, 0x44, 0x41, 0x54, 0x78, 0xDA,
+        0x63, 0x90, 0x66, 0x60, 0x78, 0x72, 0x00, 0x00, 0x03, 0x15, 0x01, 0xC0, 0x4D, 0x85, 0x16, 0x6E,
+        0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82,
+    ];
+
+    fn gradient(x: usize, y: usize) -> [u8; 4] {
+        [(x * 50) as u8, (y * 60) as u8, (x * y * 17) as u8, 0xFF]
+    }
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> [u8; 4] {
+        let offset = (y * frame.width + x) * 4;
+        frame.pixels[offset..offset + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        // Big enough to need more than one stored block
+        let mut frame = Frame::new(160, 144);
+        for (index, value) in frame.pixels.iter_mut().enumerate() {
+            *value = (index * 7 % 251) as u8;
+        }
+        assert!(frame.pixels.len() > STORED_BLOCK_SIZE);
+        assert_eq!(decode(&encode(&frame)), Ok(frame));
+    }
+
+    #[test]
+    fn decodes_compressed_images() {
+        for png in [&FIXED[..], &DYNAMIC[..]] {
+            let frame = decode(png).unwrap();
+            assert_eq!((frame.width, frame.height), (5, 5));
+            for y in 0..5 {
+                for x in 0..5 {
+                    assert_eq!(pixel(&frame, x, y), gradient(x, y), "({}, {})", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_palette_images() {
+        let frame = decode(&PALETTE).unwrap();
+        assert_eq!((frame.width, frame.height), (5, 2));
+        assert_eq!(pixel(&frame, 0, 0), [0, 0, 0, 0]);
+        assert_eq!(pixel(&frame, 1, 0), [0xFF, 0, 0, 0xFF]);
+        assert_eq!(pixel(&frame, 2, 0), [0, 0xFF, 0, 0xFF]);
+        assert_eq!(pixel(&frame, 4, 0), [0, 0, 0, 0]);
+        assert_eq!(pixel(&frame, 0, 1), [0, 0, 0xFF, 0xFF]);
+        assert_eq!(pixel(&frame, 4, 1), [0, 0, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_read() {
+        assert_eq!(decode(b"GIF89a"), Err("Not a PNG file".to_string()));
+        assert_eq!(decode(&FIXED[..45]), Err("IDAT chunk is cut off".to_string()));
+
+        // Interlaced, the CRC isn't checked
+        let mut interlaced = FIXED;
+        interlaced[SIGNATURE.len() + 8 + 12] = 1;
+        assert_eq!(decode(&interlaced), Err("Interlaced images are not supported".to_string()));
+    }
+}
@@ -0,0 +1,188 @@
+use super::filters::*;
+use super::upscale::Upscaler;
+use super::png;
+
+// A finished RGBA frame, either straight from the LCD or from a previous post-process stage
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize) -> Frame {
+        Frame {
+            width,
+            height,
+            pixels: vec![0; width * height * 4],
+        }
+    }
+
+    pub fn from_rgba(width: usize, height: usize, pixels: &[u8]) -> Frame {
+        assert_eq!(pixels.len(), width * height * 4, "Frame buffer does not match {}x{}", width, height);
+        Frame {
+            width,
+            height,
+            pixels: pixels.to_vec(),
+        }
+    }
+
+    pub fn save_png(&self, path: &str) -> Result<(), String> {
+        std::fs::write(path, png::encode(self)).map_err(|e| format!("Could not write {}: {}", path, e))
+    }
+
+    pub fn load_png(path: &str) -> Result<Frame, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        png::decode(&bytes).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+pub trait PostProcess {
+    fn process(&mut self, frame: Frame) -> Frame;
+}
+
+// The DMG LCD is slow to change, so a pixel keeps part of its old colour for a few frames.
+// Games that flicker objects every other frame rely on this to look transparent.
+pub struct FrameBlend {
+    // How much of the previous frames stays on screen, 0 turns blending off
+    pub persistence: f32,
+    accumulated: Vec<f32>,
+}
+
+impl FrameBlend {
+    // Any more and a still image would take seconds to show
+    pub const MAX_PERSISTENCE: f32 = 0.95;
+
+    pub fn new(persistence: f32) -> Result<FrameBlend, String> {
+        if !(0.0..=FrameBlend::MAX_PERSISTENCE).contains(&persistence) {
+            return Err(format!("Persistence {} is not between 0 and {}", persistence, FrameBlend::MAX_PERSISTENCE));
+        }
+        Ok(FrameBlend {
+            persistence,
+            accumulated: Vec::new(),
+        })
+    }
+
+    // Forget the old frames, e.g. after loading a different game
+    pub fn reset(&mut self) {
+        self.accumulated.clear();
+    }
+}
+
+impl PostProcess for FrameBlend {
+    fn process(&mut self, mut frame: Frame) -> Frame {
+        // Start over whenever the frame size changes, e.g. when the SGB border appears
+        if self.accumulated.len() != frame.pixels.len() {
+            self.accumulated = frame.pixels.iter().map(|&value| value as f32).collect();
+            return frame;
+        }
+
+        for (accumulated, pixel) in self.accumulated.iter_mut().zip(frame.pixels.iter_mut()) {
+            *accumulated = *accumulated * self.persistence + *pixel as f32 * (1.0 - self.persistence);
+            *pixel = accumulated.round() as u8;
+        }
+        frame
+    }
+}
+
+// Runs post-process stages one after the other, e.g. an upscaler followed by a CRT filter
+pub struct Pipeline {
+    stages: Vec<Box<dyn PostProcess>>,
+}
+
+impl Pipeline {
+    pub const FILTERS: [&'static str; 3] = ["lcd", "gbc-color", "crt"];
+
+    pub fn new() -> Pipeline {
+        Pipeline { stages: Vec::new() }
+    }
+
+    pub fn push(&mut self, stage: Box<dyn PostProcess>) {
+        self.stages.push(stage);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    // Builds a pipeline from a comma separated list of upscaler and filter names, e.g. "hq2x,crt"
+    pub fn parse(text: &str) -> Result<Pipeline, String> {
+        let mut pipeline = Pipeline::new();
+        for name in text.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let stage: Box<dyn PostProcess> = if let Some(upscaler) = Upscaler::from_name(name) {
+                Box::new(upscaler)
+            } else {
+                match name.to_ascii_lowercase().as_str() {
+                    "lcd" | "grid" => Box::new(LcdGrid::default()),
+                    "gbc-color" | "color-correction" => Box::new(ColorCorrection::default()),
+                    "crt" => Box::new(Crt::default()),
+                    _ => {
+                        return Err(format!(
+                            "Unknown filter '{}'\nAvailable filters are: {}, {}",
+                            name,
+                            Upscaler::NAMES.join(", "),
+                            Pipeline::FILTERS.join(", ")
+                        ))
+                    }
+                }
+            };
+            pipeline.push(stage);
+        }
+        Ok(pipeline)
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline::new()
+    }
+}
+
+impl PostProcess for Pipeline {
+    fn process(&mut self, frame: Frame) -> Frame {
+        self.stages.iter_mut().fold(frame, |frame, stage| stage.process(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(width: usize, height: usize, value: u8) -> Frame {
+        Frame::from_rgba(width, height, &vec![value; width * height * 4])
+    }
+
+    #[test]
+    fn blends_alternating_frames() {
+        let mut blend = FrameBlend::new(0.5).unwrap();
+        assert_eq!(blend.process(filled(2, 2, 0)), filled(2, 2, 0));
+        assert_eq!(blend.process(filled(2, 2, 255)), filled(2, 2, 128));
+        assert_eq!(blend.process(filled(2, 2, 0)), filled(2, 2, 64));
+        assert_eq!(blend.process(filled(2, 2, 255)), filled(2, 2, 159));
+    }
+
+    #[test]
+    fn no_persistence_passes_frames_through() {
+        let mut blend = FrameBlend::new(0.0).unwrap();
+        blend.process(filled(2, 2, 0));
+        assert_eq!(blend.process(filled(2, 2, 255)), filled(2, 2, 255));
+        assert_eq!(blend.process(filled(2, 2, 10)), filled(2, 2, 10));
+    }
+
+    #[test]
+    fn new_frame_size_starts_over() {
+        let mut blend = FrameBlend::new(0.5).unwrap();
+        blend.process(filled(2, 2, 0));
+        assert_eq!(blend.process(filled(4, 2, 255)), filled(4, 2, 255));
+        assert_eq!(blend.process(filled(4, 2, 0)), filled(4, 2, 128));
+    }
+
+    #[test]
+    fn persistence_out_of_range() {
+        assert!(FrameBlend::new(-0.1).is_err());
+        assert!(FrameBlend::new(0.96).is_err());
+        assert!(FrameBlend::new(f32::NAN).is_err());
+        assert!(FrameBlend::new(FrameBlend::MAX_PERSISTENCE).is_ok());
+    }
+}
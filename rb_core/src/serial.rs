@@ -0,0 +1,52 @@
+// Shifting out a byte with the internal clock takes 8 bits at 8192 Hz
+const TRANSFER_CYCLES: u32 = 4096;
+
+// SB and SC (0xFF01, 0xFF02). Nothing is plugged into the link port, so every byte
+// sent with the internal clock is answered with 0xFF and external clock transfers never finish.
+pub struct Serial {
+    pub data: u8,
+    transferring: bool,
+    internal_clock: bool,
+    cycles_left: u32,
+    // Every byte that was sent, test ROMs report their results this way
+    pub output: Vec<u8>,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            transferring: false,
+            internal_clock: false,
+            cycles_left: 0,
+            output: Vec::new(),
+        }
+    }
+
+    pub fn read_control(&self) -> u8 {
+        0b01111110 | (if self.transferring { 1 } else { 0 }) << 7 | (if self.internal_clock { 1 } else { 0 })
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        self.internal_clock = value & 0b1 != 0;
+        self.transferring = value & 0x80 != 0;
+        if self.transferring {
+            self.output.push(self.data);
+            self.cycles_left = TRANSFER_CYCLES;
+        }
+    }
+
+    // Returns true when a transfer finished and the serial interrupt should be requested
+    pub fn step(&mut self, cycles: u32) -> bool {
+        if !self.transferring || !self.internal_clock {
+            return false;
+        }
+        self.cycles_left = self.cycles_left.saturating_sub(cycles);
+        if self.cycles_left > 0 {
+            return false;
+        }
+        self.transferring = false;
+        self.data = 0xFF;
+        true
+    }
+}
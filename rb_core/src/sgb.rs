@@ -0,0 +1,611 @@
+use super::gpu::*;
+use super::palette::*;
+
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+
+// Where the game screen sits inside the border
+const GAME_SCREEN_X: usize = 48;
+const GAME_SCREEN_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const MAX_PACKETS: usize = 7;
+const TRANSFER_SIZE: usize = 0x1000;
+
+// Every 8x8 cell of the game screen is coloured with one of the four palettes
+const ATTRIBUTE_WIDTH: usize = SCREEN_WIDTH / 8;
+const ATTRIBUTE_HEIGHT: usize = SCREEN_HEIGHT / 8;
+const ATTRIBUTE_FILE_SIZE: usize = ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT / 4;
+const NUMBER_OF_ATTRIBUTE_FILES: usize = 45;
+const NUMBER_OF_SYSTEM_PALETTES: usize = 512;
+
+const BORDER_TILES: usize = 256;
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_MAP_HEIGHT: usize = 28;
+
+// Palette the SGB starts out with before the game sends any
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mask {
+    Cancel,
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Transfer {
+    Palettes,
+    Tiles(usize),
+    Border,
+    Attributes,
+}
+
+pub struct Sgb {
+    // Packet transfer state, bits arrive least significant first through P14/P15
+    command: [u8; PACKET_SIZE * MAX_PACKETS],
+    bits_received: usize,
+    waiting_for_pulse: bool,
+    receiving: bool,
+    waiting_for_stop_bit: bool,
+    joypad_select: u8,
+
+    pub palettes: [[u16; 4]; 4],
+    system_palettes: Box<[[u16; 4]; NUMBER_OF_SYSTEM_PALETTES]>,
+    pub attributes: [u8; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+    attribute_files: Box<[[u8; ATTRIBUTE_FILE_SIZE]; NUMBER_OF_ATTRIBUTE_FILES]>,
+    border_tiles: Box<[u8; BORDER_TILES * BORDER_TILE_SIZE]>,
+    border_map: [u16; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT],
+    border_palettes: [[u16; 16]; 4],
+    pub mask: Mask,
+    pending_transfer: Option<Transfer>,
+
+    pub player_count: u8,
+    pub current_player: u8,
+
+    game_screen: Box<[u8; SCREEN_WIDTH * SCREEN_HEIGHT * 4]>,
+    // The border with the colourised game screen on top
+    pub output: Box<[u8; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT * 4]>,
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            command: [0; PACKET_SIZE * MAX_PACKETS],
+            bits_received: 0,
+            waiting_for_pulse: false,
+            receiving: false,
+            waiting_for_stop_bit: false,
+            joypad_select: 0x30,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: Box::new([[0; 4]; NUMBER_OF_SYSTEM_PALETTES]),
+            attributes: [0; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+            attribute_files: Box::new([[0; ATTRIBUTE_FILE_SIZE]; NUMBER_OF_ATTRIBUTE_FILES]),
+            border_tiles: Box::new([0; BORDER_TILES * BORDER_TILE_SIZE]),
+            border_map: [0; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT],
+            border_palettes: [[0; 16]; 4],
+            mask: Mask::Cancel,
+            pending_transfer: None,
+            player_count: 1,
+            current_player: 0,
+            game_screen: Box::new([0; SCREEN_WIDTH * SCREEN_HEIGHT * 4]),
+            output: Box::new([0; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT * 4]),
+        }
+    }
+
+    // Games talk to the SGB by pulsing P14 and P15 in the joypad register:
+    // both low starts a transfer, P14 low sends a 0, P15 low sends a 1 and
+    // both high has to follow every bit. Each packet is 16 bytes followed by a 0 stop bit.
+    pub fn write_joypad(&mut self, value: u8) {
+        let select = value & 0x30;
+
+        // With multiple joypads the next one gets selected when P15 goes high
+        if self.player_count > 1 && select & 0x20 != 0 && self.joypad_select & 0x20 == 0 {
+            self.current_player = (self.current_player + 1) % self.player_count;
+        }
+        self.joypad_select = select;
+
+        match select {
+            0x00 => {
+                // Reset pulse, anything half received gets thrown away unless it's
+                // the start of the next packet of a longer command
+                if !self.waiting_for_stop_bit && !self.bits_received.is_multiple_of(PACKET_SIZE * 8) {
+                    self.bits_received = 0;
+                }
+                self.receiving = true;
+                self.waiting_for_pulse = false;
+            }
+            0x30 => self.waiting_for_pulse = true,
+            0x10 | 0x20 if self.receiving && self.waiting_for_pulse => {
+                self.waiting_for_pulse = false;
+                let bit = select == 0x10;
+                if self.waiting_for_stop_bit {
+                    self.receiving = false;
+                    self.waiting_for_stop_bit = false;
+                    if bit {
+                        // A packet must end in a 0, drop the corrupt command
+                        self.bits_received = 0;
+                    } else {
+                        self.packet_received();
+                    }
+                    return;
+                }
+                let byte = self.bits_received / 8;
+                if self.bits_received.is_multiple_of(8) {
+                    self.command[byte] = 0;
+                }
+                if bit {
+                    self.command[byte] |= 1 << (self.bits_received % 8);
+                }
+                self.bits_received += 1;
+                if self.bits_received.is_multiple_of(PACKET_SIZE * 8) {
+                    self.waiting_for_stop_bit = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // In multiplayer mode reading with both lines high gives the selected joypad, 0xF being player 1
+    pub fn joypad_id(&self) -> Option<u8> {
+        if self.player_count > 1 && self.joypad_select == 0x30 {
+            Some(0xF - self.current_player)
+        } else {
+            None
+        }
+    }
+
+    fn packet_received(&mut self) {
+        // The first byte holds the command in its top 5 bits and the number of packets in the rest
+        let packets = ((self.command[0] & 0b111) as usize).max(1);
+        if self.bits_received < packets * PACKET_SIZE * 8 {
+            return;
+        }
+        self.bits_received = 0;
+        let command = self.command;
+        self.execute(command[0] >> 3, &command);
+    }
+
+    fn execute(&mut self, command: u8, data: &[u8]) {
+        match command {
+            0x00 => self.set_palette_pair(0, 1, data),
+            0x01 => self.set_palette_pair(2, 3, data),
+            0x02 => self.set_palette_pair(0, 3, data),
+            0x03 => self.set_palette_pair(1, 2, data),
+            0x04 => self.attribute_block(data),
+            0x05 => self.attribute_line(data),
+            0x06 => self.attribute_divide(data),
+            0x07 => self.attribute_characters(data),
+            0x0A => self.set_system_palettes(data),
+            0x0B => self.pending_transfer = Some(Transfer::Palettes),
+            0x11 => {
+                // MLT_REQ
+                self.player_count = match data[1] & 0b11 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            }
+            0x13 => self.pending_transfer = Some(Transfer::Tiles((data[1] & 0b1) as usize)),
+            0x14 => self.pending_transfer = Some(Transfer::Border),
+            0x15 => self.pending_transfer = Some(Transfer::Attributes),
+            0x16 => {
+                // ATTR_SET
+                self.apply_attribute_file((data[1] & 0x3F) as usize);
+                if data[1] & 0x40 != 0 {
+                    self.mask = Mask::Cancel;
+                }
+            }
+            0x17 => {
+                // MASK_EN
+                self.mask = match data[1] & 0b11 {
+                    0 => Mask::Cancel,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                };
+            }
+            // Sound, the SNES side commands and the like have nothing to do here
+            _ => {}
+        }
+    }
+
+    // PAL01, PAL23, PAL03 and PAL12, colour 0 is shared by all palettes
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |index: usize| data[1 + index * 2] as u16 | (data[2 + index * 2] as u16) << 8;
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for index in 1..4 {
+            self.palettes[first][index] = color(index);
+            self.palettes[second][index] = color(index + 3);
+        }
+    }
+
+    // ATTR_BLK colours the inside, outside and border of up to 18 rectangles
+    fn attribute_block(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(18);
+        for set in data[2..2 + count * 6].chunks(6) {
+            let mut control = set[0] & 0b111;
+            let inside_palette = set[1] & 0b11;
+            let mut line_palette = (set[1] >> 2) & 0b11;
+            let outside_palette = (set[1] >> 4) & 0b11;
+            // Changing only the inside or only the outside also changes the surrounding line
+            if control == 0b001 {
+                control = 0b011;
+                line_palette = inside_palette;
+            } else if control == 0b100 {
+                control = 0b110;
+                line_palette = outside_palette;
+            }
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+
+            for y in 0..ATTRIBUTE_HEIGHT {
+                for x in 0..ATTRIBUTE_WIDTH {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let inside = x > x1 && x < x2 && y > y1 && y < y2;
+                    let palette = if inside {
+                        (control & 0b001 != 0).then_some(inside_palette)
+                    } else if within {
+                        (control & 0b010 != 0).then_some(line_palette)
+                    } else {
+                        (control & 0b100 != 0).then_some(outside_palette)
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * ATTRIBUTE_WIDTH + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    // ATTR_LIN colours whole rows or columns
+    fn attribute_line(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(110);
+        for &line in data[2..2 + count].iter() {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0b11;
+            if line & 0x80 != 0 {
+                if number < ATTRIBUTE_HEIGHT {
+                    self.attributes[number * ATTRIBUTE_WIDTH..(number + 1) * ATTRIBUTE_WIDTH].fill(palette);
+                }
+            } else if number < ATTRIBUTE_WIDTH {
+                for y in 0..ATTRIBUTE_HEIGHT {
+                    self.attributes[y * ATTRIBUTE_WIDTH + number] = palette;
+                }
+            }
+        }
+    }
+
+    // ATTR_DIV splits the screen in two along a row or column
+    fn attribute_divide(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = (data[1] >> 2) & 0b11;
+        let on_line = (data[1] >> 4) & 0b11;
+        let horizontal = data[1] & 0x40 != 0;
+        let divide = data[2] as usize;
+
+        for y in 0..ATTRIBUTE_HEIGHT {
+            for x in 0..ATTRIBUTE_WIDTH {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * ATTRIBUTE_WIDTH + x] = match position.cmp(&divide) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    // ATTR_CHR sets cells one by one, 4 to a byte
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let mut x = (data[1] as usize).min(ATTRIBUTE_WIDTH - 1);
+        let mut y = (data[2] as usize).min(ATTRIBUTE_HEIGHT - 1);
+        let count = (data[3] as usize | (data[4] as usize) << 8).min(ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT);
+        let vertical = data[5] & 0b1 != 0;
+
+        for index in 0..count {
+            let byte = match data.get(6 + index / 4) {
+                Some(byte) => *byte,
+                None => break,
+            };
+            self.attributes[y * ATTRIBUTE_WIDTH + x] = (byte >> (6 - (index % 4) * 2)) & 0b11;
+
+            if vertical {
+                y += 1;
+                if y == ATTRIBUTE_HEIGHT {
+                    y = 0;
+                    x = (x + 1) % ATTRIBUTE_WIDTH;
+                }
+            } else {
+                x += 1;
+                if x == ATTRIBUTE_WIDTH {
+                    x = 0;
+                    y = (y + 1) % ATTRIBUTE_HEIGHT;
+                }
+            }
+        }
+    }
+
+    // PAL_SET picks four of the palettes sent earlier with PAL_TRN
+    fn set_system_palettes(&mut self, data: &[u8]) {
+        for palette in 0..4 {
+            let number = (data[1 + palette * 2] as usize | (data[2 + palette * 2] as usize) << 8)
+                % NUMBER_OF_SYSTEM_PALETTES;
+            self.palettes[palette] = self.system_palettes[number];
+        }
+        // Colour 0 of the first palette is used everywhere
+        let color_0 = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color_0;
+        }
+        if data[9] & 0x80 != 0 {
+            self.apply_attribute_file((data[9] & 0x3F) as usize);
+        }
+        if data[9] & 0x40 != 0 {
+            self.mask = Mask::Cancel;
+        }
+    }
+
+    fn apply_attribute_file(&mut self, number: usize) {
+        if number >= NUMBER_OF_ATTRIBUTE_FILES {
+            return;
+        }
+        let file = self.attribute_files[number];
+        for (index, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (file[index / 4] >> (6 - (index % 4) * 2)) & 0b11;
+        }
+    }
+
+    // Called at the start of V-Blank with the frame the LCD just finished
+    pub fn frame_completed(&mut self, gpu: &GPU) {
+        if let Some(transfer) = self.pending_transfer.take() {
+            self.vram_transfer(transfer, gpu);
+        }
+
+        match self.mask {
+            Mask::Cancel => {
+                for (index, pixel) in self.game_screen.chunks_mut(4).enumerate() {
+                    let palette = self.attributes[(index / SCREEN_WIDTH / 8) * ATTRIBUTE_WIDTH + (index % SCREEN_WIDTH) / 8];
+                    let shade = gpu.shade_buffer[index] as usize;
+                    let color = if shade == 0 { self.palettes[0][0] } else { self.palettes[palette as usize][shade] };
+                    pixel[..3].copy_from_slice(&from_rgb555(color));
+                    pixel[3] = 255;
+                }
+            }
+            Mask::Freeze => {}
+            Mask::Black => fill(&mut self.game_screen[..], [0, 0, 0]),
+            Mask::Color0 => fill(&mut self.game_screen[..], from_rgb555(self.palettes[0][0])),
+        }
+
+        self.compose();
+    }
+
+    // The SGB doesn't read VRAM, it takes the first 4KB of tile data shown on screen
+    fn vram_transfer(&mut self, transfer: Transfer, gpu: &GPU) {
+        let data = screen_tile_data(gpu);
+        match transfer {
+            Transfer::Palettes => {
+                for (palette, colors) in self.system_palettes.iter_mut().zip(data.chunks(8)) {
+                    for (index, color) in palette.iter_mut().enumerate() {
+                        *color = colors[index * 2] as u16 | (colors[index * 2 + 1] as u16) << 8;
+                    }
+                }
+            }
+            Transfer::Tiles(half) => {
+                let offset = half * TRANSFER_SIZE;
+                self.border_tiles[offset..offset + TRANSFER_SIZE].copy_from_slice(&data);
+            }
+            Transfer::Border => {
+                for (index, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = data[index * 2] as u16 | (data[index * 2 + 1] as u16) << 8;
+                }
+                // Followed by the colours of palettes 4-7
+                let colors = &data[0x800..0x880];
+                for (index, color) in self.border_palettes.iter_mut().flatten().enumerate() {
+                    *color = colors[index * 2] as u16 | (colors[index * 2 + 1] as u16) << 8;
+                }
+            }
+            Transfer::Attributes => {
+                for (file, bytes) in self.attribute_files.iter_mut().zip(data.chunks(ATTRIBUTE_FILE_SIZE)) {
+                    file.copy_from_slice(bytes);
+                }
+            }
+        }
+    }
+
+    fn compose(&mut self) {
+        fill(&mut self.output[..], from_rgb555(self.palettes[0][0]));
+
+        for y in 0..SCREEN_HEIGHT {
+            let source = y * SCREEN_WIDTH * 4;
+            let destination = ((GAME_SCREEN_Y + y) * SGB_SCREEN_WIDTH + GAME_SCREEN_X) * 4;
+            self.output[destination..destination + SCREEN_WIDTH * 4]
+                .copy_from_slice(&self.game_screen[source..source + SCREEN_WIDTH * 4]);
+        }
+
+        // The border goes over the game screen, colour 0 is see through
+        for y in 0..SGB_SCREEN_HEIGHT {
+            for x in 0..SGB_SCREEN_WIDTH {
+                let entry = self.border_map[(y / 8) * BORDER_MAP_WIDTH + x / 8];
+                let color = self.border_pixel(entry, x % 8, y % 8);
+                if color != 0 {
+                    // Border entries use palettes 4-7
+                    let palette = ((entry >> 10) & 0b11) as usize;
+                    let offset = (y * SGB_SCREEN_WIDTH + x) * 4;
+                    self.output[offset..offset + 3].copy_from_slice(&from_rgb555(self.border_palettes[palette][color]));
+                    self.output[offset + 3] = 255;
+                }
+            }
+        }
+    }
+
+    // Border tiles are 4 bits per pixel in the SNES layout: two bitplanes per row
+    // for the first 16 bytes and the other two in the next 16
+    fn border_pixel(&self, entry: u16, x: usize, y: usize) -> usize {
+        let tile = (entry & 0xFF) as usize * BORDER_TILE_SIZE;
+        let x = if entry & 0x4000 != 0 { 7 - x } else { x };
+        let y = if entry & 0x8000 != 0 { 7 - y } else { y };
+        let bit = 7 - x;
+        let planes = [
+            self.border_tiles[tile + y * 2],
+            self.border_tiles[tile + y * 2 + 1],
+            self.border_tiles[tile + 16 + y * 2],
+            self.border_tiles[tile + 16 + y * 2 + 1],
+        ];
+        planes
+            .iter()
+            .enumerate()
+            .fold(0, |color, (plane, byte)| color | (((byte >> bit) & 0b1) as usize) << plane)
+    }
+}
+
+fn fill(buffer: &mut [u8], rgb: Rgb) {
+    for pixel in buffer.chunks_mut(4) {
+        pixel[..3].copy_from_slice(&rgb);
+        pixel[3] = 255;
+    }
+}
+
+// Games lay out the tiles to transfer in order across the background, 20 tiles to a row
+fn screen_tile_data(gpu: &GPU) -> [u8; TRANSFER_SIZE] {
+    let tile_map = if gpu.background_tile_map == TileMap::X9800 {
+        0x9800
+    } else {
+        0x9C00
+    } - VRAM_BEGIN;
+    let mut data = [0; TRANSFER_SIZE];
+    for (tile, bytes) in data.chunks_mut(16).enumerate() {
+        let tile_number = gpu.vram[tile_map + (tile / ATTRIBUTE_WIDTH) * 32 + tile % ATTRIBUTE_WIDTH];
+        let address = match gpu.background_and_window_data_select {
+            BackgroundAndWindowDataSelect::X8000 => tile_number as usize * 16,
+            BackgroundAndWindowDataSelect::X8800 => (0x1000 + tile_number as i8 as isize * 16) as usize,
+        };
+        bytes.copy_from_slice(&gpu.vram[address..address + 16]);
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends a command bit by bit the way games do, one reset pulse per packet
+    fn send(sgb: &mut Sgb, command: &[u8]) {
+        for packet in command.chunks(PACKET_SIZE) {
+            send_packet(sgb, packet, false);
+        }
+    }
+
+    fn send_packet(sgb: &mut Sgb, packet: &[u8], stop_bit: bool) {
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        let bits = (0..PACKET_SIZE * 8).map(|bit| packet.get(bit / 8).is_some_and(|byte| byte >> (bit % 8) & 1 != 0));
+        for bit in bits.chain(Some(stop_bit)) {
+            sgb.write_joypad(if bit { 0x10 } else { 0x20 });
+            sgb.write_joypad(0x30);
+        }
+    }
+
+    fn attribute(sgb: &Sgb, x: usize, y: usize) -> u8 {
+        sgb.attributes[y * ATTRIBUTE_WIDTH + x]
+    }
+
+    #[test]
+    fn palette_packet() {
+        let mut sgb = Sgb::new();
+        // PAL12, colour 0 and three colours for each of the palettes
+        send(&mut sgb, &[0x03 << 3 | 1, 0x00, 0x10, 0x01, 0x11, 0x02, 0x12, 0x03, 0x13, 0x04, 0x14, 0x05, 0x15, 0x06, 0x16]);
+        assert_eq!(sgb.palettes[1], [0x1000, 0x1101, 0x1202, 0x1303]);
+        assert_eq!(sgb.palettes[2], [0x1000, 0x1404, 0x1505, 0x1606]);
+        assert_eq!(sgb.palettes[0], [0x1000, DEFAULT_PALETTE[1], DEFAULT_PALETTE[2], DEFAULT_PALETTE[3]]);
+    }
+
+    #[test]
+    fn packet_without_stop_bit_is_dropped() {
+        let mut sgb = Sgb::new();
+        // PAL01 with colour 0 white
+        let packet = [1, 0xFF, 0x7F];
+        send_packet(&mut sgb, &packet, true);
+        assert_eq!(sgb.palettes, [DEFAULT_PALETTE; 4]);
+
+        // The next command still gets through
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.palettes[0][0], 0x7FFF);
+    }
+
+    #[test]
+    fn attribute_block() {
+        let mut sgb = Sgb::new();
+        // Inside in palette 1, the line around it in 2 and everything outside in 3
+        send(&mut sgb, &[0x04 << 3 | 1, 1, 0b111, 3 << 4 | 2 << 2 | 1, 2, 3, 6, 8]);
+        assert_eq!(attribute(&sgb, 4, 5), 1);
+        assert_eq!(attribute(&sgb, 2, 3), 2);
+        assert_eq!(attribute(&sgb, 6, 8), 2);
+        assert_eq!(attribute(&sgb, 7, 8), 3);
+        assert_eq!(attribute(&sgb, 0, 0), 3);
+
+        // Only the inside also colours the line, the outside stays
+        send(&mut sgb, &[0x04 << 3 | 1, 1, 0b001, 1, 10, 10, 12, 12]);
+        assert_eq!(attribute(&sgb, 10, 10), 1);
+        assert_eq!(attribute(&sgb, 11, 11), 1);
+        assert_eq!(attribute(&sgb, 13, 13), 3);
+    }
+
+    #[test]
+    fn attribute_characters() {
+        let mut sgb = Sgb::new();
+        // 48 cells from (18, 1) wrapping to the next row after column 19, the data goes on
+        // into the second packet
+        let mut command = [0; PACKET_SIZE * 2];
+        command[..6].copy_from_slice(&[0x07 << 3 | 2, 18, 1, 48, 0, 0]);
+        command[6..18].fill(0b00011011);
+        send(&mut sgb, &command);
+        assert_eq!(attribute(&sgb, 18, 1), 0);
+        assert_eq!(attribute(&sgb, 19, 1), 1);
+        assert_eq!(attribute(&sgb, 0, 2), 2);
+        assert_eq!(attribute(&sgb, 1, 2), 3);
+        assert_eq!(attribute(&sgb, 5, 4), 3);
+        assert_eq!(attribute(&sgb, 6, 4), 0);
+
+        // Down the columns instead
+        send(&mut sgb, &[0x07 << 3 | 1, 5, 17, 2, 0, 1, 0b11110000]);
+        assert_eq!(attribute(&sgb, 5, 17), 3);
+        assert_eq!(attribute(&sgb, 6, 0), 3);
+    }
+
+    #[test]
+    fn palette_set() {
+        let mut sgb = Sgb::new();
+        sgb.system_palettes[1] = [0x0001, 0x0011, 0x0021, 0x0031];
+        sgb.system_palettes[300] = [0x0300, 0x0310, 0x0320, 0x0330];
+        sgb.attribute_files[2][0] = 0b11100100;
+        sgb.mask = Mask::Freeze;
+
+        // Palettes 1, 300, 1 and 1, then attribute file 2 and cancel the mask
+        send(&mut sgb, &[0x0A << 3 | 1, 1, 0, 0x2C, 0x01, 1, 0, 1, 0, 0xC2]);
+        assert_eq!(sgb.palettes[0], [0x0001, 0x0011, 0x0021, 0x0031]);
+        assert_eq!(sgb.palettes[1], [0x0001, 0x0310, 0x0320, 0x0330]);
+        assert_eq!(&sgb.attributes[..5], &[3, 2, 1, 0, 0]);
+        assert_eq!(sgb.mask, Mask::Cancel);
+    }
+
+    #[test]
+    fn multiplayer() {
+        let mut sgb = Sgb::new();
+        assert_eq!(sgb.joypad_id(), None);
+        send(&mut sgb, &[0x11 << 3 | 1, 1]);
+        assert_eq!(sgb.player_count, 2);
+        assert_eq!(sgb.joypad_id(), Some(0xF));
+        // P15 going high selects the next joypad
+        sgb.write_joypad(0x10);
+        sgb.write_joypad(0x30);
+        assert_eq!(sgb.joypad_id(), Some(0xE));
+        sgb.write_joypad(0x10);
+        sgb.write_joypad(0x30);
+        assert_eq!(sgb.joypad_id(), Some(0xF));
+    }
+}
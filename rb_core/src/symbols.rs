@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap};
+
+// Labels from an RGBDS .sym file. Addresses are only unique together with their bank, the same
+// address in two ROM banks can be two different routines.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    labels: BTreeMap<(usize, u16), String>,
+    addresses: HashMap<String, (usize, u16)>,
+}
+
+impl Symbols {
+    pub fn from_file(path: &str) -> Result<Symbols, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        Symbols::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // One label per line with its bank and address in hex, comments start with ';'
+    //
+    //     ; File generated by rgblink
+    //     00:0150 Main
+    //     00:0158 Main.loop
+    //     02:4000 LoadLevel
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("Line {}: {}", number + 1, message);
+            let mut words = line.split_whitespace();
+            let (location, name) = match (words.next(), words.next(), words.next()) {
+                (Some(location), Some(name), None) => (location, name),
+                _ => return Err(error("expected '<bank>:<address> <label>'".to_string())),
+            };
+            let (bank, address) = parse_location(location).ok_or_else(|| error(format!("'{}' is not a bank and address", location)))?;
+            symbols.labels.entry((bank, address)).or_insert_with(|| name.to_string());
+            symbols.addresses.insert(name.to_string(), (bank, address));
+        }
+        Ok(symbols)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    // The label right at the address
+    pub fn label(&self, bank: usize, address: u16) -> Option<&str> {
+        self.labels.get(&(bank, address)).map(|label| label.as_str())
+    }
+
+    // The closest label at or before the address in the same bank and memory region, e.g. "Main+3"
+    pub fn describe(&self, bank: usize, address: u16) -> Option<String> {
+        let ((_, start), label) = self.labels.range((bank, 0)..=(bank, address)).next_back()?;
+        if region(*start) != region(address) {
+            return None;
+        }
+        match address - start {
+            0 => Some(label.clone()),
+            offset => Some(format!("{}+{}", label, offset)),
+        }
+    }
+
+    // Where a label is, as its bank and address
+    pub fn lookup(&self, label: &str) -> Option<(usize, u16)> {
+        self.addresses.get(label).copied()
+    }
+}
+
+// "02:4000" as in .sym files
+pub fn parse_location(text: &str) -> Option<(usize, u16)> {
+    let (bank, address) = text.split_once(':')?;
+    Some((usize::from_str_radix(bank, 16).ok()?, u16::from_str_radix(address, 16).ok()?))
+}
+
+// Labels don't reach across these, a ROM label says nothing about VRAM right after it
+fn region(address: u16) -> u8 {
+    match address {
+        0x0000..=0x3FFF => 0,
+        0x4000..=0x7FFF => 1,
+        0x8000..=0x9FFF => 2,
+        0xA000..=0xBFFF => 3,
+        0xC000..=0xCFFF => 4,
+        0xD000..=0xDFFF => 5,
+        0xE000..=0xFF7F => 6,
+        _ => 7,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "; File generated by rgblink
+00:0150 Main
+00:0150 Start
+00:0158 Main.loop
+00:3FF0 Tail
+01:4000 LoadLevel
+02:4000 DrawBoss ; same address, another bank
+00:C000 wBuffer
+";
+
+    #[test]
+    fn banks_and_local_labels() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.label(0, 0x0158), Some("Main.loop"));
+        assert_eq!(symbols.label(1, 0x4000), Some("LoadLevel"));
+        assert_eq!(symbols.label(2, 0x4000), Some("DrawBoss"));
+        assert_eq!(symbols.label(3, 0x4000), None);
+        // The first label at an address names it, every label can still be looked up
+        assert_eq!(symbols.label(0, 0x0150), Some("Main"));
+        assert_eq!(symbols.lookup("Start"), Some((0, 0x0150)));
+        assert_eq!(symbols.lookup("Main.loop"), Some((0, 0x0158)));
+        assert_eq!(symbols.lookup("DrawBoss"), Some((2, 0x4000)));
+        assert_eq!(symbols.lookup("Nowhere"), None);
+    }
+
+    #[test]
+    fn describe() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.describe(0, 0x0150).as_deref(), Some("Main"));
+        assert_eq!(symbols.describe(0, 0x0153).as_deref(), Some("Main+3"));
+        assert_eq!(symbols.describe(0, 0x015A).as_deref(), Some("Main.loop+2"));
+        assert_eq!(symbols.describe(2, 0x4010).as_deref(), Some("DrawBoss+16"));
+        assert_eq!(symbols.describe(0, 0x0100), None);
+        // Nothing reaches from ROM into the next region
+        assert_eq!(symbols.describe(0, 0x3FFF).as_deref(), Some("Tail+15"));
+        assert_eq!(symbols.describe(0, 0x8000), None);
+        assert_eq!(symbols.describe(0, 0xC004).as_deref(), Some("wBuffer+4"));
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(Symbols::parse("00:0150").unwrap_err(), "Line 1: expected '<bank>:<address> <label>'");
+        assert_eq!(Symbols::parse("\n0150 Main").unwrap_err(), "Line 2: '0150' is not a bank and address");
+        assert_eq!(Symbols::parse("00:10000 Main").unwrap_err(), "Line 1: '00:10000' is not a bank and address");
+        assert!(Symbols::parse("; nothing\n\n").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+// T-cycles TIMA stays 0 after it overflowed, before TMA gets loaded and the interrupt requested
+const RELOAD_DELAY: u8 = 4;
+
+// DIV, TIMA, TMA and TAC (0xFF04-0xFF07)
+pub struct Timer {
+    // DIV is the upper byte of this counter, it counts up every T-cycle
+    divider: u16,
+    pub counter: u8,
+    pub modulo: u8,
+    pub enabled: bool,
+    pub clock_select: u8,
+    reload_delay: u8,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            divider: 0,
+            counter: 0,
+            modulo: 0,
+            enabled: false,
+            clock_select: 0,
+            reload_delay: 0,
+        }
+    }
+
+    pub fn read_divider(&self) -> u8 {
+        (self.divider >> 8) as u8
+    }
+
+    pub fn set_divider(&mut self, divider: u16) {
+        self.divider = divider;
+    }
+
+    // Any write clears the whole counter, which can tick TIMA
+    pub fn reset_divider(&mut self) {
+        let before = self.input();
+        self.divider = 0;
+        if before {
+            self.increment();
+        }
+    }
+
+    // Writing TIMA while the reload is pending cancels it along with the interrupt
+    pub fn write_counter(&mut self, value: u8) {
+        self.counter = value;
+        self.reload_delay = 0;
+    }
+
+    pub fn read_control(&self) -> u8 {
+        0b11111000 | (if self.enabled { 1 } else { 0 }) << 2 | self.clock_select
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        let before = self.input();
+        self.enabled = value & 0b100 != 0;
+        self.clock_select = value & 0b11;
+        // Turning the timer off or changing the clock can pull the input low and tick TIMA
+        if before && !self.input() {
+            self.increment();
+        }
+    }
+
+    // Returns true when TIMA got reloaded after an overflow and the timer interrupt should be requested
+    pub fn step(&mut self, cycles: u32) -> bool {
+        let mut reloaded = false;
+        for _ in 0..cycles {
+            if self.reload_delay > 0 {
+                self.reload_delay -= 1;
+                if self.reload_delay == 0 {
+                    self.counter = self.modulo;
+                    reloaded = true;
+                }
+            }
+            let before = self.input();
+            self.divider = self.divider.wrapping_add(1);
+            if before && !self.input() {
+                self.increment();
+            }
+        }
+        reloaded
+    }
+
+    // TIMA counts on the falling edge of one of the divider bits
+    fn input(&self) -> bool {
+        let bit = match self.clock_select {
+            0b00 => 9, // 4096 Hz
+            0b01 => 3, // 262144 Hz
+            0b10 => 5, // 65536 Hz
+            _ => 7,    // 16384 Hz
+        };
+        self.enabled && (self.divider >> bit) & 1 == 1
+    }
+
+    fn increment(&mut self) {
+        let (counter, overflow) = self.counter.overflowing_add(1);
+        self.counter = counter;
+        if overflow {
+            self.reload_delay = RELOAD_DELAY;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Enabled at 262144 Hz, TIMA counts when bit 3 of the divider falls
+    fn enabled() -> Timer {
+        let mut timer = Timer::new();
+        timer.write_control(0b101);
+        timer
+    }
+
+    #[test]
+    fn divider() {
+        let mut timer = Timer::new();
+        timer.step(255);
+        assert_eq!(timer.read_divider(), 0);
+        timer.step(1);
+        assert_eq!(timer.read_divider(), 1);
+        timer.reset_divider();
+        assert_eq!(timer.read_divider(), 0);
+    }
+
+    #[test]
+    fn counts_on_the_falling_edge() {
+        let mut timer = enabled();
+        timer.step(15);
+        assert_eq!(timer.counter, 0);
+        timer.step(1);
+        assert_eq!(timer.counter, 1);
+        timer.step(16 * 3);
+        assert_eq!(timer.counter, 4);
+
+        // 4096 Hz
+        timer.write_control(0b100);
+        timer.set_divider(0);
+        timer.step(1023);
+        assert_eq!(timer.counter, 4);
+        timer.step(1);
+        assert_eq!(timer.counter, 5);
+    }
+
+    #[test]
+    fn resetting_the_divider_can_count() {
+        let mut timer = enabled();
+        timer.set_divider(0b1000);
+        timer.reset_divider();
+        assert_eq!(timer.counter, 1);
+
+        timer.set_divider(0b0111);
+        timer.reset_divider();
+        assert_eq!(timer.counter, 1);
+    }
+
+    #[test]
+    fn turning_off_can_count() {
+        let mut timer = enabled();
+        timer.set_divider(0b1000);
+        timer.write_control(0b001);
+        assert_eq!(timer.counter, 1);
+
+        // Bit 9 is low, so switching to 4096 Hz is a falling edge too
+        let mut timer = enabled();
+        timer.set_divider(0b1000);
+        timer.write_control(0b100);
+        assert_eq!(timer.counter, 1);
+    }
+
+    #[test]
+    fn reload_is_delayed() {
+        let mut timer = enabled();
+        timer.counter = 0xFF;
+        timer.modulo = 0x42;
+        timer.set_divider(0b1111);
+        assert!(!timer.step(1));
+        assert_eq!(timer.counter, 0);
+        assert!(!timer.step(3));
+        assert_eq!(timer.counter, 0);
+        assert!(timer.step(1));
+        assert_eq!(timer.counter, 0x42);
+    }
+
+    #[test]
+    fn reload_takes_the_latest_modulo() {
+        let mut timer = enabled();
+        timer.counter = 0xFF;
+        timer.set_divider(0b1111);
+        timer.step(1);
+        timer.modulo = 0x80;
+        assert!(timer.step(4));
+        assert_eq!(timer.counter, 0x80);
+    }
+
+    #[test]
+    fn writing_the_counter_cancels_the_reload() {
+        let mut timer = enabled();
+        timer.counter = 0xFF;
+        timer.modulo = 0x42;
+        timer.set_divider(0b1111);
+        timer.step(2);
+        timer.write_counter(0x10);
+        assert!(!timer.step(4));
+        assert_eq!(timer.counter, 0x10);
+    }
+}
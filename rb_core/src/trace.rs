@@ -0,0 +1,189 @@
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+
+use super::symbols::Symbols;
+
+// Where tracing begins, it keeps going from then on
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TraceStart {
+    Immediately,
+    // The first time this instruction is about to run
+    Pc(u16),
+    // Once this many T-cycles have passed since the CPU was created
+    Cycle(u64),
+}
+
+// Logs every instruction before it runs in the format gameboy-doctor and many other emulators use:
+//
+//     A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+//
+// so our execution can be diffed line by line against a known-good log. Only when symbols are
+// given the label of PC goes after a ';' at the end, which breaks the diff.
+pub struct Tracer {
+    pub start: TraceStart,
+    // Only instructions at these addresses are logged
+    pub range: Option<RangeInclusive<u16>>,
+    // Stops logging after this many lines
+    pub limit: Option<u64>,
+    pub symbols: Option<Symbols>,
+    sink: BufWriter<Box<dyn Write>>,
+    started: bool,
+    lines: u64,
+    // T-cycles run so far
+    pub(crate) cycles: u64,
+}
+
+impl Tracer {
+    pub fn new(sink: Box<dyn Write>) -> Tracer {
+        Tracer {
+            start: TraceStart::Immediately,
+            range: None,
+            limit: None,
+            symbols: None,
+            sink: BufWriter::with_capacity(1 << 16, sink),
+            started: false,
+            lines: 0,
+            cycles: 0,
+        }
+    }
+
+    // "-" logs to stderr
+    pub fn to_file(path: &str) -> Result<Tracer, String> {
+        if path == "-" {
+            return Ok(Tracer::new(Box::new(std::io::stderr())));
+        }
+        let file = std::fs::File::create(path).map_err(|e| format!("Could not create {}: {}", path, e))?;
+        Ok(Tracer::new(Box::new(file)))
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.limit.is_some_and(|limit| self.lines >= limit)
+    }
+
+    // Registers in the order A, F, B, C, D, E, H, L and the 4 bytes at PC, in the given bank
+    pub(crate) fn log(&mut self, registers: [u8; 8], sp: u16, pc: u16, bank: usize, memory: [u8; 4]) {
+        if !self.started {
+            self.started = match self.start {
+                TraceStart::Immediately => true,
+                TraceStart::Pc(start) => pc == start,
+                TraceStart::Cycle(start) => self.cycles >= start,
+            };
+            if !self.started {
+                return;
+            }
+        }
+        if self.is_done() || self.range.as_ref().is_some_and(|range| !range.contains(&pc)) {
+            return;
+        }
+        let [a, f, b, c, d, e, h, l] = registers;
+        let label = match self.symbols.as_ref().and_then(|symbols| symbols.describe(bank, pc)) {
+            Some(label) => format!(" ; {}", label),
+            None => String::new(),
+        };
+        let result = writeln!(
+            self.sink,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}{}",
+            a, f, b, c, d, e, h, l, sp, pc, memory[0], memory[1], memory[2], memory[3], label
+        );
+        self.lines += 1;
+        // A trace that can't be written is useless, but no reason to stop the game
+        if result.is_err() || self.is_done() {
+            self.limit = Some(self.lines);
+            let _ = self.sink.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.sink.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::cpu::CPU;
+
+    // Keeps what the tracer wrote where the test can still read it
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buffer)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone()).unwrap().lines().map(str::to_string).collect()
+        }
+    }
+
+    fn tracer() -> (Tracer, Output) {
+        let output = Output::default();
+        (Tracer::new(Box::new(output.clone())), output)
+    }
+
+    fn log(tracer: &mut Tracer, pc: u16) {
+        tracer.log([0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D], 0xFFFE, pc, 1, [0x00, 0xC3, 0x13, 0x02]);
+    }
+
+    // The first lines of every gameboy-doctor log
+    #[test]
+    fn gameboy_doctor_format() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x13, 0x02]);
+        rom[0x14D] = 0x01;
+        let mut cpu = CPU::new(None, rom);
+        let (tracer, output) = tracer();
+        cpu.tracer = Some(tracer);
+        cpu.step();
+        cpu.step();
+        cpu.tracer.as_mut().unwrap().flush();
+        assert_eq!(
+            output.lines(),
+            [
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02",
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,13,02,00",
+            ]
+        );
+    }
+
+    #[test]
+    fn start_range_and_limit() {
+        let (mut tracer, output) = tracer();
+        tracer.start = TraceStart::Pc(0x4002);
+        tracer.range = Some(0x4000..=0x4003);
+        tracer.limit = Some(2);
+        for pc in [0x4000, 0x4001, 0x4005, 0x4002, 0x4005, 0x4000, 0x4003, 0x4001] {
+            log(&mut tracer, pc);
+        }
+        assert!(tracer.is_done());
+        tracer.flush();
+        let pcs = output.lines().iter().map(|line| line.split(' ').nth(9).unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(pcs, ["PC:4002", "PC:4000"]);
+    }
+
+    #[test]
+    fn labels_go_at_the_end() {
+        let (mut tracer, output) = tracer();
+        tracer.symbols = Some(Symbols::parse("00:4000 Other\n01:4000 Main").unwrap());
+        log(&mut tracer, 0x4003);
+        log(&mut tracer, 0x0100);
+        tracer.flush();
+        assert_eq!(
+            output.lines(),
+            [
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:4003 PCMEM:00,C3,13,02 ; Main+3",
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02",
+            ]
+        );
+    }
+}
@@ -0,0 +1,373 @@
+use super::postprocess::*;
+
+type Pixel = [u8; 4];
+
+// Two colours closer than this in YUV count as the same for HQx
+const HQX_Y_THRESHOLD: i32 = 48;
+const HQX_U_THRESHOLD: i32 = 7;
+const HQX_V_THRESHOLD: i32 = 6;
+
+// How much closer the corner diagonal has to be for xBRZ to draw the edge at full strength
+const XBRZ_DOMINANT_DIRECTION: f32 = 3.6;
+
+// Pixel art upscalers that run on the CPU over an RGBA frame
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Upscaler {
+    Nearest(usize),
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    Hq3x,
+    Xbrz(usize),
+}
+
+impl Upscaler {
+    pub const NAMES: [&'static str; 10] = [
+        "nearest2", "nearest3", "nearest4", "scale2x", "scale3x", "hq2x", "hq3x", "xbrz2", "xbrz3", "xbrz4",
+    ];
+
+    pub fn from_name(name: &str) -> Option<Upscaler> {
+        match name.to_ascii_lowercase().as_str() {
+            "nearest2" => Some(Upscaler::Nearest(2)),
+            "nearest3" => Some(Upscaler::Nearest(3)),
+            "nearest4" => Some(Upscaler::Nearest(4)),
+            "scale2x" | "epx" => Some(Upscaler::Scale2x),
+            "scale3x" => Some(Upscaler::Scale3x),
+            "hq2x" => Some(Upscaler::Hq2x),
+            "hq3x" => Some(Upscaler::Hq3x),
+            "xbrz2" => Some(Upscaler::Xbrz(2)),
+            "xbrz3" => Some(Upscaler::Xbrz(3)),
+            "xbrz4" => Some(Upscaler::Xbrz(4)),
+            _ => None,
+        }
+    }
+
+    pub fn factor(&self) -> usize {
+        match *self {
+            Upscaler::Nearest(factor) | Upscaler::Xbrz(factor) => factor,
+            Upscaler::Scale2x | Upscaler::Hq2x => 2,
+            Upscaler::Scale3x | Upscaler::Hq3x => 3,
+        }
+    }
+
+    pub fn upscale(&self, frame: &Frame) -> Frame {
+        let factor = self.factor();
+        let mut output = Frame::new(frame.width * factor, frame.height * factor);
+        let block: fn(&Neighbours, usize, &mut [Pixel]) = match self {
+            Upscaler::Nearest(_) => nearest,
+            Upscaler::Scale2x | Upscaler::Scale3x => scale_x,
+            Upscaler::Hq2x | Upscaler::Hq3x => hq_x,
+            Upscaler::Xbrz(_) => xbrz,
+        };
+
+        let mut pixels = vec![[0; 4]; factor * factor];
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                block(&Neighbours::around(frame, x, y), factor, &mut pixels);
+                for (index, pixel) in pixels.iter().enumerate() {
+                    let output_x = x * factor + index % factor;
+                    let output_y = y * factor + index / factor;
+                    let offset = (output_y * output.width + output_x) * 4;
+                    output.pixels[offset..offset + 4].copy_from_slice(pixel);
+                }
+            }
+        }
+        output
+    }
+}
+
+impl PostProcess for Upscaler {
+    fn process(&mut self, frame: Frame) -> Frame {
+        self.upscale(&frame)
+    }
+}
+
+// The 3x3 block around a pixel, edges repeat the outermost pixels
+//     a b c
+//     d e f
+//     g h i
+struct Neighbours {
+    pixels: [Pixel; 9],
+}
+
+impl Neighbours {
+    fn around(frame: &Frame, x: usize, y: usize) -> Neighbours {
+        let mut pixels = [[0; 4]; 9];
+        for (index, pixel) in pixels.iter_mut().enumerate() {
+            let pixel_x = (x + index % 3).saturating_sub(1).min(frame.width - 1);
+            let pixel_y = (y + index / 3).saturating_sub(1).min(frame.height - 1);
+            let offset = (pixel_y * frame.width + pixel_x) * 4;
+            pixel.copy_from_slice(&frame.pixels[offset..offset + 4]);
+        }
+        Neighbours { pixels }
+    }
+
+    fn get(&self, x: usize, y: usize) -> Pixel {
+        self.pixels[y * 3 + x]
+    }
+
+    // Turns the block so the corner at (2, 2) becomes the one asked for,
+    // that way every corner can be worked out with the bottom right rules
+    fn rotated(&self, corner: usize) -> Neighbours {
+        let mut pixels = self.pixels;
+        for _ in 0..corner {
+            let old = pixels;
+            for y in 0..3 {
+                for x in 0..3 {
+                    pixels[y * 3 + x] = old[(2 - x) * 3 + y];
+                }
+            }
+        }
+        Neighbours { pixels }
+    }
+}
+
+// Writes pixel (x, y) of a block as if the block had been turned by `corner` quarter turns
+fn rotated_index(x: usize, y: usize, factor: usize, corner: usize) -> usize {
+    let (mut x, mut y) = (x, y);
+    for _ in 0..corner {
+        let old_x = x;
+        x = y;
+        y = factor - 1 - old_x;
+    }
+    y * factor + x
+}
+
+fn nearest(neighbours: &Neighbours, _factor: usize, output: &mut [Pixel]) {
+    output.fill(neighbours.get(1, 1));
+}
+
+// Scale2x/Scale3x (EPX), each corner takes the colour of its neighbours when they agree
+fn scale_x(n: &Neighbours, factor: usize, output: &mut [Pixel]) {
+    let [a, b, c, d, e, f, g, h, i] = n.pixels;
+    output.fill(e);
+    if b == h || d == f {
+        return;
+    }
+    if factor == 2 {
+        output[0] = if d == b { d } else { e };
+        output[1] = if b == f { f } else { e };
+        output[2] = if d == h { d } else { e };
+        output[3] = if h == f { f } else { e };
+    } else {
+        output[0] = if d == b { d } else { e };
+        output[1] = if (d == b && e != c) || (b == f && e != a) { b } else { e };
+        output[2] = if b == f { f } else { e };
+        output[3] = if (d == b && e != g) || (d == h && e != a) { d } else { e };
+        output[5] = if (b == f && e != i) || (h == f && e != c) { f } else { e };
+        output[6] = if d == h { d } else { e };
+        output[7] = if (d == h && e != i) || (h == f && e != g) { h } else { e };
+        output[8] = if h == f { f } else { e };
+    }
+}
+
+// HQ2x/HQ3x compare neighbours in YUV and interpolate the corners towards the ones that
+// differ from the centre. This works out each corner from its three neighbours instead
+// of the full 256 entry pattern table, which keeps the same look on pixel art.
+fn hq_x(neighbours: &Neighbours, factor: usize, output: &mut [Pixel]) {
+    let e = neighbours.get(1, 1);
+    output.fill(e);
+
+    for corner in 0..4 {
+        let n = neighbours.rotated(corner);
+        let (f, h, i) = (n.get(2, 1), n.get(1, 2), n.get(2, 2));
+        let differs_f = yuv_differs(e, f);
+        let differs_h = yuv_differs(e, h);
+        let differs_i = yuv_differs(e, i);
+
+        let color = if differs_f && differs_h && !yuv_differs(f, h) {
+            // An edge runs across the corner
+            if differs_i { interpolate(&[(e, 2), (f, 1), (h, 1)]) } else { interpolate(&[(e, 6), (f, 1), (h, 1)]) }
+        } else if differs_i && !differs_f && !differs_h {
+            interpolate(&[(e, 3), (i, 1)])
+        } else if differs_f && differs_h {
+            interpolate(&[(e, 2), (f, 1), (h, 1)])
+        } else {
+            continue;
+        };
+
+        let last = factor - 1;
+        output[rotated_index(last, last, factor, corner)] = color;
+        if factor == 3 && differs_f && differs_h && !yuv_differs(f, h) {
+            // Soften the middle of the edges next to the corner on HQ3x
+            output[rotated_index(last, 1, factor, corner)] = interpolate(&[(e, 7), (f, 1)]);
+            output[rotated_index(1, last, factor, corner)] = interpolate(&[(e, 7), (h, 1)]);
+        }
+    }
+}
+
+// xBRZ: decide for every corner whether an edge runs along the opposite diagonal, then
+// cut the corner with an anti-aliased line. The shallow and steep line passes of the full
+// algorithm are left out, corners alone cover most of what Game Boy graphics need.
+fn xbrz(neighbours: &Neighbours, factor: usize, output: &mut [Pixel]) {
+    let e = neighbours.get(1, 1);
+    output.fill(e);
+
+    for corner in 0..4 {
+        let n = neighbours.rotated(corner);
+        let (b, d, f, h, i) = (n.get(1, 0), n.get(0, 1), n.get(2, 1), n.get(1, 2), n.get(2, 2));
+        if f == h && e == i {
+            continue;
+        }
+
+        // Weight of the diagonal through the centre against the one across the corner
+        let through = color_distance(d, h) + color_distance(b, f) + 4.0 * color_distance(e, i);
+        let across = color_distance(e, f) + color_distance(e, h) + 4.0 * color_distance(f, h);
+        if across >= through {
+            continue;
+        }
+        let strength = if through > XBRZ_DOMINANT_DIRECTION * across { 1.0 } else { 0.5 };
+
+        let blend = if color_distance(e, f) <= color_distance(e, h) { f } else { h };
+        for y in 0..factor {
+            for x in 0..factor {
+                // Distance of the sub pixel centre past the line from (factor, factor / 2) to (factor / 2, factor)
+                let distance = (x as f32 + 0.5) + (y as f32 + 0.5) - factor as f32 * 1.5;
+                let weight = (distance + 0.5).clamp(0.0, 1.0) * strength;
+                if weight > 0.0 {
+                    let index = rotated_index(x, y, factor, corner);
+                    output[index] = mix(output[index], blend, weight);
+                }
+            }
+        }
+    }
+}
+
+fn yuv(pixel: Pixel) -> (i32, i32, i32) {
+    let (r, g, b) = (pixel[0] as i32, pixel[1] as i32, pixel[2] as i32);
+    let y = (299 * r + 587 * g + 114 * b) / 1000;
+    let u = (-169 * r - 331 * g + 500 * b) / 1000 + 128;
+    let v = (500 * r - 419 * g - 81 * b) / 1000 + 128;
+    (y, u, v)
+}
+
+fn yuv_differs(first: Pixel, second: Pixel) -> bool {
+    let (y1, u1, v1) = yuv(first);
+    let (y2, u2, v2) = yuv(second);
+    (y1 - y2).abs() > HQX_Y_THRESHOLD || (u1 - u2).abs() > HQX_U_THRESHOLD || (v1 - v2).abs() > HQX_V_THRESHOLD
+}
+
+// The YCbCr distance xBRZ uses, luma counts twice as much as colour
+fn color_distance(first: Pixel, second: Pixel) -> f32 {
+    let r = first[0] as f32 - second[0] as f32;
+    let g = first[1] as f32 - second[1] as f32;
+    let b = first[2] as f32 - second[2] as f32;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let cb = 0.5 / (1.0 - 0.0722) * (b - y);
+    let cr = 0.5 / (1.0 - 0.2126) * (r - y);
+    ((2.0 * y).powi(2) + cb.powi(2) + cr.powi(2)).sqrt()
+}
+
+fn interpolate(colors: &[(Pixel, u32)]) -> Pixel {
+    let total: u32 = colors.iter().map(|(_, weight)| weight).sum();
+    let mut result = [0; 4];
+    for (channel, value) in result.iter_mut().enumerate() {
+        let sum: u32 = colors.iter().map(|(color, weight)| color[channel] as u32 * weight).sum();
+        *value = (sum / total) as u8;
+    }
+    result
+}
+
+fn mix(first: Pixel, second: Pixel, weight: f32) -> Pixel {
+    let mut result = [0; 4];
+    for (channel, value) in result.iter_mut().enumerate() {
+        *value = (first[channel] as f32 * (1.0 - weight) + second[channel] as f32 * weight).round() as u8;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: Pixel = [0, 0, 0, 0xFF];
+    const WHITE: Pixel = [0xFF, 0xFF, 0xFF, 0xFF];
+
+    // White with black above and to the left of the bottom right pixel
+    //     B B
+    //     B W
+    fn corner() -> Frame {
+        let mut frame = Frame::new(2, 2);
+        for (index, pixel) in frame.pixels.chunks_mut(4).enumerate() {
+            pixel.copy_from_slice(if index == 3 { &WHITE } else { &BLACK });
+        }
+        frame
+    }
+
+    // The block the bottom right pixel turned into
+    fn block(upscaler: Upscaler) -> Vec<Pixel> {
+        let output = upscaler.upscale(&corner());
+        let factor = upscaler.factor();
+        let mut pixels = Vec::new();
+        for y in factor..factor * 2 {
+            for x in factor..factor * 2 {
+                let offset = (y * output.width + x) * 4;
+                pixels.push(output.pixels[offset..offset + 4].try_into().unwrap());
+            }
+        }
+        pixels
+    }
+
+    fn is_grey(pixel: Pixel) -> bool {
+        pixel[0] > 0 && pixel[0] < 0xFF && pixel[0] == pixel[1] && pixel[1] == pixel[2]
+    }
+
+    #[test]
+    fn names() {
+        for name in Upscaler::NAMES {
+            let upscaler = Upscaler::from_name(name).unwrap();
+            assert!(name.contains(&upscaler.factor().to_string()), "{}", name);
+        }
+        assert_eq!(Upscaler::from_name("EPX"), Some(Upscaler::Scale2x));
+        assert_eq!(Upscaler::from_name("hq4x"), None);
+    }
+
+    #[test]
+    fn flat_colour_stays_flat() {
+        let mut frame = Frame::new(3, 2);
+        for pixel in frame.pixels.chunks_mut(4) {
+            pixel.copy_from_slice(&[0x12, 0x34, 0x56, 0xFF]);
+        }
+        for name in Upscaler::NAMES {
+            let upscaler = Upscaler::from_name(name).unwrap();
+            let output = upscaler.upscale(&frame);
+            assert_eq!((output.width, output.height), (3 * upscaler.factor(), 2 * upscaler.factor()), "{}", name);
+            assert!(output.pixels.chunks(4).all(|pixel| pixel == [0x12, 0x34, 0x56, 0xFF]), "{}", name);
+        }
+    }
+
+    #[test]
+    fn nearest() {
+        assert_eq!(block(Upscaler::Nearest(3)), vec![WHITE; 9]);
+    }
+
+    #[test]
+    fn scale_x_fills_the_corner() {
+        assert_eq!(block(Upscaler::Scale2x), vec![BLACK, WHITE, WHITE, WHITE]);
+        assert_eq!(block(Upscaler::Scale3x), vec![BLACK, BLACK, WHITE, BLACK, WHITE, WHITE, WHITE, WHITE, WHITE]);
+    }
+
+    #[test]
+    fn hq_x_blends_the_corner() {
+        let pixels = block(Upscaler::Hq2x);
+        assert!(is_grey(pixels[0]), "{:?}", pixels[0]);
+        assert_eq!(&pixels[1..], &[WHITE; 3]);
+
+        let pixels = block(Upscaler::Hq3x);
+        assert!(is_grey(pixels[0]) && is_grey(pixels[1]) && is_grey(pixels[3]), "{:?}", pixels);
+        assert_eq!(&pixels[4..], &[WHITE; 5]);
+    }
+
+    #[test]
+    fn xbrz_cuts_the_corner() {
+        let pixels = block(Upscaler::Xbrz(4));
+        assert!(is_grey(pixels[0]) && pixels[0][0] < pixels[1][0], "{:?}", pixels);
+        assert_eq!(pixels[15], WHITE);
+        // Symmetric along the diagonal
+        for y in 0..4 {
+            for x in 0..4 {
+                assert_eq!(pixels[y * 4 + x], pixels[x * 4 + y]);
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// Talks to the GDB stub over loopback like GDB would, with a small program in a made up ROM:
+//
+//     0100  ld a, $01        0200  inc a              0210  ld [$C000], a
+//     0102  call $0200       0201  call $0210         0213  ret
+//     0105  ld b, a          0204  ret
+//     0106  jr $0106
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use rb_core::{GdbStub, CPU};
+
+#[test]
+fn gdb_session() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = thread::spawn(move || session(TcpStream::connect(address).unwrap()));
+
+    let (stream, _) = listener.accept().unwrap();
+    let mut stub = GdbStub::new(stream);
+    let mut cpu = CPU::new(None, rom());
+    while !stub.quit {
+        cpu.run_frame_with(|cpu| stub.step(cpu));
+    }
+    client.join().unwrap();
+}
+
+fn rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x108].copy_from_slice(&[0x3E, 0x01, 0xCD, 0x00, 0x02, 0x47, 0x18, 0xFE]);
+    rom[0x200..0x205].copy_from_slice(&[0x3C, 0xCD, 0x10, 0x02, 0xC9]);
+    rom[0x210..0x214].copy_from_slice(&[0xEA, 0x00, 0xC0, 0xC9]);
+    rom
+}
+
+fn session(mut gdb: TcpStream) {
+    assert!(request(&mut gdb, "qSupported:swbreak+;xmlRegisters=i386").contains("qXfer:features:read+"));
+    let target = request(&mut gdb, "qXfer:features:read:target.xml:0,fff");
+    assert!(target.starts_with('l') && target.contains(r#"<reg name="pc" bitsize="16" type="code_ptr"/>"#));
+    assert_eq!(request(&mut gdb, "?"), "S05");
+
+    // AF BC DE HL SP PC as the boot ROM leaves them, little endian
+    assert_eq!(request(&mut gdb, "g"), "8001".to_string() + "1300" + "d800" + "4d01" + "feff" + "0001");
+    assert_eq!(request(&mut gdb, "P1=3412"), "OK");
+    assert_eq!(request(&mut gdb, "p1"), "3412");
+
+    assert_eq!(request(&mut gdb, "Z0,200,1"), "OK");
+    assert_eq!(request(&mut gdb, "c"), "T05swbreak:;");
+    assert_eq!(request(&mut gdb, "p5"), "0002");
+    assert_eq!(request(&mut gdb, "z0,200,1"), "OK");
+
+    assert_eq!(request(&mut gdb, "s"), "S05");
+    assert_eq!(request(&mut gdb, "p5"), "0102");
+
+    assert_eq!(request(&mut gdb, "Z2,c000,1"), "OK");
+    assert_eq!(request(&mut gdb, "c"), "T05watch:c000;");
+    assert_eq!(request(&mut gdb, "p5"), "1302");
+    assert_eq!(request(&mut gdb, "z2,c000,1"), "OK");
+
+    assert_eq!(request(&mut gdb, "Mc001,2:abcd"), "OK");
+    assert_eq!(request(&mut gdb, "mc000,3"), "02abcd");
+
+    // Bank 3 of WRAM while bank 1 is switched in, ROM bank 2 isn't there without an MBC
+    assert_eq!(request(&mut gdb, "M3d000,1:55"), "OK");
+    assert_eq!(request(&mut gdb, "m3d000,1"), "55");
+    assert_eq!(request(&mut gdb, "md000,1"), "00");
+    assert_eq!(request(&mut gdb, "m24000,1"), "E01");
+
+    // The whole I/O page reads without crashing, unknown registers as FF
+    let io = request(&mut gdb, "mff00,80");
+    assert_eq!(io.len(), 0x100);
+    assert_eq!(&io[0x03 * 2..0x04 * 2], "ff");
+    assert_eq!(request(&mut gdb, "M0100,1:00"), "E01");
+    assert_eq!(request(&mut gdb, "Mff03,1:00"), "E01");
+    assert_eq!(request(&mut gdb, "mffffffff,2"), "E01");
+
+    // Ctrl-C stops the endless loop at the end
+    send(&mut gdb, "c");
+    thread::sleep(Duration::from_millis(50));
+    gdb.write_all(&[0x03]).unwrap();
+    assert_eq!(reply(&mut gdb), "S02");
+    assert_eq!(request(&mut gdb, "p5"), "0601");
+    assert_eq!(request(&mut gdb, "p1"), "3402");
+
+    send(&mut gdb, "k");
+}
+
+fn request(gdb: &mut TcpStream, packet: &str) -> String {
+    send(gdb, packet);
+    reply(gdb)
+}
+
+fn send(gdb: &mut TcpStream, packet: &str) {
+    let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    gdb.write_all(format!("${}#{:02x}", packet, checksum).as_bytes()).unwrap();
+    assert_eq!(read(gdb), b'+', "{} wasn't acknowledged", packet);
+}
+
+fn reply(gdb: &mut TcpStream) -> String {
+    while read(gdb) != b'$' {}
+    let mut data = Vec::new();
+    let mut sum = 0u8;
+    loop {
+        let byte = read(gdb);
+        if byte == b'#' {
+            break;
+        }
+        sum = sum.wrapping_add(byte);
+        if byte == b'}' {
+            let escaped = read(gdb);
+            sum = sum.wrapping_add(escaped);
+            data.push(escaped ^ 0x20);
+        } else {
+            data.push(byte);
+        }
+    }
+    let checksum = [read(gdb), read(gdb)];
+    assert_eq!(u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(), sum);
+    gdb.write_all(b"+").unwrap();
+    String::from_utf8(data).unwrap()
+}
+
+fn read(gdb: &mut TcpStream) -> u8 {
+    let mut byte = [0];
+    gdb.read_exact(&mut byte).unwrap();
+    byte[0]
+}
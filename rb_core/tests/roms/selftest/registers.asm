@@ -0,0 +1,38 @@
+; Reports in registers like mooneye's test ROMs: B, C, D, E, H and L hold 3, 5, 8, 13,
+; 21 and 34 at LD B,B when it passed and $42 each when it failed.
+
+INCLUDE "header.inc"
+
+SECTION "Main", ROM0[$150]
+Start:
+    ; The timer at 262144 Hz has to count while the loop runs
+    ld a, $05
+    ldh [$FF07], a
+    xor a
+    ldh [$FF05], a
+    ld b, 20
+Delay:
+    dec b
+    jr nz, Delay
+    ldh a, [$FF05]
+    and a
+    jr z, Failed
+    ld b, 3
+    ld c, 5
+    ld d, 8
+    ld e, 13
+    ld h, 21
+    ld l, 34
+    ld b, b
+Passed:
+    jr Passed
+Failed:
+    ld b, $42
+    ld c, b
+    ld d, b
+    ld e, b
+    ld h, b
+    ld l, b
+    ld b, b
+Stop:
+    jr Stop
@@ -0,0 +1,41 @@
+; Reports through the serial port like Blargg's test ROMs, the output ends in "Passed"
+; or "Failed".
+
+INCLUDE "header.inc"
+
+SECTION "Main", ROM0[$150]
+Start:
+    ld sp, $DFFF
+    ; DAA turns the binary sum of BCD 19 and 28 back into BCD 47
+    ld a, $19
+    add a, $28
+    daa
+    cp $47
+    jr nz, Failed
+    ; ADD SP carries out of the low byte
+    add sp, -1
+    jr nc, Failed
+    inc sp
+    ld hl, PassedText
+    jr Print
+Failed:
+    ld hl, FailedText
+Print:
+    ld a, [hl+]
+    and a
+    jr z, Done
+    ldh [$FF01], a
+    ld a, $81
+    ldh [$FF02], a
+Wait:
+    ldh a, [$FF02]
+    bit 7, a
+    jr nz, Wait
+    jr Print
+Done:
+    jr Done
+
+PassedText:
+    db "serial\n\nPassed\n", 0
+FailedText:
+    db "serial\n\nFailed\n", 0
@@ -0,0 +1,63 @@
+; Draws a checkerboard of checkered tiles for the screenshot tests, holding A inverts
+; the palette.
+
+INCLUDE "header.inc"
+
+SECTION "Main", ROM0[$150]
+Start:
+    ld sp, $DFFF
+WaitVBlank:
+    ldh a, [$FF44]
+    cp 144
+    jr c, WaitVBlank
+    xor a
+    ldh [$FF40], a
+
+    ; Tile 1 has its four quarters in colours 1 and 2
+    ld hl, $8010
+    ld c, 4
+TopHalf:
+    ld a, $F0
+    ld [hl+], a
+    ld a, $0F
+    ld [hl+], a
+    dec c
+    jr nz, TopHalf
+    ld c, 4
+BottomHalf:
+    ld a, $0F
+    ld [hl+], a
+    ld a, $F0
+    ld [hl+], a
+    dec c
+    jr nz, BottomHalf
+
+    ; Tiles 0 and 1 alternate along the rows and columns of the map
+    ld hl, $9800
+Map:
+    ld a, l
+    swap a
+    rrca
+    xor l
+    and 1
+    ld [hl+], a
+    ld a, h
+    cp $9C
+    jr nz, Map
+
+    ld a, $E4
+    ldh [$FF47], a
+    ld a, $91
+    ldh [$FF40], a
+
+Main:
+    ld a, $10
+    ldh [$FF00], a
+    ldh a, [$FF00]
+    and 1
+    ld a, $E4
+    jr nz, SetPalette
+    ld a, $1B
+SetPalette:
+    ldh [$FF47], a
+    jr Main
@@ -0,0 +1,227 @@
+// Catches rendering regressions by running ROMs for a number of frames and comparing the
+// screen against reference PNGs. The tests are listed in tests/screenshots/screenshots.txt,
+// the ROMs are looked up in TEST_ROMS and then rb_core/tests/roms. A missing ROM is ignored,
+// unless TEST_ROMS is set, then it fails. The selftest ROMs are part of the repository.
+//
+//     cargo test -p rb_core --test screenshots                  compare against the references
+//     cargo test -p rb_core --test screenshots -- --bless       write new references instead
+//     cargo test -p rb_core --test screenshots -- dmg-acid2     only run tests with that in their name
+//
+// When a screenshot differs, it and an image showing the differing pixels in red are written to
+// target/tmp/screenshots.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use rb_core::{Button, Frame, CPU};
+
+const SCREEN_WIDTH: usize = CPU::getRESW();
+const SCREEN_HEIGHT: usize = CPU::getRESH();
+
+struct Screenshot {
+    name: String,
+    rom: String,
+    frames: u32,
+    // Frame number, button and whether it is pressed
+    input: Vec<(u32, Button, bool)>,
+}
+
+fn main() {
+    let mut bless = false;
+    let mut filters = Vec::new();
+    for argument in std::env::args().skip(1) {
+        match argument.as_str() {
+            "--bless" => bless = true,
+            // Ignore whatever cargo passes on to every test binary
+            _ if argument.starts_with('-') => {}
+            _ => filters.push(argument),
+        }
+    }
+
+    let directory = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/screenshots");
+    let list = directory.join("screenshots.txt");
+    let text = std::fs::read_to_string(&list).unwrap_or_else(|e| panic!("Could not read {}: {}", list.display(), e));
+    let screenshots = parse(&text).unwrap_or_else(|e| panic!("{}: {}", list.display(), e));
+    let test_roms = std::env::var("TEST_ROMS").ok().map(PathBuf::from);
+    let roms = test_roms
+        .iter()
+        .cloned()
+        .chain(Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roms")))
+        .collect::<Vec<_>>();
+    let output = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("screenshots");
+
+    let mut failed = 0;
+    let mut ran = 0;
+    let mut ignored = 0;
+    for screenshot in &screenshots {
+        if !filters.is_empty() && !filters.iter().any(|filter| screenshot.name.contains(filter.as_str())) {
+            continue;
+        }
+        let buffer = match roms.iter().find_map(|directory| std::fs::read(directory.join(&screenshot.rom)).ok()) {
+            Some(buffer) => buffer,
+            None if test_roms.is_some() => {
+                eprintln!("{}: FAILED {} not found in TEST_ROMS", screenshot.name, screenshot.rom);
+                ran += 1;
+                failed += 1;
+                continue;
+            }
+            None => {
+                eprintln!("{}: ignored, {} not found", screenshot.name, screenshot.rom);
+                ignored += 1;
+                continue;
+            }
+        };
+        ran += 1;
+
+        let frame = match run(screenshot, buffer) {
+            Ok(frame) => frame,
+            Err(message) => {
+                eprintln!("{}: CRASHED {}", screenshot.name, message);
+                failed += 1;
+                continue;
+            }
+        };
+        let reference = directory.join(format!("{}.png", screenshot.name));
+        if bless {
+            save(&frame, &reference);
+            eprintln!("{}: blessed", screenshot.name);
+            continue;
+        }
+        match compare(&frame, &reference) {
+            Ok(()) => eprintln!("{}: passed", screenshot.name),
+            Err((message, diff)) => {
+                std::fs::create_dir_all(&output).unwrap();
+                let actual = output.join(format!("{}.png", screenshot.name));
+                save(&frame, &actual);
+                let mut written = actual.display().to_string();
+                if let Some(diff) = diff {
+                    let path = output.join(format!("{}-diff.png", screenshot.name));
+                    save(&diff, &path);
+                    written = format!("{} and {}", written, path.display());
+                }
+                eprintln!("{}: FAILED {}, wrote {}", screenshot.name, message, written);
+                failed += 1;
+            }
+        }
+    }
+
+    eprintln!("{} of {} screenshots matched, {} ignored", ran - failed, ran, ignored);
+    if failed > 0 || ran == 0 {
+        std::process::exit(1);
+    }
+}
+
+fn parse(text: &str) -> Result<Vec<Screenshot>, String> {
+    let mut screenshots = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: String| format!("Line {}: {}", number + 1, message);
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        if words.len() < 3 {
+            return Err(error("expected '<name> <rom> <frames> [<frame>:+<button>|-<button> ...]'".to_string()));
+        }
+        let frames = words[2]
+            .parse()
+            .map_err(|_| error(format!("'{}' is not a number of frames", words[2])))?;
+        let mut input = Vec::new();
+        for event in &words[3..] {
+            let (frame, action) = event
+                .split_once(':')
+                .ok_or_else(|| error(format!("expected '<frame>:+<button>' or '<frame>:-<button>', not '{}'", event)))?;
+            let frame = frame.parse().map_err(|_| error(format!("'{}' is not a frame number", frame)))?;
+            let (pressed, name) = match (action.strip_prefix('+'), action.strip_prefix('-')) {
+                (Some(name), _) => (true, name),
+                (_, Some(name)) => (false, name),
+                _ => return Err(error(format!("'{}' should start with + or -", action))),
+            };
+            input.push((frame, parse_button(name).map_err(error)?, pressed));
+        }
+        screenshots.push(Screenshot {
+            name: words[0].to_string(),
+            rom: words[1].to_string(),
+            frames,
+            input,
+        });
+    }
+    Ok(screenshots)
+}
+
+fn parse_button(name: &str) -> Result<Button, String> {
+    match name.to_ascii_lowercase().as_str() {
+        "right" => Ok(Button::Right),
+        "left" => Ok(Button::Left),
+        "up" => Ok(Button::Up),
+        "down" => Ok(Button::Down),
+        "a" => Ok(Button::A),
+        "b" => Ok(Button::B),
+        "select" => Ok(Button::Select),
+        "start" => Ok(Button::Start),
+        _ => Err(format!("unknown button '{}'", name)),
+    }
+}
+
+// Runs the ROM and returns the LCD as it was after the last frame
+fn run(screenshot: &Screenshot, buffer: Vec<u8>) -> Result<Frame, String> {
+    // The emulator panics on hardware it doesn't have, like memory bank controllers
+    catch_unwind(AssertUnwindSafe(|| {
+        let mut cpu = CPU::new(None, buffer);
+        for frame in 0..screenshot.frames {
+            for &(_, button, pressed) in screenshot.input.iter().filter(|(at, _, _)| *at == frame) {
+                if pressed {
+                    cpu.press(button);
+                } else {
+                    cpu.release(button);
+                }
+            }
+            cpu.run_frame();
+        }
+        Frame::from_rgba(SCREEN_WIDTH, SCREEN_HEIGHT, &cpu.bus.gpu.canvas_buffer)
+    }))
+    .map_err(|panic| {
+        panic
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|message| message.to_string()))
+            .unwrap_or_default()
+    })
+}
+
+// On a mismatch, returns what differs and, when the sizes match, an image of the reference
+// dimmed to grey with the differing pixels in red
+fn compare(frame: &Frame, reference: &Path) -> Result<(), (String, Option<Frame>)> {
+    let reference = Frame::load_png(&reference.display().to_string())
+        .map_err(|e| (format!("{}, run with --bless to create it", e), None))?;
+    if (frame.width, frame.height) != (reference.width, reference.height) {
+        let message = format!(
+            "screen is {}x{} but the reference is {}x{}",
+            frame.width, frame.height, reference.width, reference.height
+        );
+        return Err((message, None));
+    }
+
+    let mut diff = Frame::new(frame.width, frame.height);
+    let mut differing = 0;
+    let pixels = frame.pixels.chunks(4).zip(reference.pixels.chunks(4));
+    for ((actual, wanted), output) in pixels.zip(diff.pixels.chunks_mut(4)) {
+        // The alpha channel isn't part of what's on screen
+        if actual[..3] != wanted[..3] {
+            differing += 1;
+            output.copy_from_slice(&[0xFF, 0, 0, 0xFF]);
+        } else {
+            let grey = ((wanted[0] as u32 + wanted[1] as u32 + wanted[2] as u32) / 9) as u8;
+            output.copy_from_slice(&[grey, grey, grey, 0xFF]);
+        }
+    }
+    if differing == 0 {
+        Ok(())
+    } else {
+        Err((format!("{} of {} pixels differ", differing, frame.width * frame.height), Some(diff)))
+    }
+}
+
+fn save(frame: &Frame, path: &Path) {
+    frame.save_png(&path.display().to_string()).unwrap();
+}
@@ -0,0 +1,216 @@
+// Runs Blargg's and mooneye's test ROMs. They are not part of the repository, put them in
+// rb_core/tests/roms (or point TEST_ROMS somewhere else) like this:
+//
+//     blargg/cpu_instrs/cpu_instrs.gb, blargg/cpu_instrs/individual/*.gb
+//     blargg/instr_timing/instr_timing.gb
+//     blargg/mem_timing/mem_timing.gb, blargg/mem_timing/individual/*.gb
+//     blargg/halt_bug.gb
+//     mooneye/acceptance/**/*.gb
+//
+// Both suites are ignored by default, run them with `cargo test -- --ignored`. The small
+// ROMs in tests/roms/selftest are our own and always run.
+//
+// There are no memory bank controllers yet, so only 32 KB ROMs without one are run. That leaves
+// out the combined cpu_instrs.gb and most of mooneye's acceptance tests, they are listed as
+// unsupported instead. Any other ROM that fails, times out or crashes fails the test.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use rb_core::CPU;
+
+const CYCLES_PER_SECOND: u64 = 4_194_304;
+// Blargg's combined cpu_instrs takes almost a minute on a DMG
+const BLARGG_TIMEOUT_SECONDS: u64 = 120;
+const MOONEYE_TIMEOUT_SECONDS: u64 = 20;
+
+// What mooneye's tests leave in B, C, D, E, H and L before LD B,B
+const MOONEYE_PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAILED: [u8; 6] = [0x42; 6];
+const LD_B_B: u8 = 0x40;
+// Blargg prints which test failed right after "Failed", give it time to finish the line
+const FAILURE_DETAILS_CYCLES: u64 = CYCLES_PER_SECOND / 10;
+
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x147;
+const ROM_ONLY: u8 = 0x00;
+const MAX_ROM_SIZE: usize = 0x8000;
+
+#[derive(PartialEq)]
+enum Outcome {
+    Passed,
+    Failed(String),
+    TimedOut,
+    Crashed(String),
+    // Needs hardware the emulator doesn't have, so it isn't run
+    Unsupported(String),
+}
+
+#[test]
+fn selftest() {
+    // tiles.gb only draws, it is checked by the screenshot tests
+    let selftest = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roms/selftest");
+    let roms = ["serial.gb", "registers.gb"].map(|rom| selftest.join(rom));
+    run_suite("Selftest", &selftest, &roms, MOONEYE_TIMEOUT_SECONDS);
+}
+
+#[test]
+#[ignore = "needs Blargg's test ROMs in tests/roms/blargg or TEST_ROMS"]
+fn blargg() {
+    let blargg = roms_directory().join("blargg");
+    let roms = ["cpu_instrs", "instr_timing", "mem_timing"]
+        .iter()
+        .flat_map(|suite| find_roms(&blargg.join(suite)))
+        .chain(Some(blargg.join("halt_bug.gb")).filter(|rom| rom.exists()))
+        .collect::<Vec<_>>();
+    run_suite("Blargg", &blargg, &roms, BLARGG_TIMEOUT_SECONDS);
+}
+
+#[test]
+#[ignore = "needs mooneye's test ROMs in tests/roms/mooneye or TEST_ROMS"]
+fn mooneye() {
+    let acceptance = roms_directory().join("mooneye/acceptance");
+    let roms = find_roms(&acceptance)
+        .into_iter()
+        .filter(|rom| runs_on_dmg(rom))
+        .collect::<Vec<_>>();
+    run_suite("Mooneye", &acceptance, &roms, MOONEYE_TIMEOUT_SECONDS);
+}
+
+fn roms_directory() -> PathBuf {
+    match std::env::var("TEST_ROMS") {
+        Ok(directory) => PathBuf::from(directory),
+        Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roms"),
+    }
+}
+
+fn find_roms(directory: &Path) -> Vec<PathBuf> {
+    let mut roms = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(directory) {
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            if path.is_dir() {
+                roms.extend(find_roms(&path));
+            } else if path.extension().is_some_and(|extension| extension == "gb") {
+                roms.push(path);
+            }
+        }
+    }
+    roms.sort();
+    roms
+}
+
+// Mooneye puts the models a test is meant for at the end of its name, e.g. boot_regs-dmgABC
+// or di_timing-GS where G stands for the DMG and MGB. Tests without one run everywhere.
+fn runs_on_dmg(rom: &Path) -> bool {
+    let name = rom.file_stem().unwrap().to_string_lossy();
+    match name.rsplit_once('-') {
+        Some((_, models)) if models.chars().all(|c| c.is_ascii_uppercase()) => models.contains('G'),
+        Some((_, models)) => models.contains("dmgABC"),
+        None => true,
+    }
+}
+
+fn run_suite(suite: &str, directory: &Path, roms: &[PathBuf], timeout_seconds: u64) {
+    assert!(!roms.is_empty(), "No {} test ROMs found in {}", suite, directory.display());
+
+    let mut results = Vec::new();
+    for rom in roms {
+        let start = Instant::now();
+        let outcome = run_rom(rom, timeout_seconds);
+        let name = rom.strip_prefix(directory).unwrap_or(rom).display().to_string();
+        results.push((name, outcome, start.elapsed()));
+    }
+
+    let width = results.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+    eprintln!("{:<width$}  {:<11} {:>8}", "ROM", "Result", "Time", width = width);
+    for (name, outcome, elapsed) in &results {
+        let (result, details) = match outcome {
+            Outcome::Passed => ("passed", String::new()),
+            Outcome::Failed(details) => ("FAILED", details.clone()),
+            Outcome::TimedOut => ("TIMED OUT", String::new()),
+            Outcome::Crashed(details) => ("CRASHED", details.clone()),
+            Outcome::Unsupported(details) => ("unsupported", details.clone()),
+        };
+        eprintln!(
+            "{:<width$}  {:<11} {:>7.1}s  {}",
+            name,
+            result,
+            elapsed.as_secs_f32(),
+            details,
+            width = width
+        );
+    }
+
+    let passed = results.iter().filter(|(_, outcome, _)| *outcome == Outcome::Passed).count();
+    let unsupported = results.iter().filter(|(_, outcome, _)| matches!(outcome, Outcome::Unsupported(_))).count();
+    let run = results.len() - unsupported;
+    eprintln!("{}: {} of {} passed, {} unsupported", suite, passed, run, unsupported);
+    assert_eq!(passed, run, "{} test ROMs failed, timed out or crashed", run - passed);
+}
+
+fn run_rom(rom: &Path, timeout_seconds: u64) -> Outcome {
+    let buffer = match std::fs::read(rom) {
+        Ok(buffer) => buffer,
+        Err(e) => return Outcome::Crashed(e.to_string()),
+    };
+    if let Some(reason) = unsupported(&buffer) {
+        return Outcome::Unsupported(reason);
+    }
+    // Anything else the emulator panics on is a crash
+    catch_unwind(AssertUnwindSafe(|| {
+        let mut cpu = CPU::new(None, buffer);
+        let mut cycles = 0;
+        let mut serial_length = 0;
+        let mut failed_at = None;
+        while cycles < timeout_seconds * CYCLES_PER_SECOND {
+            if cpu.bus.read_byte(cpu.pc) == LD_B_B {
+                let r = &cpu.registers;
+                match [r.b, r.c, r.d, r.e, r.h, r.l] {
+                    MOONEYE_PASSED => return Outcome::Passed,
+                    MOONEYE_FAILED => return Outcome::Failed("mooneye failure signature".to_string()),
+                    _ => {}
+                }
+            }
+            cycles += cpu.step() as u64;
+
+            let output = &cpu.bus.serial.output;
+            if output.len() != serial_length {
+                serial_length = output.len();
+                let text = String::from_utf8_lossy(output);
+                if text.contains("Passed") {
+                    return Outcome::Passed;
+                }
+                if text.contains("Failed") && failed_at.is_none() {
+                    failed_at = Some(cycles);
+                }
+            }
+            if failed_at.is_some_and(|failed_at| cycles - failed_at > FAILURE_DETAILS_CYCLES) {
+                let text = String::from_utf8_lossy(output);
+                return Outcome::Failed(text.split_whitespace().collect::<Vec<_>>().join(" "));
+            }
+        }
+        match failed_at {
+            Some(_) => Outcome::Failed(String::from_utf8_lossy(&cpu.bus.serial.output).trim().to_string()),
+            None => Outcome::TimedOut,
+        }
+    }))
+    .unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|message| message.to_string()))
+            .unwrap_or_default();
+        Outcome::Crashed(message)
+    })
+}
+
+fn unsupported(rom: &[u8]) -> Option<String> {
+    let cartridge_type = rom.get(CARTRIDGE_TYPE_ADDRESS).copied().unwrap_or(ROM_ONLY);
+    if cartridge_type != ROM_ONLY {
+        return Some(format!("cartridge type {:02X} needs a memory bank controller", cartridge_type));
+    }
+    if rom.len() > MAX_ROM_SIZE {
+        return Some(format!("{} KB ROM without a memory bank controller", rom.len() / 1024));
+    }
+    None
+}
@@ -0,0 +1,751 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+use rb_core::*;
+
+use crate::parse_address;
+
+// Instructions shown before and after PC
+const DISASSEMBLY_BEFORE: usize = 4;
+const DISASSEMBLY_AFTER: usize = 8;
+// How far back to look for an instruction that ends right at PC
+const DISASSEMBLY_LOOKBEHIND: u16 = 12;
+const MEMORY_DUMP_LENGTH: u16 = 64;
+const MEMORY_DUMP_ROW: u16 = 16;
+const INTERRUPT_VECTORS: [u16; 5] = [0x40, 0x48, 0x50, 0x58, 0x60];
+
+const HELP: &str = "\
+Numbers are hexadecimal, write $a or 0xa when they could be mistaken for a register. Addresses
+can also be labels from the .sym file or bank:address like 02:4000, breaks and untils on those
+only stop while that bank is switched in.
+
+  break <addr> [if <condition>]   b   stop before the instruction at addr runs,
+                                      conditions look like 'a == 3C', 'hl >= C000' or '[FF44] != 90'
+  watch <addr>[-<end>] [r|w|rw]   w   stop when the CPU reads and/or writes there, writes by default
+  delete <n>|all                  del remove a breakpoint or watchpoint
+  info                            i   list breakpoints and watchpoints
+  step [n]                        s   run one or n instructions
+  next                            n   run one instruction, calls and RSTs as a whole
+  finish                          out run until the current routine returns
+  continue                        c   run until a breakpoint or watchpoint
+  until <addr>                    u   run until the instruction at addr
+  regs                            r   show the registers
+  set <register> <value>              change a register, ime or one of a f b c d e h l af bc de hl sp pc
+  x <addr> [length]                   show memory
+  write <addr> <byte>...              change memory
+  dis [addr] [count]              d   disassemble, around PC without an address
+  backtrace                       bt  show the calls that led here since the debugger started
+  quit                            q   exit the emulator
+An empty line repeats the last command.";
+
+#[derive(Copy, Clone, PartialEq)]
+enum Register {
+    A, F, B, C, D, E, H, L, AF, BC, DE, HL, SP, PC, Ime,
+}
+
+enum Operand {
+    Register(Register),
+    Memory(u16),
+    Value(u16),
+}
+
+struct Condition {
+    left: Operand,
+    comparison: String,
+    right: Operand,
+    text: String,
+}
+
+// An address that only counts in one bank when it has one
+#[derive(Copy, Clone, PartialEq)]
+struct Location {
+    bank: Option<usize>,
+    address: u16,
+}
+
+impl Location {
+    fn matches(&self, cpu: &CPU, address: u16) -> bool {
+        self.address == address && self.bank.is_none_or(|bank| cpu.bus.bank_at(address) == bank)
+    }
+}
+
+enum Point {
+    Breakpoint(Location, Option<Condition>),
+    Watchpoint(Watchpoint),
+}
+
+enum Mode {
+    Paused,
+    Running,
+    Step(u32),
+    // Until the instruction after a call comes up with the stack back where it was
+    StepOver { pc: u16, sp: u16 },
+    // Until a RET takes the stack above where it was
+    StepOut { sp: u16 },
+    RunTo(Location),
+}
+
+// A call or interrupt that hasn't returned yet
+struct Frame {
+    // Where the call was made or the interrupt came in
+    from: u16,
+    bank: usize,
+    // Where the return address is on the stack
+    sp: u16,
+    interrupt: bool,
+}
+
+// A REPL on the terminal that runs in front of the CPU, see HELP for what it can do
+pub struct Debugger {
+    symbols: Symbols,
+    points: BTreeMap<usize, Point>,
+    next_id: usize,
+    mode: Mode,
+    // Why the debugger should stop before the next instruction
+    stop_reason: Option<String>,
+    // Lets the instruction the REPL was left at run without hitting its own breakpoint again
+    resuming: bool,
+    last_command: String,
+    call_stack: Vec<Frame>,
+    pub quit: bool,
+}
+
+impl Debugger {
+    pub fn new(symbols: Symbols) -> Debugger {
+        Debugger {
+            symbols,
+            points: BTreeMap::new(),
+            next_id: 1,
+            mode: Mode::Paused,
+            stop_reason: None,
+            resuming: false,
+            last_command: String::new(),
+            call_stack: Vec::new(),
+            quit: false,
+        }
+    }
+
+    // Opens the REPL before the next instruction
+    pub fn pause(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    // Runs one instruction unless the debugger has to stop first, None once the user quits
+    pub fn step(&mut self, cpu: &mut CPU) -> Option<u32> {
+        if let Some(reason) = self.should_stop(cpu) {
+            if !reason.is_empty() {
+                println!("{}", reason);
+            }
+            self.repl(cpu);
+        }
+        if self.quit {
+            return None;
+        }
+
+        let (pc, sp) = (cpu.pc, cpu.sp);
+        let bank = cpu.bus.bank_at(pc);
+        let returning = matches!(self.mode, Mode::StepOut { .. }) && cpu.disassemble(pc).text.starts_with("ret");
+        let calling = is_call(cpu.bus.peek(pc));
+        let cycles = cpu.step();
+        self.track_calls(cpu, pc, sp, bank, calling);
+
+        if let Some(hit) = cpu.bus.watch_hit.take() {
+            let id = self
+                .points
+                .iter()
+                .find(|(_, point)| matches!(point, Point::Watchpoint(watchpoint) if watchpoint.matches(hit.address, hit.write)))
+                .map_or(0, |(id, _)| *id);
+            let access = if hit.write { "wrote" } else { "read" };
+            let direction = if hit.write { "to" } else { "from" };
+            self.stop_reason = Some(format!(
+                "Watchpoint {}: {} ${:02X} {} {} at {}",
+                id,
+                access,
+                hit.value,
+                direction,
+                self.describe(cpu, hit.address),
+                self.describe_in(bank, pc)
+            ));
+        }
+        let done = match &mut self.mode {
+            Mode::Step(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            Mode::StepOver { pc, sp } => cpu.pc == *pc && cpu.sp >= *sp,
+            Mode::StepOut { sp: start } => returning && cpu.sp > *start,
+            _ => false,
+        };
+        if done {
+            self.stop_reason.get_or_insert_with(String::new);
+        }
+        Some(cycles)
+    }
+
+    fn should_stop(&mut self, cpu: &mut CPU) -> Option<String> {
+        if let Some(reason) = self.stop_reason.take() {
+            return Some(reason);
+        }
+        if std::mem::take(&mut self.resuming) {
+            return None;
+        }
+        if matches!(self.mode, Mode::Paused) {
+            return Some(String::new());
+        }
+        if matches!(self.mode, Mode::RunTo(location) if location.matches(cpu, cpu.pc)) {
+            return Some(String::new());
+        }
+        let pc = cpu.pc;
+        for (id, point) in &self.points {
+            if let Point::Breakpoint(location, condition) = point {
+                if location.matches(cpu, pc) && condition.as_ref().is_none_or(|condition| evaluate(condition, cpu)) {
+                    return Some(format!("Breakpoint {} at {}", id, self.describe(cpu, pc)));
+                }
+            }
+        }
+        None
+    }
+
+    // Keeps the call stack up to date from what the last instruction did to SP and PC
+    fn track_calls(&mut self, cpu: &mut CPU, pc: u16, sp: u16, bank: usize, calling: bool) {
+        // An interrupt can come in right after the instruction, on top of whatever it did
+        let interrupted = INTERRUPT_VECTORS.contains(&cpu.pc) && !(calling && sp.wrapping_sub(2) == cpu.sp);
+        let stack = if interrupted { cpu.sp.wrapping_add(2) } else { cpu.sp };
+        // Returns, or anything else that moved the stack above a return address
+        while self.call_stack.last().is_some_and(|frame| frame.sp < stack) {
+            self.call_stack.pop();
+        }
+        if calling && stack == sp.wrapping_sub(2) {
+            self.call_stack.push(Frame { from: pc, bank, sp: stack, interrupt: false });
+        }
+        if interrupted {
+            let from = u16::from_le_bytes([cpu.bus.peek(cpu.sp), cpu.bus.peek(cpu.sp.wrapping_add(1))]);
+            let bank = cpu.bus.bank_at(from);
+            self.call_stack.push(Frame { from, bank, sp: cpu.sp, interrupt: true });
+        }
+    }
+
+    fn repl(&mut self, cpu: &mut CPU) {
+        self.mode = Mode::Paused;
+        self.show_current(cpu);
+        let stdin = std::io::stdin();
+        while matches!(self.mode, Mode::Paused) && !self.quit {
+            print!("(rb) ");
+            let _ = std::io::stdout().flush();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                self.quit = true;
+                break;
+            }
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string(),
+            };
+            self.last_command = line.clone();
+            if let Err(e) = self.command(&line, cpu) {
+                println!("{}", e);
+            }
+        }
+        self.resuming = true;
+    }
+
+    fn command(&mut self, line: &str, cpu: &mut CPU) -> Result<(), String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let (command, arguments) = match words.split_first() {
+            Some((command, arguments)) => (*command, arguments),
+            None => return Ok(()),
+        };
+        match command {
+            "help" | "h" | "?" => println!("{}", HELP),
+            "break" | "b" => {
+                let location = self.location(arguments.first().ok_or("break needs an address")?)?;
+                let condition = match arguments.get(1) {
+                    Some(&"if") => Some(self.parse_condition(&arguments[2..])?),
+                    Some(other) => return Err(format!("Expected 'if' instead of '{}'", other)),
+                    None => None,
+                };
+                let id = self.add(Point::Breakpoint(location, condition));
+                println!("Breakpoint {} at {}", id, self.describe_location(location));
+            }
+            "watch" | "w" => {
+                let range = arguments.first().ok_or("watch needs an address")?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (self.address(start)?, self.address(end)?),
+                    None => (self.address(range)?, self.address(range)?),
+                };
+                let (read, write) = match arguments.get(1).copied() {
+                    None | Some("w") => (false, true),
+                    Some("r") => (true, false),
+                    Some("rw") => (true, true),
+                    Some(other) => return Err(format!("Expected r, w or rw instead of '{}'", other)),
+                };
+                let id = self.add(Point::Watchpoint(Watchpoint { start, end, read, write }));
+                println!("Watchpoint {} on ${:04X}-${:04X}", id, start, end);
+                self.update_watchpoints(cpu);
+            }
+            "delete" | "del" => {
+                match arguments.first().copied() {
+                    Some("all") => self.points.clear(),
+                    Some(id) => {
+                        let id = id.parse().map_err(|_| format!("'{}' is not a breakpoint number", id))?;
+                        self.points.remove(&id).ok_or(format!("There is no breakpoint {}", id))?;
+                    }
+                    None => return Err("delete needs a breakpoint number or all".to_string()),
+                }
+                self.update_watchpoints(cpu);
+            }
+            "info" | "i" => {
+                if self.points.is_empty() {
+                    println!("No breakpoints or watchpoints");
+                }
+                for (id, point) in &self.points {
+                    match point {
+                        Point::Breakpoint(location, None) => println!("{:>3}  break {}", id, self.describe_location(*location)),
+                        Point::Breakpoint(location, Some(condition)) => {
+                            println!("{:>3}  break {} if {}", id, self.describe_location(*location), condition.text)
+                        }
+                        Point::Watchpoint(watchpoint) => {
+                            let access = match (watchpoint.read, watchpoint.write) {
+                                (true, true) => "rw",
+                                (true, false) => "r",
+                                _ => "w",
+                            };
+                            println!("{:>3}  watch ${:04X}-${:04X} {}", id, watchpoint.start, watchpoint.end, access);
+                        }
+                    }
+                }
+            }
+            "step" | "s" => {
+                let count = match arguments.first() {
+                    Some(count) => count.parse().ok().filter(|&count| count > 0).ok_or(format!("'{}' is not a count", count))?,
+                    None => 1,
+                };
+                self.mode = Mode::Step(count);
+            }
+            "next" | "n" => {
+                let instruction = cpu.disassemble(cpu.pc);
+                self.mode = if instruction.text.starts_with("call") || instruction.text.starts_with("rst") {
+                    Mode::StepOver { pc: cpu.pc.wrapping_add(instruction.length()), sp: cpu.sp }
+                } else {
+                    Mode::Step(1)
+                };
+            }
+            "finish" | "out" => self.mode = Mode::StepOut { sp: cpu.sp },
+            "continue" | "c" => self.mode = Mode::Running,
+            "until" | "u" => self.mode = Mode::RunTo(self.location(arguments.first().ok_or("until needs an address")?)?),
+            "regs" | "r" => show_registers(cpu),
+            "set" => {
+                if arguments.len() != 2 {
+                    return Err("Usage: set <register> <value>".to_string());
+                }
+                let register = parse_register(arguments[0]).ok_or(format!("'{}' is not a register", arguments[0]))?;
+                set_register(cpu, register, self.address(arguments[1])?);
+                show_registers(cpu);
+            }
+            "x" => {
+                let start = self.address(arguments.first().ok_or("x needs an address")?)?;
+                let length = match arguments.get(1) {
+                    Some(length) => parse_address(length)?,
+                    None => MEMORY_DUMP_LENGTH,
+                };
+                for row in (0..length).step_by(MEMORY_DUMP_ROW as usize) {
+                    let address = start.wrapping_add(row);
+                    let bytes = (row..length.min(row.saturating_add(MEMORY_DUMP_ROW)))
+                        .map(|offset| format!("{:02X}", cpu.bus.peek(start.wrapping_add(offset))))
+                        .collect::<Vec<_>>();
+                    println!("{:04X}  {}", address, bytes.join(" "));
+                }
+            }
+            "write" => {
+                let address = self.address(arguments.first().ok_or("write needs an address")?)?;
+                if arguments.len() < 2 {
+                    return Err("write needs at least one byte".to_string());
+                }
+                for (offset, value) in arguments[1..].iter().enumerate() {
+                    let value = u8::try_from(parse_address(value)?).map_err(|_| format!("'{}' is not a byte", value))?;
+                    let address = address.wrapping_add(offset as u16);
+                    if !cpu.bus.poke(address, value) {
+                        return Err(format!("${:04X} can't be written, it is ROM or not mapped", address));
+                    }
+                }
+            }
+            "dis" | "d" => match arguments.first() {
+                Some(address) => {
+                    let mut address = self.address(address)?;
+                    let count = match arguments.get(1) {
+                        Some(count) => count.parse().map_err(|_| format!("'{}' is not a count", count))?,
+                        None => DISASSEMBLY_AFTER,
+                    };
+                    for _ in 0..count {
+                        let instruction = cpu.disassemble(address);
+                        address = address.wrapping_add(instruction.length());
+                        println!("{}", self.format_line(instruction, cpu));
+                    }
+                }
+                None => {
+                    for instruction in disassembly_around(cpu) {
+                        println!("{}", self.format_line(instruction, cpu));
+                    }
+                }
+            },
+            "backtrace" | "bt" => {
+                println!("#0  {}", self.describe(cpu, cpu.pc));
+                for (depth, frame) in self.call_stack.iter().rev().enumerate() {
+                    let interrupt = if frame.interrupt { " (interrupted)" } else { "" };
+                    println!("#{:<2} {}{}", depth + 1, self.describe_in(frame.bank, frame.from), interrupt);
+                }
+            }
+            "quit" | "q" => self.quit = true,
+            _ => return Err(format!("Unknown command '{}', try help", command)),
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, point: Point) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.points.insert(id, point);
+        id
+    }
+
+    fn update_watchpoints(&self, cpu: &mut CPU) {
+        cpu.bus.watchpoints = self
+            .points
+            .values()
+            .filter_map(|point| match point {
+                Point::Watchpoint(watchpoint) => Some(*watchpoint),
+                _ => None,
+            })
+            .collect();
+    }
+
+    fn show_current(&self, cpu: &mut CPU) {
+        let instruction = cpu.disassemble(cpu.pc);
+        println!("{}", self.format_line(instruction, cpu));
+    }
+
+    // Marks PC with an arrow and breakpoints with an asterisk, with labels for the address and
+    // the operand
+    fn format_line(&self, mut instruction: Disassembled, cpu: &CPU) -> String {
+        let address = instruction.address;
+        let breakpoint = self
+            .points
+            .values()
+            .any(|point| matches!(point, Point::Breakpoint(location, _) if location.matches(cpu, address)));
+        let marker = match (address == cpu.pc, breakpoint) {
+            (true, _) => "=>",
+            (false, true) => " *",
+            _ => "  ",
+        };
+        if let Some(label) = instruction.operand.and_then(|operand| self.symbols.label(cpu.bus.bank_at(operand), operand)) {
+            instruction.label_operand(label);
+        }
+        match self.symbols.label(cpu.bus.bank_at(address), address) {
+            Some(label) => format!("   {}:\n{} {}", label, marker, instruction),
+            None => format!("{} {}", marker, instruction),
+        }
+    }
+
+    // A label or bank:address, anything else is a plain address
+    fn location(&self, text: &str) -> Result<Location, String> {
+        if let Some((bank, address)) = self.symbols.lookup(text).or_else(|| parse_location(text)) {
+            return Ok(Location { bank: Some(bank), address });
+        }
+        let address = parse_address(text).map_err(|_| format!("'{}' is not an address or a label", text))?;
+        Ok(Location { bank: None, address })
+    }
+
+    fn address(&self, text: &str) -> Result<u16, String> {
+        Ok(self.location(text)?.address)
+    }
+
+    // "$0203 (UpdateScore+3)" as it is mapped right now
+    fn describe(&self, cpu: &CPU, address: u16) -> String {
+        self.describe_in(cpu.bus.bank_at(address), address)
+    }
+
+    fn describe_in(&self, bank: usize, address: u16) -> String {
+        match self.symbols.describe(bank, address) {
+            Some(label) => format!("${:04X} ({})", address, label),
+            None => format!("${:04X}", address),
+        }
+    }
+
+    // "02:4000 (LoadLevel)" for breakpoints in a bank
+    fn describe_location(&self, location: Location) -> String {
+        match location.bank {
+            Some(bank) => match self.symbols.describe(bank, location.address) {
+                Some(label) => format!("{:02X}:{:04X} ({})", bank, location.address, label),
+                None => format!("{:02X}:{:04X}", bank, location.address),
+            },
+            None => format!("${:04X}", location.address),
+        }
+    }
+
+    fn parse_condition(&self, words: &[&str]) -> Result<Condition, String> {
+        if words.len() != 3 {
+            return Err("Conditions look like '<register|[address]|value> <comparison> <register|[address]|value>'".to_string());
+        }
+        if !["==", "!=", "<", "<=", ">", ">="].contains(&words[1]) {
+            return Err(format!("'{}' is not one of == != < <= > >=", words[1]));
+        }
+        Ok(Condition {
+            left: self.parse_operand(words[0])?,
+            comparison: words[1].to_string(),
+            right: self.parse_operand(words[2])?,
+            text: words.join(" "),
+        })
+    }
+
+    fn parse_operand(&self, text: &str) -> Result<Operand, String> {
+        if let Some(register) = parse_register(text) {
+            return Ok(Operand::Register(register));
+        }
+        if let Some(address) = text.strip_prefix('[').and_then(|text| text.strip_suffix(']')) {
+            return Ok(Operand::Memory(self.address(address)?));
+        }
+        Ok(Operand::Value(self.address(text)?))
+    }
+}
+
+// CALL and RST, taken or not
+fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC) || opcode & 0xC7 == 0xC7
+}
+
+// Instructions don't say where they start, so this looks for a starting point before PC that
+// decodes into an instruction ending right at PC
+fn disassembly_around(cpu: &mut CPU) -> Vec<Disassembled> {
+    let pc = cpu.pc;
+    let mut instructions = Vec::new();
+    for back in (1..=DISASSEMBLY_LOOKBEHIND.min(pc)).rev() {
+        let mut address = pc - back;
+        let mut before = Vec::new();
+        while address < pc {
+            let instruction = cpu.disassemble(address);
+            address += instruction.length();
+            before.push(instruction);
+        }
+        if address == pc {
+            let skip = before.len().saturating_sub(DISASSEMBLY_BEFORE);
+            instructions.extend(before.into_iter().skip(skip));
+            break;
+        }
+    }
+    let mut address = pc;
+    for _ in 0..DISASSEMBLY_AFTER {
+        let instruction = cpu.disassemble(address);
+        address = address.wrapping_add(instruction.length());
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+fn show_registers(cpu: &CPU) {
+    let r = &cpu.registers;
+    let f = r.get_af() as u8;
+    let flags = [(0x80, 'Z'), (0x40, 'N'), (0x20, 'H'), (0x10, 'C')]
+        .iter()
+        .map(|&(mask, name)| if f & mask != 0 { name } else { '-' })
+        .collect::<String>();
+    let state = if cpu.is_halted() {
+        " halted"
+    } else if cpu.is_stopped() {
+        " stopped"
+    } else {
+        ""
+    };
+    println!(
+        "A:{:02X} F:{:02X} ({}) B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} IME:{}{}",
+        r.a, f, flags, r.b, r.c, r.d, r.e, r.h, r.l, cpu.sp, cpu.pc, cpu.ime() as u8, state
+    );
+}
+
+fn parse_register(name: &str) -> Option<Register> {
+    let register = match name.to_ascii_lowercase().as_str() {
+        "a" => Register::A,
+        "f" => Register::F,
+        "b" => Register::B,
+        "c" => Register::C,
+        "d" => Register::D,
+        "e" => Register::E,
+        "h" => Register::H,
+        "l" => Register::L,
+        "af" => Register::AF,
+        "bc" => Register::BC,
+        "de" => Register::DE,
+        "hl" => Register::HL,
+        "sp" => Register::SP,
+        "pc" => Register::PC,
+        "ime" => Register::Ime,
+        _ => return None,
+    };
+    Some(register)
+}
+
+fn get_register(cpu: &CPU, register: Register) -> u16 {
+    let r = &cpu.registers;
+    match register {
+        Register::A => r.a as u16,
+        Register::F => r.get_af() & 0xFF,
+        Register::B => r.b as u16,
+        Register::C => r.c as u16,
+        Register::D => r.d as u16,
+        Register::E => r.e as u16,
+        Register::H => r.h as u16,
+        Register::L => r.l as u16,
+        Register::AF => r.get_af(),
+        Register::BC => r.get_bc(),
+        Register::DE => r.get_de(),
+        Register::HL => r.get_hl(),
+        Register::SP => cpu.sp,
+        Register::PC => cpu.pc,
+        Register::Ime => cpu.ime() as u16,
+    }
+}
+
+fn set_register(cpu: &mut CPU, register: Register, value: u16) {
+    let r = &mut cpu.registers;
+    let byte = value as u8;
+    match register {
+        Register::A => r.a = byte,
+        Register::F => r.set_af((r.a as u16) << 8 | byte as u16),
+        Register::B => r.b = byte,
+        Register::C => r.c = byte,
+        Register::D => r.d = byte,
+        Register::E => r.e = byte,
+        Register::H => r.h = byte,
+        Register::L => r.l = byte,
+        Register::AF => r.set_af(value),
+        Register::BC => r.set_bc(value),
+        Register::DE => r.set_de(value),
+        Register::HL => r.set_hl(value),
+        Register::SP => cpu.sp = value,
+        Register::PC => cpu.pc = value,
+        Register::Ime => cpu.interrupts_enabled = value != 0,
+    }
+}
+
+fn evaluate(condition: &Condition, cpu: &mut CPU) -> bool {
+    let mut value = |operand: &Operand| match operand {
+        Operand::Register(register) => get_register(cpu, *register),
+        Operand::Memory(address) => cpu.bus.peek(*address) as u16,
+        Operand::Value(value) => *value,
+    };
+    let (left, right) = (value(&condition.left), value(&condition.right));
+    match condition.comparison.as_str() {
+        "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        "<=" => left <= right,
+        ">" => left > right,
+        _ => left >= right,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CALL: u8 = 0xCD;
+    const RET: u8 = 0xC9;
+
+    // CALL 0200 at the entry point, the routine is a NOP and a RET
+    fn cpu() -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[CALL, 0x00, 0x02]);
+        rom[0x201] = RET;
+        CPU::new(None, rom)
+    }
+
+    // Runs until the debugger wants to stop, without opening the REPL
+    fn steps_until_stop(debugger: &mut Debugger, cpu: &mut CPU) -> usize {
+        for steps in 1..=100 {
+            debugger.step(cpu);
+            if debugger.stop_reason.take().is_some() {
+                return steps;
+            }
+        }
+        panic!("The debugger never stopped");
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(parse_address("C000"), Ok(0xC000));
+        assert_eq!(parse_address("$ff44"), Ok(0xFF44));
+        assert_eq!(parse_address("0x3C"), Ok(0x3C));
+        assert!(parse_address("hello").is_err());
+        assert!(parse_address("10000").is_err());
+    }
+
+    #[test]
+    fn parses_registers() {
+        assert!(parse_register("a") == Some(Register::A));
+        assert!(parse_register("HL") == Some(Register::HL));
+        assert!(parse_register("ime") == Some(Register::Ime));
+        assert!(parse_register("ix").is_none());
+    }
+
+    #[test]
+    fn parses_conditions() {
+        let debugger = Debugger::new(Symbols::default());
+        let condition = debugger.parse_condition(&["a", "==", "3C"]).unwrap();
+        assert!(matches!(condition.left, Operand::Register(Register::A)));
+        assert!(matches!(condition.right, Operand::Value(0x3C)));
+        assert_eq!(condition.text, "a == 3C");
+
+        let condition = debugger.parse_condition(&["[FF44]", ">=", "$90"]).unwrap();
+        assert!(matches!(condition.left, Operand::Memory(0xFF44)));
+        assert!(matches!(condition.right, Operand::Value(0x90)));
+
+        assert!(debugger.parse_condition(&["a", "=", "3C"]).is_err());
+        assert!(debugger.parse_condition(&["a", "=="]).is_err());
+        assert!(debugger.parse_condition(&["[zz]", "==", "1"]).is_err());
+    }
+
+    #[test]
+    fn conditional_breakpoint() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new(Symbols::default());
+        debugger.command("break 0100 if a != 1", &mut cpu).unwrap();
+        debugger.mode = Mode::Running;
+        cpu.registers.a = 1;
+        assert!(debugger.should_stop(&mut cpu).is_none());
+        cpu.registers.a = 2;
+        assert_eq!(debugger.should_stop(&mut cpu), Some("Breakpoint 1 at $0100".to_string()));
+
+        assert!(debugger.command("break 0100 when a == 1", &mut cpu).is_err());
+        assert!(debugger.command("break", &mut cpu).is_err());
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new(Symbols::default());
+        debugger.command("next", &mut cpu).unwrap();
+        // The CALL, the NOP and the RET
+        assert_eq!(steps_until_stop(&mut debugger, &mut cpu), 3);
+        assert_eq!(cpu.pc, 0x0103);
+        assert_eq!(cpu.sp, 0xFFFE);
+    }
+
+    #[test]
+    fn finish_runs_until_the_routine_returns() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new(Symbols::default());
+        debugger.command("step", &mut cpu).unwrap();
+        assert_eq!(steps_until_stop(&mut debugger, &mut cpu), 1);
+        assert_eq!(cpu.pc, 0x0200);
+
+        debugger.command("finish", &mut cpu).unwrap();
+        assert_eq!(steps_until_stop(&mut debugger, &mut cpu), 2);
+        assert_eq!(cpu.pc, 0x0103);
+    }
+
+    #[test]
+    fn dump_at_the_end_of_memory() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new(Symbols::default());
+        debugger.command("x FFF0 FFFF", &mut cpu).unwrap();
+    }
+}
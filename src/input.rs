@@ -0,0 +1,338 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+use sdl2::controller::{Axis, GameController};
+use sdl2::controller::Button as PadButton;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::GameControllerSubsystem;
+
+use rb_core::*;
+
+const BUTTONS: [Button; 8] = [
+    Button::Right,
+    Button::Left,
+    Button::Up,
+    Button::Down,
+    Button::A,
+    Button::B,
+    Button::Select,
+    Button::Start,
+];
+
+// How far the analog stick has to be pushed before it counts as a direction
+const STICK_DEAD_ZONE: i16 = 12000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Press(Button),
+    // Repeatedly presses and releases the button while held
+    Turbo(Button),
+}
+
+pub struct Bindings {
+    pub keys: HashMap<Keycode, Action>,
+    pub pad_buttons: HashMap<PadButton, Action>,
+    pub allow_opposing: bool,
+    // Frames the turbo buttons stay pressed and then released
+    pub turbo_rate: u32,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let keys = [
+            (Keycode::Right, Action::Press(Button::Right)),
+            (Keycode::Left, Action::Press(Button::Left)),
+            (Keycode::Up, Action::Press(Button::Up)),
+            (Keycode::Down, Action::Press(Button::Down)),
+            (Keycode::X, Action::Press(Button::A)),
+            (Keycode::Z, Action::Press(Button::B)),
+            (Keycode::Backspace, Action::Press(Button::Select)),
+            (Keycode::Return, Action::Press(Button::Start)),
+            (Keycode::S, Action::Turbo(Button::A)),
+            (Keycode::A, Action::Turbo(Button::B)),
+        ];
+        let pad_buttons = [
+            (PadButton::DPadRight, Action::Press(Button::Right)),
+            (PadButton::DPadLeft, Action::Press(Button::Left)),
+            (PadButton::DPadUp, Action::Press(Button::Up)),
+            (PadButton::DPadDown, Action::Press(Button::Down)),
+            (PadButton::B, Action::Press(Button::A)),
+            (PadButton::A, Action::Press(Button::B)),
+            (PadButton::Back, Action::Press(Button::Select)),
+            (PadButton::Start, Action::Press(Button::Start)),
+            (PadButton::Y, Action::Turbo(Button::A)),
+            (PadButton::X, Action::Turbo(Button::B)),
+        ];
+        Bindings {
+            keys: keys.into_iter().collect(),
+            pad_buttons: pad_buttons.into_iter().collect(),
+            allow_opposing: false,
+            turbo_rate: 2,
+        }
+    }
+}
+
+impl Bindings {
+    pub fn from_file(path: &str) -> Result<Bindings, String> {
+        let mut file = std::fs::File::open(path)
+            .map_err(|e| format!("Could not open controls file {}: {}", path, e))?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)
+            .map_err(|e| format!("Could not read controls file {}: {}", path, e))?;
+        Bindings::parse(&text)
+    }
+
+    // One binding per line, keys use SDL key names and pad buttons SDL controller names:
+    //
+    //     # comment
+    //     key a = X
+    //     key turbo_b = A
+    //     pad start = start
+    //     allow_opposing = false
+    //     turbo_rate = 2
+    //
+    // Any binding in the file replaces all default bindings of the same device.
+    pub fn parse(text: &str) -> Result<Bindings, String> {
+        let mut bindings = Bindings::default();
+        let mut keys = HashMap::new();
+        let mut pad_buttons = HashMap::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| format!("Line {}: {}", number + 1, message);
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected '<setting> = <value>'".to_string()))?;
+            let value = value.trim();
+            let mut words = key.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some("allow_opposing"), None, _) => {
+                    bindings.allow_opposing = value.parse().map_err(|_| error(format!("'{}' is not true or false", value)))?
+                }
+                (Some("turbo_rate"), None, _) => {
+                    bindings.turbo_rate = value
+                        .parse()
+                        .ok()
+                        .filter(|&rate| rate > 0)
+                        .ok_or_else(|| error(format!("'{}' is not a number of frames", value)))?
+                }
+                (Some("key"), Some(action), None) => {
+                    let keycode = Keycode::from_name(value).ok_or_else(|| error(format!("unknown key '{}'", value)))?;
+                    keys.insert(keycode, parse_action(action).map_err(error)?);
+                }
+                (Some("pad"), Some(action), None) => {
+                    let button = PadButton::from_string(value)
+                        .ok_or_else(|| error(format!("unknown controller button '{}'", value)))?;
+                    pad_buttons.insert(button, parse_action(action).map_err(error)?);
+                }
+                _ => return Err(error(format!("unknown setting '{}'", key.trim()))),
+            }
+        }
+
+        if !keys.is_empty() {
+            bindings.keys = keys;
+        }
+        if !pad_buttons.is_empty() {
+            bindings.pad_buttons = pad_buttons;
+        }
+        Ok(bindings)
+    }
+}
+
+fn parse_action(name: &str) -> Result<Action, String> {
+    let (turbo, name) = match name.strip_prefix("turbo_") {
+        Some(name) => (true, name),
+        None => (false, name),
+    };
+    let button = match name.to_ascii_lowercase().as_str() {
+        "right" => Button::Right,
+        "left" => Button::Left,
+        "up" => Button::Up,
+        "down" => Button::Down,
+        "a" => Button::A,
+        "b" => Button::B,
+        "select" => Button::Select,
+        "start" => Button::Start,
+        _ => return Err(format!("unknown button '{}'", name)),
+    };
+    Ok(if turbo { Action::Turbo(button) } else { Action::Press(button) })
+}
+
+fn index(button: Button) -> usize {
+    BUTTONS.iter().position(|&other| other == button).unwrap()
+}
+
+fn opposite(button: Button) -> Option<Button> {
+    match button {
+        Button::Right => Some(Button::Left),
+        Button::Left => Some(Button::Right),
+        Button::Up => Some(Button::Down),
+        Button::Down => Some(Button::Up),
+        _ => None,
+    }
+}
+
+// Collects keyboard, controller and stick state and turns it into joypad presses
+pub struct Input {
+    bindings: Bindings,
+    subsystem: GameControllerSubsystem,
+    controllers: HashMap<u32, GameController>,
+    // Every source that holds a button down, a button is pressed while any of them is
+    held: HashMap<Action, u32>,
+    // Buttons each controller holds down, let go of when it is unplugged
+    pad_held: HashMap<u32, HashSet<PadButton>>,
+    sticks: HashMap<u32, [bool; 8]>,
+    // The most recently pressed direction wins over the one opposite to it
+    latest: [u64; 8],
+    presses: u64,
+    frame: u32,
+    pressed: [bool; 8],
+}
+
+impl Input {
+    pub fn new(bindings: Bindings, subsystem: GameControllerSubsystem) -> Input {
+        Input {
+            bindings,
+            subsystem,
+            controllers: HashMap::new(),
+            held: HashMap::new(),
+            pad_held: HashMap::new(),
+            sticks: HashMap::new(),
+            latest: [0; 8],
+            presses: 0,
+            frame: 0,
+            pressed: [false; 8],
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => {
+                if let Some(&action) = self.bindings.keys.get(&keycode) {
+                    self.hold(action);
+                }
+            }
+            Event::KeyUp { keycode: Some(keycode), .. } => {
+                if let Some(&action) = self.bindings.keys.get(&keycode) {
+                    self.let_go(action);
+                }
+            }
+            Event::ControllerButtonDown { which, button, .. } => {
+                if let Some(&action) = self.bindings.pad_buttons.get(&button) {
+                    if self.pad_held.entry(which).or_default().insert(button) {
+                        self.hold(action);
+                    }
+                }
+            }
+            Event::ControllerButtonUp { which, button, .. } => {
+                if let Some(&action) = self.bindings.pad_buttons.get(&button) {
+                    if self.pad_held.get_mut(&which).is_some_and(|held| held.remove(&button)) {
+                        self.let_go(action);
+                    }
+                }
+            }
+            Event::ControllerAxisMotion { which, axis, value, .. } => {
+                let (negative, positive) = match axis {
+                    Axis::LeftX => (Button::Left, Button::Right),
+                    Axis::LeftY => (Button::Up, Button::Down),
+                    _ => return,
+                };
+                self.set_stick(which, negative, value < -STICK_DEAD_ZONE);
+                self.set_stick(which, positive, value > STICK_DEAD_ZONE);
+            }
+            Event::ControllerDeviceAdded { which, .. } => match self.subsystem.open(which) {
+                Ok(controller) => {
+                    println!("Controller connected: {}", controller.name());
+                    self.controllers.insert(controller.instance_id(), controller);
+                }
+                Err(e) => println!("Could not open controller {}: {}", which, e),
+            },
+            Event::ControllerDeviceRemoved { which, .. } => {
+                if let Some(controller) = self.controllers.remove(&which) {
+                    println!("Controller disconnected: {}", controller.name());
+                }
+                // Nothing is going to tell us it let go of its buttons
+                for button in self.pad_held.remove(&which).unwrap_or_default() {
+                    if let Some(&action) = self.bindings.pad_buttons.get(&button) {
+                        self.let_go(action);
+                    }
+                }
+                self.sticks.remove(&which);
+            }
+            _ => {}
+        }
+    }
+
+    // Called once per emulated frame to run the turbo buttons
+    pub fn next_frame(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    // Presses and releases joypad buttons to match what is held right now
+    pub fn update(&mut self, cpu: &mut CPU) {
+        let turbo_on = (self.frame / self.bindings.turbo_rate).is_multiple_of(2);
+        let mut wanted = [false; 8];
+        for (index, &button) in BUTTONS.iter().enumerate() {
+            wanted[index] = self.is_held(Action::Press(button))
+                || self.sticks.values().any(|stick| stick[index])
+                || (turbo_on && self.is_held(Action::Turbo(button)));
+        }
+
+        if !self.bindings.allow_opposing {
+            for &button in BUTTONS.iter() {
+                if let Some(other) = opposite(button) {
+                    let (this, that) = (index(button), index(other));
+                    if wanted[this] && wanted[that] && self.latest[this] < self.latest[that] {
+                        wanted[this] = false;
+                    }
+                }
+            }
+        }
+
+        for (index, &button) in BUTTONS.iter().enumerate() {
+            if wanted[index] != self.pressed[index] {
+                self.pressed[index] = wanted[index];
+                if wanted[index] {
+                    cpu.press(button);
+                } else {
+                    cpu.release(button);
+                }
+            }
+        }
+    }
+
+    fn is_held(&self, action: Action) -> bool {
+        self.held.get(&action).is_some_and(|&count| count > 0)
+    }
+
+    fn hold(&mut self, action: Action) {
+        *self.held.entry(action).or_insert(0) += 1;
+        let (Action::Press(button) | Action::Turbo(button)) = action;
+        self.pressed_now(button);
+    }
+
+    fn let_go(&mut self, action: Action) {
+        if let Some(count) = self.held.get_mut(&action) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    fn set_stick(&mut self, which: u32, button: Button, pushed: bool) {
+        let index = index(button);
+        let stick = self.sticks.entry(which).or_insert([false; 8]);
+        let newly_pushed = pushed && !stick[index];
+        stick[index] = pushed;
+        if newly_pushed {
+            self.pressed_now(button);
+        }
+    }
+
+    fn pressed_now(&mut self, button: Button) {
+        self.presses += 1;
+        self.latest[index(button)] = self.presses;
+    }
+}
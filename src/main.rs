@@ -1,57 +1,332 @@
-use std::thread::sleep;
-use std::time::{Instant, Duration};
-use std::{io::Read, env};
-use sdl2::event::Event;
+use std::time::{Duration, Instant};
+use std::io::Read;
+use std::path::Path;
+use clap::{Arg, ArgAction, Command};
+use sdl2::event::{Event, WindowEvent};
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
-use sdl2::render::Canvas;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Canvas, Texture, TextureCreator};
 use sdl2::ttf::Font;
-use sdl2::video::Window;
+use sdl2::video::{FullscreenType, Window, WindowContext};
 use sdl2::keyboard::Keycode;
 use sdl2::render::TextureQuery;
 
 use rb_core::*;
 
+mod input;
+use input::*;
+mod debugger;
+use debugger::Debugger;
+
 const SCREEN_WIDTH: usize = CPU::getRESW();
 const SCREEN_HEIGHT: usize = CPU::getRESH();
-const SCALE: u32 = 4;
-const WINDOW_WIDTH: u32 = (SCREEN_WIDTH as u32 + 10) * SCALE;
-const WINDOW_HEIGHT: u32 = (SCREEN_HEIGHT as u32 + 10) * SCALE;
-const TICKS_PER_FRAME: usize = 70224;
+const DEFAULT_SCALE: u32 = 4;
+const CONTROLS_FILE: &str = "controls.cfg";
+// Bank 0 and one switchable bank
+const MINIMUM_ROM_SIZE: usize = 0x8000;
 
 const ONE_SECOND_IN_MICROS: usize = 1000000000;
 const ONE_SECOND_IN_CYCLES: usize = 4190000;
-const NUMBER_OF_PIXELS: usize = 23040;
 
-fn buffer_from_file(path: &str) -> Vec<u8> {
-    let mut file = std::fs::File::open(path).expect("File not there");
+struct Options {
+    rom: String,
+    boot_rom: Option<String>,
+    scale: u32,
+    palette: Option<String>,
+    fullscreen: bool,
+    headless: bool,
+    frames: u32,
+    screenshot: Option<String>,
+    trace: Option<String>,
+    trace_start: TraceStart,
+    trace_range: Option<(u16, u16)>,
+    trace_limit: Option<u64>,
+    trace_labels: bool,
+    blend: Option<f32>,
+    filters: Option<String>,
+    controls: Option<String>,
+    debug: bool,
+    gdb: Option<u16>,
+    symbols: Option<String>,
+}
+
+fn parse_options() -> Options {
+    let matches = Command::new("rusty-boi")
+        .about("A Game Boy emulator")
+        .arg(Arg::new("rom").required(true).help("Game ROM to run"))
+        .arg(Arg::new("boot-rom").long("boot-rom").takes_value(true).value_name("FILE")
+            .help("Boot ROM to run before the game, skipped when left out"))
+        .arg(Arg::new("scale").long("scale").takes_value(true).value_name("N")
+            .value_parser(clap::value_parser!(u32).range(1..=16)).default_value("4")
+            .help("Window size as a multiple of the screen"))
+        .arg(Arg::new("palette").long("palette").takes_value(true).value_name("NAME|FILE")
+            .help("DMG palette preset (grey, dmg, pocket, light) or palette file"))
+        .arg(Arg::new("fullscreen").long("fullscreen").action(ArgAction::SetTrue)
+            .help("Start in fullscreen"))
+        // There is no sound yet, this is accepted so scripts don't have to change later
+        .arg(Arg::new("mute").long("mute").action(ArgAction::SetTrue)
+            .help("Turn off sound, does nothing yet as there is no sound"))
+        .arg(Arg::new("headless").long("headless").action(ArgAction::SetTrue)
+            .help("Run without a window, for scripts and tests"))
+        .arg(Arg::new("frames").long("frames").takes_value(true).value_name("N")
+            .value_parser(clap::value_parser!(u32)).default_value("600").requires("headless")
+            .help("Frames to run in headless mode"))
+        .arg(Arg::new("screenshot").long("screenshot").takes_value(true).value_name("FILE").requires("headless")
+            .help("Save the last frame as a PNG in headless mode"))
+        .arg(Arg::new("trace").long("trace").takes_value(true).value_name("FILE")
+            .help("Log every instruction in gameboy-doctor's format, - for stderr"))
+        .arg(Arg::new("trace-from-pc").long("trace-from-pc").takes_value(true).value_name("ADDRESS")
+            .requires("trace").conflicts_with("trace-from-cycle").value_parser(parse_address)
+            .help("Start tracing when the instruction at this address runs"))
+        .arg(Arg::new("trace-from-cycle").long("trace-from-cycle").takes_value(true).value_name("N")
+            .requires("trace").value_parser(clap::value_parser!(u64))
+            .help("Start tracing after N T-cycles"))
+        .arg(Arg::new("trace-range").long("trace-range").takes_value(true).value_name("START-END")
+            .requires("trace").value_parser(parse_range)
+            .help("Only trace instructions at these addresses, e.g. 0150-3FFF"))
+        .arg(Arg::new("trace-labels").long("trace-labels").action(ArgAction::SetTrue).requires("trace")
+            .help("Add labels from the .sym file to the trace, it then no longer diffs against gameboy-doctor logs"))
+        .arg(Arg::new("trace-limit").long("trace-limit").takes_value(true).value_name("LINES")
+            .requires("trace").value_parser(clap::value_parser!(u64))
+            .help("Stop tracing after this many instructions"))
+        .arg(Arg::new("blend").long("blend").takes_value(true).value_name("PERSISTENCE")
+            .value_parser(parse_persistence)
+            .help("Blend frames like the slow DMG LCD, 0 to 0.95"))
+        .arg(Arg::new("filters").long("filters").takes_value(true).value_name("LIST")
+            .help("Comma separated upscalers and filters, e.g. hq2x,crt"))
+        .arg(Arg::new("controls").long("controls").takes_value(true).value_name("FILE")
+            .help("Key and controller bindings, defaults to controls.cfg when it exists"))
+        .arg(Arg::new("debug").long("debug").action(ArgAction::SetTrue)
+            .help("Start in the debugger, F12 breaks into it while the game runs"))
+        .arg(Arg::new("gdb").long("gdb").takes_value(true).value_name("PORT")
+            .value_parser(clap::value_parser!(u16)).conflicts_with("debug")
+            .help("Wait for GDB to connect on this local port before starting"))
+        .arg(Arg::new("sym").long("sym").takes_value(true).value_name("FILE")
+            .help("RGBDS symbol file for labels in the debugger and --trace-labels, the ROM's .sym file by default"))
+        .get_matches();
+
+    Options {
+        rom: matches.get_one::<String>("rom").unwrap().clone(),
+        boot_rom: matches.get_one::<String>("boot-rom").cloned(),
+        scale: *matches.get_one::<u32>("scale").unwrap_or(&DEFAULT_SCALE),
+        palette: matches.get_one::<String>("palette").cloned(),
+        fullscreen: matches.get_flag("fullscreen"),
+        headless: matches.get_flag("headless"),
+        frames: *matches.get_one::<u32>("frames").unwrap(),
+        screenshot: matches.get_one::<String>("screenshot").cloned(),
+        trace: matches.get_one::<String>("trace").cloned(),
+        trace_start: match (matches.get_one::<u16>("trace-from-pc"), matches.get_one::<u64>("trace-from-cycle")) {
+            (Some(&pc), _) => TraceStart::Pc(pc),
+            (_, Some(&cycle)) => TraceStart::Cycle(cycle),
+            _ => TraceStart::Immediately,
+        },
+        trace_range: matches.get_one::<(u16, u16)>("trace-range").copied(),
+        trace_limit: matches.get_one::<u64>("trace-limit").copied(),
+        trace_labels: matches.get_flag("trace-labels"),
+        blend: matches.get_one::<f32>("blend").copied(),
+        filters: matches.get_one::<String>("filters").cloned(),
+        controls: matches.get_one::<String>("controls").cloned(),
+        debug: matches.get_flag("debug"),
+        gdb: matches.get_one::<u16>("gdb").copied(),
+        symbols: matches.get_one::<String>("sym").cloned(),
+    }
+}
+
+// Hexadecimal, with or without a 0x or $ prefix
+fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|_| format!("'{}' is not a hexadecimal address", text))
+}
+
+fn parse_range(text: &str) -> Result<(u16, u16), String> {
+    let (start, end) = text.split_once('-').ok_or_else(|| format!("'{}' is not a range like 0150-3FFF", text))?;
+    Ok((parse_address(start)?, parse_address(end)?))
+}
+
+fn parse_persistence(text: &str) -> Result<f32, String> {
+    let persistence = text.parse::<f32>().map_err(|_| format!("'{}' is not a number", text))?;
+    FrameBlend::new(persistence).map(|blend| blend.persistence)
+}
+
+fn buffer_from_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut file = std::fs::File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
     let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).expect("Could not read file");
+    file.read_to_end(&mut buffer).map_err(|e| format!("Could not read {}: {}", path, e))?;
     // println!("{:?}", buffer);
-    buffer
+    Ok(buffer)
+}
+
+// Accepts either the name of a built in preset or a path to a palette file
+fn load_palette(name: &str) -> Result<Palette, String> {
+    if let Some(palette) = Palette::from_preset(name) {
+        return Ok(palette);
+    }
+    Palette::from_file(name).map_err(|e| format!("{}\nBuilt in palettes are: {}", e, Palette::PRESETS.join(", ")))
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    
-    let bootrombuffer = buffer_from_file(&args[1]);
-    let rombuffer = buffer_from_file(&args[2]);
+    let options = parse_options();
+    if let Err(e) = run(options) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let rombuffer = buffer_from_file(&options.rom)?;
+    if rombuffer.len() < MINIMUM_ROM_SIZE {
+        return Err(format!("{} is too small to be a Game Boy ROM", options.rom));
+    }
+    let model = Model::from_header(&rombuffer);
+    let bootrombuffer = match &options.boot_rom {
+        Some(path) => {
+            let buffer = buffer_from_file(path)?;
+            if buffer.len() != model.boot_rom_size() {
+                return Err(format!(
+                    "{} is not a {:?} boot ROM, expected {} bytes got {}",
+                    path,
+                    model,
+                    model.boot_rom_size(),
+                    buffer.len()
+                ));
+            }
+            Some(buffer)
+        }
+        None => None,
+    };
+
+    let symbols = load_symbols(&options)?;
+    let mut cpu = CPU::with_model(bootrombuffer, rombuffer, model);
+    if let Some(path) = &options.trace {
+        let mut tracer = Tracer::to_file(path)?;
+        tracer.start = options.trace_start;
+        tracer.range = options.trace_range.map(|(start, end)| start..=end);
+        tracer.limit = options.trace_limit;
+        if options.trace_labels {
+            if symbols.is_empty() {
+                return Err("--trace-labels needs a .sym file next to the ROM or --sym".to_string());
+            }
+            tracer.symbols = Some(symbols.clone());
+        }
+        cpu.tracer = Some(tracer);
+    }
+    if let Some(palette) = &options.palette {
+        cpu.bus.gpu.set_palette(load_palette(palette)?);
+    }
+
+    // LCD ghosting, how much of the previous frames stays on screen
+    let frame_blend = options.blend.map(FrameBlend::new).transpose()?;
+    // Upscalers and filters, e.g. "hq2x,crt"
+    let filters = options.filters.as_deref().map(Pipeline::parse).transpose()?;
+
+    if options.headless {
+        run_headless(&mut cpu, &options, &symbols, frame_blend, filters)
+    } else {
+        run_window(&mut cpu, &options, &symbols, frame_blend, filters)
+    }
+}
+
+// RGBDS writes game.sym next to game.gb, no labels when there isn't one
+fn load_symbols(options: &Options) -> Result<Symbols, String> {
+    if let Some(path) = &options.symbols {
+        return Symbols::from_file(path);
+    }
+    let path = Path::new(&options.rom).with_extension("sym");
+    if !path.exists() {
+        return Ok(Symbols::default());
+    }
+    Symbols::from_file(&path.to_string_lossy())
+}
+
+fn attach_debuggers(options: &Options, symbols: &Symbols) -> Result<(Option<Debugger>, Option<GdbStub>), String> {
+    let debugger = if options.debug { Some(Debugger::new(symbols.clone())) } else { None };
+    let gdb = match options.gdb {
+        Some(port) => {
+            let address = format!("127.0.0.1:{}", port);
+            println!("Waiting for GDB to connect to {}", address);
+            Some(GdbStub::listen(&address)?)
+        }
+        None => None,
+    };
+    Ok((debugger, gdb))
+}
+
+// Runs one instruction through the debugger or the GDB stub when there is one, None once they quit
+fn step(cpu: &mut CPU, debugger: &mut Option<Debugger>, gdb: &mut Option<GdbStub>) -> Option<u32> {
+    match (debugger, gdb) {
+        (Some(debugger), _) => debugger.step(cpu),
+        (_, Some(gdb)) => gdb.step(cpu),
+        _ => Some(cpu.step()),
+    }
+}
+
+// Runs a fixed number of frames as fast as possible and optionally saves the last one
+fn run_headless(cpu: &mut CPU, options: &Options, symbols: &Symbols, mut frame_blend: Option<FrameBlend>, mut filters: Option<Pipeline>) -> Result<(), String> {
+    let mut frame = cpu.frame();
+    let (mut debugger, mut gdb) = attach_debuggers(options, symbols)?;
+    let mut quit = false;
+    for _ in 0..options.frames {
+        frame = cpu.run_frame_with(|cpu| {
+            let cycles = step(cpu, &mut debugger, &mut gdb);
+            quit = cycles.is_none();
+            cycles
+        });
+        if quit {
+            break;
+        }
+        if let Some(frame_blend) = &mut frame_blend {
+            frame = frame_blend.process(frame);
+        }
+    }
+    if let Some(path) = &options.screenshot {
+        if let Some(filters) = &mut filters {
+            frame = filters.process(frame);
+        }
+        frame.save_png(path)?;
+    }
+    Ok(())
+}
+
+fn run_window(cpu: &mut CPU, options: &Options, symbols: &Symbols, mut frame_blend: Option<FrameBlend>, mut filters: Option<Pipeline>) -> Result<(), String> {
+    let mut frame = cpu.frame();
+    let scale = options.scale;
+
+    // The SGB draws its border around the game screen
+    let (screen_width, screen_height) = if cpu.bus.sgb.is_some() {
+        (SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT)
+    } else {
+        (SCREEN_WIDTH, SCREEN_HEIGHT)
+    };
 
     // Setup SDL
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-    .window("GameBoy Emulator", WINDOW_WIDTH, WINDOW_HEIGHT)
-    .position_centered()
-    .opengl()
-    .build()
-    .unwrap();
-
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let mut window = video_subsystem
+    .window("GameBoy Emulator", screen_width as u32 * scale, screen_height as u32 * scale);
+    window.position_centered().resizable().opengl();
+    if options.fullscreen {
+        window.fullscreen_desktop();
+    }
+    let window = window.build().map_err(|e| e.to_string())?;
+
+    let mut canvas = window.into_canvas().present_vsync().build().map_err(|e| e.to_string())?;
     canvas.clear();
     canvas.present();
 
-    let mut event_pump = sdl_context.event_pump().unwrap();
+    // The frame is uploaded into this once it is finished and SDL does the scaling
+    let texture_creator = canvas.texture_creator();
+    let mut texture = create_screen_texture(&texture_creator, &frame)?;
+
+    let mut event_pump = sdl_context.event_pump()?;
+
+    // Controls are read from controls.cfg in the working directory when there is one
+    let bindings = match &options.controls {
+        Some(path) => Bindings::from_file(path)?,
+        None if std::path::Path::new(CONTROLS_FILE).exists() => Bindings::from_file(CONTROLS_FILE)?,
+        None => Bindings::default(),
+    };
+    let mut input = Input::new(bindings, sdl_context.game_controller()?);
 
     // // Debug
     // let window2 = video_subsystem.window("MemView", 500, 500).position_centered()
@@ -66,77 +341,116 @@ fn main() {
     // font.set_style(sdl2::ttf::FontStyle::BOLD);
 
     
-    // let mut ins: [u8; 10] = [0; 10];
 
-    let mut cpu = CPU::new(Some(bootrombuffer), rombuffer);
-    
-    let mut cycles_elapsed_in_frame = 0usize;
+
+    let (mut debugger, mut gdb) = attach_debuggers(options, symbols)?;
     let mut now = Instant::now();
     'running: loop {
+        let mut redraw = false;
         for evt in event_pump.poll_iter() {
             match evt {
                 Event::Quit{..}  | Event::KeyDown{keycode: Some(Keycode::Escape), ..} => { 
                     break 'running;
                 },
-                _ => ()
+                Event::KeyDown{keycode: Some(Keycode::F11), repeat: false, ..} => {
+                    toggle_fullscreen(&mut canvas)?;
+                },
+                // The letterbox has to be worked out again for the new window size
+                Event::Window{win_event: WindowEvent::SizeChanged(..) | WindowEvent::Exposed, ..} => {
+                    redraw = true;
+                },
+                Event::KeyDown{keycode: Some(Keycode::F12), repeat: false, ..} => {
+                    println!("Paused, type help for a list of commands");
+                    debugger.get_or_insert_with(|| Debugger::new(symbols.clone())).pause();
+                },
+                _ => input.handle_event(&evt)
             }
         }
+        input.update(cpu);
         let time_delta = now.elapsed().subsec_nanos();
         now = Instant::now();
         let delta = time_delta as f64 / ONE_SECOND_IN_MICROS as f64;
         let cycles_to_run = delta * ONE_SECOND_IN_CYCLES as f64;
 
         let mut cycles_elapsed = 0;
+        let mut frame_completed = false;
         while cycles_elapsed <= cycles_to_run as usize {
             cycles_elapsed += 1;
-            cpu.step();
-            // ins = get_mem(&cpu);
-            // draw_debug(&mut canvas2, &mut font, ins);
+            if step(cpu, &mut debugger, &mut gdb).is_none() {
+                break 'running;
+            }
+            if cpu.bus.frame_ready {
+                cpu.bus.frame_ready = false;
+                frame_completed = true;
+                input.next_frame();
+                frame = cpu.frame();
+                if let Some(frame_blend) = &mut frame_blend {
+                    frame = frame_blend.process(frame);
+                }
+                if let Some(filters) = &mut filters {
+                    frame = filters.process(frame);
+                }
+            }
+            // let lines = disassemble_at_pc(cpu);
+            // draw_debug(&mut canvas2, &mut font, &lines);
             // sleep(Duration::from_millis(100));
         }
-        draw_screen(&cpu, &mut canvas);
 
-        cycles_elapsed_in_frame += cycles_elapsed;
-
-        // TODO: Consider updating buffer after every line is rendered.
-        if cycles_elapsed_in_frame >= TICKS_PER_FRAME {
-            draw_screen(&cpu, &mut canvas);
+        if frame_completed {
+            let query = texture.query();
+            if query.width as usize != frame.width || query.height as usize != frame.height {
+                texture = create_screen_texture(&texture_creator, &frame)?;
+            }
+            texture.update(None, &frame.pixels, frame.width * 4).map_err(|e| e.to_string())?;
+        }
+        if frame_completed || redraw {
+            draw_screen(&texture, &mut canvas)?;
+        } else {
+            // Nothing to show yet, without a present there is no vsync to wait on
+            std::thread::sleep(Duration::from_millis(1));
         }
-
     }
+    Ok(())
 }
 
-fn draw_screen(cpu: &CPU, canvas: &mut Canvas<Window>) {
+fn create_screen_texture<'a>(texture_creator: &'a TextureCreator<WindowContext>, frame: &Frame) -> Result<Texture<'a>, String> {
+    texture_creator
+        .create_texture_streaming(PixelFormatEnum::RGBA32, frame.width as u32, frame.height as u32)
+        .map_err(|e| e.to_string())
+}
+
+// Scales the screen by the largest whole number that fits and centres it with black bars around.
+// The texture is as big as the frame after the filters, which may have upscaled it.
+fn draw_screen(texture: &Texture, canvas: &mut Canvas<Window>) -> Result<(), String> {
+    let (window_width, window_height) = canvas.output_size()?;
+    let TextureQuery { width: screen_width, height: screen_height, .. } = texture.query();
+    let scale = (window_width / screen_width).min(window_height / screen_height).max(1);
+    let (width, height) = (screen_width * scale, screen_height * scale);
+    let destination = Rect::new(
+        (window_width as i32 - width as i32) / 2,
+        (window_height as i32 - height as i32) / 2,
+        width,
+        height,
+    );
+
     canvas.set_draw_color(Color::RGB(0, 0, 0));
-    // canvas.clear();
-    // let screen_buf = cpu.bus.gpu.canvas_buffer;
-    // Now set draw color to white, iterate through each point and see if it should be drawn
-    // canvas.set_draw_color(Color::RGB(255, 255, 255));
-
-    for (i, pixel) in cpu.bus.gpu.canvas_buffer.chunks(4).enumerate() {
-        // Convert our 1D array's index into a 2D (x,y) position
-        let x = (i % SCREEN_WIDTH) as u32;
-        let y = (i / SCREEN_WIDTH) as u32;
-
-        // buffer[i] = (pixel[3] as u32) << 24
-        //     | (pixel[2] as u32) << 16
-        //     | (pixel[1] as u32) << 8
-        //     | (pixel[0] as u32);
-
-        canvas.set_draw_color(Color::RGBA(pixel[0], pixel[1], pixel[2], pixel[3]));
-
-        // Draw a rectangle at (x,y), scaled up by our SCALE value
-        let rect = Rect::new((x * SCALE) as i32, (y * SCALE) as i32, SCALE, SCALE);
-        canvas.fill_rect(rect).unwrap();
-    }
+    canvas.clear();
+    canvas.copy(texture, None, Some(destination))?;
     canvas.present();
+    Ok(())
 }
 
-fn draw_debug(canvas2: &mut Canvas<Window>, font: &mut Font, ins: [u8; 10]) {
-    let strs: Vec<String> = ins.iter()
-                               .map(|b| format!("0x{:x}", b))
-                               .collect();
-    let strs = strs.join("\n");
+fn toggle_fullscreen(canvas: &mut Canvas<Window>) -> Result<(), String> {
+    let window = canvas.window_mut();
+    let fullscreen = match window.fullscreen_state() {
+        FullscreenType::Off => FullscreenType::Desktop,
+        _ => FullscreenType::Off,
+    };
+    window.set_fullscreen(fullscreen)
+}
+
+fn draw_debug(canvas2: &mut Canvas<Window>, font: &mut Font, lines: &[String]) {
+    let strs = lines.join("\n");
 
     let texture_creator = canvas2.texture_creator();
     
@@ -159,12 +473,3 @@ fn draw_debug(canvas2: &mut Canvas<Window>, font: &mut Font, ins: [u8; 10]) {
     canvas2.copy(&texture, None, Some(Rect::new(1, 1, 500, 50))).unwrap();
     canvas2.present();
 }
-fn get_mem(cpu: &CPU) -> [u8; 10] {
-    let mut data: [u8; 10] = [0; 10];
-
-    for x in 0..10 {
-        data[x] = cpu.bus.read_byte(cpu.pc + (x as u16));
-    }
-
-    data
-}
\ No newline at end of file